@@ -0,0 +1,159 @@
+/*
+ *  Broadcasting one message to thousands of connections, once by handing
+ *  every connection its own response to serialize and once by serializing a
+ *  single frame they all share
+ *
+ *  Run with `cargo bench --bench fanout`. Each simulated client is the
+ *  outbound queue of a real connection plus the codec and write buffer its
+ *  actor would use, only the socket is left out.
+ */
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use simple_lib::{
+    actor::{
+        outbound::{BackpressurePolicy, OutboundQueue},
+        tcp_handler::ControllerMessages,
+    },
+    msg::{codec::ServerCodec, Envelope, ServerResponse},
+};
+use tokio_util::codec::Encoder;
+
+/// Counts every allocation made by the process.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size as u64, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const BROADCASTS: usize = 50;
+const MESSAGE_LEN: usize = 256;
+
+struct Client {
+    queue: OutboundQueue,
+    codec: ServerCodec,
+    buffer: BytesMut,
+}
+
+impl Client {
+    fn new() -> Self {
+        Self {
+            queue: OutboundQueue::new(1024, BackpressurePolicy::DropOldest),
+            codec: ServerCodec::new(),
+            buffer: BytesMut::with_capacity(4096),
+        }
+    }
+
+    /// Does what the connection's actor does with the next queued message.
+    async fn write_next(&mut self) {
+        match self.queue.pop().await {
+            ControllerMessages::WriteStream(response) => {
+                self.codec.encode(response, &mut self.buffer).unwrap()
+            }
+            ControllerMessages::WriteFrame(frame) => {
+                self.codec.encode(frame, &mut self.buffer).unwrap()
+            }
+            _ => unreachable!(),
+        }
+        // as if the socket took all of it
+        self.buffer.clear();
+    }
+}
+
+fn broadcast(n: usize) -> ServerResponse {
+    ServerResponse::Broadcast {
+        room: "lobby".to_string(),
+        envelope: Envelope::new(n as u64, 0),
+        username: "alice".to_string(),
+        message: "x".repeat(MESSAGE_LEN),
+    }
+}
+
+struct Sample {
+    elapsed: Duration,
+    allocations: u64,
+    bytes: u64,
+}
+
+async fn measure(clients: &mut [Client], shared: bool) -> Sample {
+    let codec = ServerCodec::new();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for n in 0..BROADCASTS {
+        let response = broadcast(n);
+        if shared {
+            let frame = codec.encode_frame(&response).unwrap();
+            for client in clients.iter() {
+                client.queue.push_frame(frame.clone());
+            }
+        } else {
+            for client in clients.iter() {
+                client.queue.push(response.clone());
+            }
+        }
+        for client in clients.iter_mut() {
+            client.write_next().await;
+        }
+    }
+    Sample {
+        elapsed: start.elapsed(),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        bytes: ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes,
+    }
+}
+
+fn report(clients: usize, path: &str, sample: &Sample) {
+    let per = BROADCASTS as u64;
+    println!(
+        "{clients:>7} {path:<14} {:>12.1?} {:>14} {:>16}",
+        sample.elapsed / BROADCASTS as u32,
+        sample.allocations / per,
+        sample.bytes / per,
+    );
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    println!(
+        "{BROADCASTS} broadcasts of {MESSAGE_LEN} byte messages, figures per broadcast\n\
+         {:>7} {:<14} {:>12} {:>14} {:>16}",
+        "clients", "path", "time", "allocations", "bytes allocated"
+    );
+    for n in [1_000, 5_000, 10_000] {
+        let mut clients: Vec<Client> = (0..n).map(|_| Client::new()).collect();
+        runtime.block_on(async {
+            // warm the queues and buffers up so both runs start alike
+            measure(&mut clients, false).await;
+            let per_client = measure(&mut clients, false).await;
+            let shared = measure(&mut clients, true).await;
+            report(n, "per-client", &per_client);
+            report(n, "shared frame", &shared);
+        });
+    }
+}
@@ -0,0 +1,186 @@
+/*
+ *  Room traffic through a real server over loopback, with the server given
+ *  more and more worker threads
+ *
+ *  Run with `cargo bench --bench load > /dev/null`, the server logs every
+ *  message to stdout so the figures go to stderr. Every round starts a
+ *  server with as many room shards as worker threads, in a runtime of its
+ *  own, and the clients run in another. Every client sends to its room and
+ *  reads what the others in it send, and the round is timed until every
+ *  broadcast has been delivered. The figures can only grow with the worker
+ *  threads while there are idle cpus left for them, the clients included.
+ */
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use futures::{SinkExt, StreamExt};
+use simple_lib::{
+    msg::{codec::ClientCodec, Capabilities, ClientMessage, ServerResponse, PROTOCOL_VERSION},
+    server::ChatServer,
+};
+use tokio::{net::TcpStream, runtime::Runtime};
+use tokio_util::codec::Framed;
+
+const ROOMS: usize = 16;
+const CLIENTS_PER_ROOM: usize = 8;
+const MESSAGES_PER_CLIENT: usize = 200;
+const MESSAGE_LEN: usize = 128;
+// a round that takes longer than this has lost messages somewhere
+const ROUND_TIMEOUT: Duration = Duration::from_secs(120);
+
+type Client = Framed<TcpStream, ClientCodec>;
+
+/// Connects, registers `name` and joins `room`.
+async fn join(addr: SocketAddr, name: String, room: String) -> Client {
+    let stream = TcpStream::connect(addr).await.unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut client = Framed::new(stream, ClientCodec::new());
+    client
+        .send(ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+        })
+        .await
+        .unwrap();
+    client.send(ClientMessage::UserName(name)).await.unwrap();
+    client
+        .send(ClientMessage::JoinRoom(room.clone()))
+        .await
+        .unwrap();
+    while let Some(response) = client.next().await {
+        if matches!(response.unwrap(), ServerResponse::RoomJoined { room: joined } if joined == room)
+        {
+            return client;
+        }
+    }
+    panic!("server closed the connection before {room} was joined");
+}
+
+/// Sends this client's messages while reading the broadcasts of everyone
+/// else in the room, returning how many were lost along the way and the
+/// connection, so it stays open until the round is over.
+async fn chat(client: Client, room: String) -> (u64, Client) {
+    let (mut sink, mut stream) = client.split();
+    let message = "x".repeat(MESSAGE_LEN);
+    let send = async move {
+        for _ in 0..MESSAGES_PER_CLIENT {
+            sink.send(ClientMessage::Message {
+                room: room.clone(),
+                message: message.clone(),
+                nonce: None,
+            })
+            .await
+            .unwrap();
+        }
+        sink
+    };
+    let receive = async {
+        let expected = (MESSAGES_PER_CLIENT * (CLIENTS_PER_ROOM - 1)) as u64;
+        let (mut received, mut dropped) = (0, 0);
+        while received + dropped < expected {
+            match stream.next().await {
+                Some(Ok(ServerResponse::Broadcast { .. })) => received += 1,
+                Some(Ok(ServerResponse::MessagesDropped { count })) => dropped += count,
+                Some(Ok(_)) => {}
+                _ => panic!("connection closed after {received} broadcasts"),
+            }
+        }
+        dropped
+    };
+    let (sink, dropped) = tokio::join!(send, receive);
+    (dropped, sink.reunite(stream).unwrap())
+}
+
+struct Sample {
+    elapsed: Duration,
+    delivered: u64,
+    dropped: u64,
+}
+
+fn round(clients: &Runtime, workers: usize) -> Sample {
+    let server_runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(workers)
+        .enable_all()
+        .build()
+        .unwrap();
+    let server = server_runtime
+        .block_on(
+            ChatServer::builder()
+                .shards(workers)
+                .replay_len(0)
+                .queue_capacity(1 << 16)
+                .rate_limit(None)
+                .bind("127.0.0.1:0"),
+        )
+        .unwrap();
+    let addr = server.local_addr();
+
+    let (sample, connections) = clients.block_on(async {
+        tokio::time::timeout(ROUND_TIMEOUT, chat_round(addr))
+            .await
+            .expect("round timed out")
+    });
+    // closed by the server, so that nobody is written to after leaving
+    server_runtime
+        .block_on(server.shutdown("round over", None, Duration::from_secs(5)))
+        .unwrap();
+    drop(connections);
+    sample
+}
+
+/// Connects every client, then times them all chatting at once.
+async fn chat_round(addr: SocketAddr) -> (Sample, Vec<Client>) {
+    let mut joined = vec![];
+    for room in 0..ROOMS {
+        for n in 0..CLIENTS_PER_ROOM {
+            let room = format!("room-{room}");
+            let client = join(addr, format!("{room}-user-{n}"), room.clone()).await;
+            joined.push((client, room));
+        }
+    }
+    let start = Instant::now();
+    let tasks: Vec<_> = joined
+        .into_iter()
+        .map(|(client, room)| tokio::spawn(chat(client, room)))
+        .collect();
+    let (mut dropped, mut connections) = (0, vec![]);
+    for task in tasks {
+        let (lost, client) = task.await.unwrap();
+        dropped += lost;
+        connections.push(client);
+    }
+    let elapsed = start.elapsed();
+    let expected = (ROOMS * CLIENTS_PER_ROOM * MESSAGES_PER_CLIENT * (CLIENTS_PER_ROOM - 1)) as u64;
+    let sample = Sample {
+        elapsed,
+        delivered: expected - dropped,
+        dropped,
+    };
+    (sample, connections)
+}
+
+fn main() {
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let clients = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(cpus.min(4))
+        .enable_all()
+        .build()
+        .unwrap();
+    eprintln!(
+        "{ROOMS} rooms of {CLIENTS_PER_ROOM} clients, each sending {MESSAGES_PER_CLIENT} \
+         messages of {MESSAGE_LEN} bytes, on {cpus} cpus\n\
+         {:>7} {:>10} {:>12} {:>16} {:>8}",
+        "workers", "time", "delivered", "broadcasts/s", "dropped"
+    );
+    for workers in [1, 2, 4, 8] {
+        let sample = round(&clients, workers);
+        eprintln!(
+            "{workers:>7} {:>10.2?} {:>12} {:>16.0} {:>8}",
+            sample.elapsed,
+            sample.delivered,
+            sample.delivered as f64 / sample.elapsed.as_secs_f64(),
+            sample.dropped,
+        );
+    }
+}
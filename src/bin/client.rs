@@ -1,26 +1,38 @@
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::io;
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Local};
 use crossterm::{
-    event::{self, Event as CEvent, KeyCode},
+    event::{self, Event as CEvent, KeyCode, KeyModifiers},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
+use futures::{SinkExt, StreamExt};
 use ratatui::{
     backend::CrosstermBackend,
     layout::{Constraint, Direction, Layout},
-    style::{Color, Style},
-    text::Line,
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
     widgets::{Block, Borders, Paragraph},
     Terminal,
 };
-use simple_lib::msg::{ClientMessage, ServerResponse, TcpMessage};
-use tokio::{
-    io::{split, AsyncReadExt, AsyncWriteExt, BufReader},
-    net::TcpStream,
-    sync::mpsc,
+use simple_lib::{
+    history::now_millis,
+    msg::{
+        codec::ClientCodec, validate::sanitize, Capabilities, ClientMessage, HistoryEntry,
+        ServerResponse, DEFAULT_ROOM, PROTOCOL_VERSION,
+    },
+    transport::{
+        tls::{self, TlsConnector},
+        BoxedStream,
+    },
 };
+use tokio::{net::TcpStream, sync::mpsc};
+use tokio_util::codec::Framed;
 
 use clap::Parser;
 #[derive(Parser, Debug)]
@@ -29,14 +41,629 @@ struct Args {
     /// The name to greet
     #[arg(short, long)]
     user: String,
+    /// Password or token for servers that require authentication, read from
+    /// `SIMPLE_CHAT_CREDENTIAL` if not given
+    #[arg(short, long)]
+    credential: Option<String>,
+    /// Connect over tls, trusting servers issued by the authorities in this
+    /// PEM bundle
+    #[arg(long, conflicts_with = "tls_pin")]
+    tls_ca: Option<PathBuf>,
+    /// Connect over tls, trusting only the server certificate in this PEM file
+    #[arg(long)]
+    tls_pin: Option<PathBuf>,
+    /// Name the server certificate must be issued for, defaults to the
+    /// server's ip address
+    #[arg(long)]
+    tls_server_name: Option<String>,
+}
+
+/// What kind of line is shown in the chat window, which decides its colour.
+#[derive(Clone, Copy)]
+enum LineKind {
+    Chat,
+    Own,
+    Direct,
+    History,
+    Info,
+    Error,
+}
+
+/// What became of a message the user sent, as far as the server said.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Delivery {
+    Pending,
+    Confirmed,
+    Failed,
+}
+
+/// A message sent to a room, which its author may still edit or delete.
+struct Posted {
+    // the id the server gave the message, not known for one of ours until
+    // the server confirms it
+    id: Option<u64>,
+    // where the message starts in the text of the line
+    start: usize,
+    edited: bool,
+}
+
+struct ChatLine {
+    kind: LineKind,
+    text: String,
+    // for the user's own messages, the nonce they were sent with and what
+    // became of them
+    sent: Option<(u64, Delivery)>,
+    posted: Option<Posted>,
+}
+
+impl ChatLine {
+    /// A line of `text`, with anything in it that could steer the terminal
+    /// replaced, whoever wrote it.
+    fn new(kind: LineKind, text: String) -> Self {
+        let text = match sanitize(&text) {
+            Cow::Borrowed(_) => text,
+            Cow::Owned(clean) => clean,
+        };
+        Self {
+            kind,
+            text,
+            sent: None,
+            posted: None,
+        }
+    }
+
+    /// A line showing `message` from a room, after a `prefix` saying where
+    /// it was said and by whom.
+    fn posted(kind: LineKind, prefix: String, message: &str, id: Option<u64>) -> Self {
+        let mut line = Self::new(kind, prefix);
+        let start = line.text.len();
+        line.text.push_str(&sanitize(message));
+        line.posted = Some(Posted {
+            id,
+            start,
+            edited: false,
+        });
+        line
+    }
+
+    /// Marks the line as a message the user sent with `nonce`, waiting to
+    /// hear from the server.
+    fn sent(mut self, nonce: u64) -> Self {
+        self.sent = Some((nonce, Delivery::Pending));
+        self
+    }
+
+    /// The id of the room message on this line.
+    fn id(&self) -> Option<u64> {
+        self.posted.as_ref().and_then(|posted| posted.id)
+    }
+
+    /// The room message on this line, without where and by whom.
+    fn message(&self) -> Option<&str> {
+        self.posted
+            .as_ref()
+            .map(|posted| &self.text[posted.start..])
+    }
+
+    /// Shows the message as edited to read `message`, or as deleted.
+    fn amend(&mut self, message: Option<&str>) {
+        let Some(posted) = self.posted.as_mut() else {
+            return;
+        };
+        self.text.truncate(posted.start);
+        match message {
+            Some(message) => {
+                self.text.push_str(&sanitize(message));
+                posted.edited = true;
+            }
+            None => {
+                self.text.push_str("(deleted)");
+                self.kind = LineKind::History;
+                self.posted = None;
+            }
+        }
+    }
+
+    fn to_line(&self, selected: bool) -> Line<'_> {
+        let mut style = match self.kind {
+            LineKind::Chat => Style::default(),
+            LineKind::Own => Style::default().fg(Color::Gray),
+            LineKind::Direct => Style::default().fg(Color::Magenta),
+            LineKind::History => Style::default().fg(Color::DarkGray),
+            LineKind::Info => Style::default().fg(Color::Cyan),
+            LineKind::Error => Style::default().fg(Color::Red),
+        };
+        if selected {
+            style = style.add_modifier(Modifier::REVERSED);
+        }
+        let mut spans = Vec::with_capacity(3);
+        if let Some((_, delivery)) = self.sent {
+            spans.push(match delivery {
+                Delivery::Pending => Span::styled("… ", Style::default().fg(Color::DarkGray)),
+                Delivery::Confirmed => Span::styled("✓ ", Style::default().fg(Color::Green)),
+                Delivery::Failed => Span::styled("✗ ", Style::default().fg(Color::Red)),
+            });
+        }
+        spans.push(Span::styled(self.text.as_str(), style));
+        if self.posted.as_ref().is_some_and(|posted| posted.edited) {
+            spans.push(Span::styled(
+                " (edited)",
+                Style::default().fg(Color::DarkGray),
+            ));
+        }
+        Line::from(spans)
+    }
+}
+
+/// The lines of the chat window and how far back it is scrolled.
+struct ChatLog {
+    lines: Vec<ChatLine>,
+    // lines hidden below the bottom of the window, 0 follows new messages
+    scroll: usize,
+}
+
+impl ChatLog {
+    fn new() -> Self {
+        Self {
+            lines: vec![],
+            scroll: 0,
+        }
+    }
+
+    fn push(&mut self, line: ChatLine) {
+        self.lines.push(line);
+        // keep what is on screen in place while reading older messages
+        if self.scroll > 0 {
+            self.scroll += 1;
+        }
+    }
+
+    fn prepend(&mut self, lines: Vec<ChatLine>) {
+        self.lines.splice(0..0, lines);
+    }
+
+    /// The line of the message the user sent with `nonce`.
+    fn sent_with(&mut self, nonce: u64) -> Option<&mut ChatLine> {
+        self.lines
+            .iter_mut()
+            .rev()
+            .find(|line| line.sent.is_some_and(|(sent, _)| sent == nonce))
+    }
+
+    /// Marks the message sent with `nonce` as having gone out as `seq`.
+    fn confirm(&mut self, nonce: u64, seq: u64) {
+        if let Some(line) = self.sent_with(nonce) {
+            line.sent = Some((nonce, Delivery::Confirmed));
+            if let Some(posted) = line.posted.as_mut() {
+                posted.id = Some(seq);
+            }
+        }
+    }
+
+    /// Marks the message sent with `nonce` as having gone nowhere.
+    fn fail(&mut self, nonce: u64) {
+        if let Some(line) = self.sent_with(nonce) {
+            line.sent = Some((nonce, Delivery::Failed));
+        }
+    }
+
+    /// Shows the room message `id` edited to read `message`, or deleted.
+    fn amend(&mut self, id: u64, message: Option<&str>) {
+        if let Some(line) = self
+            .lines
+            .iter_mut()
+            .rev()
+            .find(|line| line.id() == Some(id))
+        {
+            line.amend(message);
+        }
+    }
+
+    /// The room message `id`, as it reads now.
+    fn message(&self, id: u64) -> Option<&str> {
+        let line = self.lines.iter().rev().find(|line| line.id() == Some(id))?;
+        line.message()
+    }
+
+    /// The user's own room message before the one with id `from`, or the
+    /// newest one if there is none, or the one after it when not `older`.
+    fn pick(&self, from: Option<u64>, older: bool) -> Option<u64> {
+        let own: Vec<u64> = self
+            .lines
+            .iter()
+            .filter(|line| line.sent.is_some())
+            .filter_map(ChatLine::id)
+            .collect();
+        match (
+            from.and_then(|id| own.iter().position(|own| *own == id)),
+            older,
+        ) {
+            (Some(at), true) => Some(own[at.saturating_sub(1)]),
+            (Some(at), false) => own.get(at + 1).copied(),
+            (None, true) => own.last().copied(),
+            (None, false) => None,
+        }
+    }
+
+    /// Gives up on hearing about messages sent before the connection was
+    /// lost.
+    fn fail_pending(&mut self) {
+        for (_, delivery) in self.lines.iter_mut().filter_map(|line| line.sent.as_mut()) {
+            if *delivery == Delivery::Pending {
+                *delivery = Delivery::Failed;
+            }
+        }
+    }
+
+    /// Scrolls back by up to `by` lines, returns `false` if the top of the
+    /// log is already on screen.
+    fn scroll_up(&mut self, by: usize, height: usize) -> bool {
+        let max = self.lines.len().saturating_sub(height);
+        if self.scroll >= max {
+            return false;
+        }
+        self.scroll = (self.scroll + by).min(max);
+        true
+    }
+
+    fn scroll_down(&mut self, by: usize) {
+        self.scroll = self.scroll.saturating_sub(by);
+    }
+
+    /// Index of the first line shown in a window `height` lines tall.
+    fn offset(&self, height: usize) -> u16 {
+        let top = self.lines.len().saturating_sub(height + self.scroll);
+        top.min(u16::MAX as usize) as u16
+    }
 }
 
+/// Number of older messages requested at a time when scrolling back.
+const HISTORY_PAGE: u32 = 50;
+
+/// How often the server is pinged, if it answers pings.
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long the server may stay silent before it is shown as not responding,
+/// after twice as long the connection is given up and made again.
+const SERVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait before the first attempt to reconnect, doubled after
+/// every failed attempt up to `MAX_RECONNECT_DELAY`.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// How long the server gets to close the connection after being told the
+/// user is leaving.
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long connecting and logging in may take before the attempt is
+/// counted as failed.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+type ServerConnection = Framed<BoxedStream, ClientCodec>;
+
 enum Event {
     Input(String),
-    ServerMsg { from: String, message: String },
+    Scroll { up: bool },
+    // Up or Down, to pick one of the user's messages to edit
+    Select { older: bool },
+    // Del, deletes the message being edited
+    Delete,
+    // Esc or Ctrl-C, the same as typing `leave` unless a message is being
+    // edited
+    Leave,
+    Server(ServerResponse),
+    // the connection to the server changed
+    Status(ServerStatus),
+    // something about the connection worth showing in the chat window
+    Notice(ChatLine),
     End,
 }
 
+/// What the client knows about the server it is connected to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ServerStatus {
+    Up,
+    // the server has been quiet for longer than `SERVER_TIMEOUT`
+    NotResponding,
+    Reconnecting,
+}
+
+/// Everything needed to log in to the server, again and again if need be.
+struct Login {
+    addr: SocketAddr,
+    user: String,
+    credential: Option<String>,
+    connector: Option<TlsConnector>,
+    // the name the server certificate must be issued for
+    server_name: String,
+}
+
+/// What the client needs to pick up where it left off after losing the
+/// server.
+#[derive(Default)]
+struct Session {
+    // handed out by servers that keep the sessions of dropped users around
+    token: Option<String>,
+    // the newest message seen in any room
+    last_seq: Option<u64>,
+    rooms: Vec<String>,
+}
+
+impl Session {
+    fn track(&mut self, response: &ServerResponse) {
+        match response {
+            ServerResponse::SessionToken { token, .. } => self.token = Some(token.clone()),
+            ServerResponse::RoomJoined { room } if !self.rooms.contains(room) => {
+                self.rooms.push(room.clone());
+            }
+            ServerResponse::RoomLeft { room } => self.rooms.retain(|r| r != room),
+            ServerResponse::Broadcast { envelope, .. } => self.seen(envelope.seq),
+            // what the user said never comes back as a broadcast
+            ServerResponse::Ack { seq, .. } => self.seen(*seq),
+            ServerResponse::HistoryReplay { entries, .. } => {
+                for entry in entries {
+                    self.seen(entry.seq);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn seen(&mut self, seq: u64) {
+        self.last_seq = Some(self.last_seq.map_or(seq, |last| last.max(seq)));
+    }
+}
+
+/// Why the connection task stopped serving a connection.
+enum Stopped {
+    // the UI is gone, so is the need for a server
+    Closed,
+    // the connection was lost, the server may have said when to come back
+    Lost { retry_after: Option<Duration> },
+}
+
+/// Talks to the server on behalf of the UI and logs in again whenever the
+/// connection is lost. Messages sent while there is no connection are kept
+/// until there is one.
+struct ConnectionTask {
+    login: Login,
+    session: Session,
+    queued: VecDeque<ClientMessage>,
+    outgoing: mpsc::Receiver<ClientMessage>,
+    events: mpsc::Sender<Event>,
+    // the server was told the user is leaving, losing it now is expected
+    leaving: bool,
+}
+
+impl ConnectionTask {
+    async fn run(mut self, mut connection: ServerConnection, mut capabilities: Capabilities) {
+        // fill the sidebar, it is kept up to date by presence notifications
+        self.queued.push_back(ClientMessage::ListUsers);
+        loop {
+            let retry_after = match self.serve(connection, capabilities).await {
+                Stopped::Closed => return,
+                Stopped::Lost { .. } if self.leaving => {
+                    let _ = self.events.send(Event::End).await;
+                    return;
+                }
+                Stopped::Lost { retry_after } => retry_after,
+            };
+            match self.reconnect(retry_after).await {
+                Some((c, caps)) => (connection, capabilities) = (c, caps),
+                None => return,
+            }
+        }
+    }
+
+    async fn serve(&mut self, connection: ServerConnection, capabilities: Capabilities) -> Stopped {
+        let (mut writer, mut reader) = connection.split();
+        while let Some(msg) = self.queued.pop_front() {
+            if writer.send(msg.clone()).await.is_err() {
+                self.queued.push_front(msg);
+                return Stopped::Lost { retry_after: None };
+            }
+        }
+
+        // servers that do not answer pings cannot be told apart from quiet ones
+        let heartbeat = capabilities.contains(Capabilities::HEARTBEAT);
+        let mut ticker =
+            tokio::time::interval_at(tokio::time::Instant::now() + PING_INTERVAL, PING_INTERVAL);
+        let mut last_heard = Instant::now();
+        let mut responding = true;
+        let mut pings_sent: u64 = 0;
+        let mut retry_after = None;
+        let mut leave_deadline = None;
+        loop {
+            tokio::select! {
+                frame = reader.next() => {
+                    let response = match frame {
+                        Some(Ok(response)) => response,
+                        Some(Err(e)) => {
+                            self.notice(LineKind::Error, format!("! failed to read from server: {e}"))
+                                .await;
+                            return Stopped::Lost { retry_after };
+                        }
+                        None => return Stopped::Lost { retry_after },
+                    };
+                    last_heard = Instant::now();
+                    if !responding {
+                        responding = true;
+                        self.status(ServerStatus::Up).await;
+                        self.notice(LineKind::Info, "* server is responding again".to_string())
+                            .await;
+                    }
+                    self.session.track(&response);
+                    match response {
+                        ServerResponse::Ping(value) => {
+                            if writer.send(ClientMessage::Pong(value)).await.is_err() {
+                                return Stopped::Lost { retry_after };
+                            }
+                        }
+                        ServerResponse::Pong(_) => {}
+                        response => {
+                            match &response {
+                                ServerResponse::ServerShuttingDown { reconnect_after, .. } => {
+                                    retry_after = *reconnect_after;
+                                }
+                                // not welcome back either
+                                ServerResponse::Kicked { .. } => self.leaving = true,
+                                // logging in afresh is done under the new name
+                                ServerResponse::NickChanged { old, new } if *old == self.login.user => {
+                                    self.login.user = new.clone();
+                                }
+                                _ => {}
+                            }
+                            if self.events.send(Event::Server(response)).await.is_err() {
+                                return Stopped::Closed;
+                            }
+                        }
+                    }
+                }
+                msg = self.outgoing.recv() => {
+                    let Some(msg) = msg else {
+                        return Stopped::Closed;
+                    };
+                    if let ClientMessage::Leave { .. } = msg {
+                        self.leaving = true;
+                        leave_deadline = Some(tokio::time::Instant::now() + LEAVE_TIMEOUT);
+                    }
+                    if writer.send(msg.clone()).await.is_err() {
+                        self.queued.push_back(msg);
+                        return Stopped::Lost { retry_after };
+                    }
+                }
+                // the server is slow to see us out, go anyway
+                _ = tokio::time::sleep_until(leave_deadline.unwrap_or_else(tokio::time::Instant::now)), if leave_deadline.is_some() => {
+                    return Stopped::Lost { retry_after: None };
+                }
+                _ = ticker.tick(), if heartbeat => {
+                    let silent = last_heard.elapsed();
+                    if silent > 2 * SERVER_TIMEOUT {
+                        return Stopped::Lost { retry_after };
+                    }
+                    if responding && silent > SERVER_TIMEOUT {
+                        responding = false;
+                        self.status(ServerStatus::NotResponding).await;
+                        self.notice(LineKind::Error, "! server is not responding".to_string())
+                            .await;
+                    }
+                    pings_sent += 1;
+                    if writer.send(ClientMessage::Ping(pings_sent)).await.is_err() {
+                        return Stopped::Lost { retry_after };
+                    }
+                }
+            }
+        }
+    }
+
+    /// Logs in again, backing off further after every failed attempt, and
+    /// resumes the session if the server still has it. Returns `None` if the
+    /// UI went away in the meantime.
+    async fn reconnect(
+        &mut self,
+        retry_after: Option<Duration>,
+    ) -> Option<(ServerConnection, Capabilities)> {
+        self.status(ServerStatus::Reconnecting).await;
+        self.notice(
+            LineKind::Error,
+            "! connection to the server was lost, reconnecting".to_string(),
+        )
+        .await;
+        let mut delay = retry_after.unwrap_or(RECONNECT_DELAY);
+        loop {
+            // keep what is typed in the meantime
+            let sleep = tokio::time::sleep(delay);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    msg = self.outgoing.recv() => match msg {
+                        // there is nobody to say goodbye to
+                        Some(ClientMessage::Leave { .. }) => {
+                            let _ = self.events.send(Event::End).await;
+                            return None;
+                        }
+                        Some(msg) => self.queued.push_back(msg),
+                        None => return None,
+                    },
+                }
+            }
+
+            let session = self
+                .session
+                .token
+                .as_deref()
+                .map(|token| (token, self.session.last_seq));
+            let attempt = tokio::time::timeout(LOGIN_TIMEOUT, log_in(&self.login, session)).await;
+            let (connection, capabilities, resumed) = match attempt {
+                Ok(Ok(logged_in)) => logged_in,
+                Ok(Err(e)) => {
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                    self.notice(
+                        LineKind::Error,
+                        format!(
+                            "! reconnecting failed: {e}, retrying in {}s",
+                            delay.as_secs()
+                        ),
+                    )
+                    .await;
+                    continue;
+                }
+                Err(_) => {
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                    self.notice(
+                        LineKind::Error,
+                        format!("! reconnecting timed out, retrying in {}s", delay.as_secs()),
+                    )
+                    .await;
+                    continue;
+                }
+            };
+
+            // what the server knows about us now comes first, then whatever
+            // was typed while it was gone
+            let mut prelude = vec![ClientMessage::ListUsers];
+            if resumed {
+                self.notice(LineKind::Info, "* reconnected, session resumed".to_string())
+                    .await;
+            } else {
+                // a fresh login only gets us back into the default room
+                self.session.token = None;
+                prelude.extend(
+                    self.session
+                        .rooms
+                        .iter()
+                        .filter(|room| *room != DEFAULT_ROOM)
+                        .map(|room| ClientMessage::JoinRoom(room.clone())),
+                );
+                self.notice(
+                    LineKind::Info,
+                    format!("* reconnected as {}", self.login.user),
+                )
+                .await;
+            }
+            for msg in prelude.into_iter().rev() {
+                self.queued.push_front(msg);
+            }
+            self.status(ServerStatus::Up).await;
+            return Some((connection, capabilities));
+        }
+    }
+
+    async fn status(&self, status: ServerStatus) {
+        let _ = self.events.send(Event::Status(status)).await;
+    }
+
+    async fn notice(&self, kind: LineKind, text: String) {
+        let _ = self
+            .events
+            .send(Event::Notice(ChatLine::new(kind, text)))
+            .await;
+    }
+}
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
     // parse command line arguments
@@ -49,53 +676,33 @@ async fn main() -> io::Result<()> {
     // server addr
     let addr_str = std::env::var("SIMPLE_CHAT_ADDR").unwrap_or("127.0.0.1:7878".to_string());
     let addr: SocketAddr = addr_str.parse().unwrap();
-    // connect to server
-    let stream_res = TcpStream::connect(addr).await;
-    let stream = match stream_res {
-        Ok(stream) => stream,
-        Err(e) => {
-            return Err(e);
-        }
+    let connector = match (&args.tls_ca, &args.tls_pin) {
+        (Some(ca), _) => Some(tls::connector_with_ca(ca)?),
+        (None, Some(cert)) => Some(tls::connector_pinned(cert)?),
+        (None, None) => None,
+    };
+    let login = Login {
+        addr,
+        user: args.user.clone(),
+        credential: args
+            .credential
+            .clone()
+            .or_else(|| std::env::var("SIMPLE_CHAT_CREDENTIAL").ok()),
+        connector,
+        server_name: match &args.tls_server_name {
+            Some(name) => name.clone(),
+            None => addr.ip().to_string(),
+        },
     };
-    let (reader, mut writer) = split(stream);
-    let mut reader = BufReader::new(reader);
-
-    // request the server to confirm the username
-    println!("Requesting server to accept username {}", args.user);
-    let user_req = ClientMessage::UserName(args.user.clone());
-    if let Some(bytes) = user_req.to_bytes() {
-        let res = writer.write_all(&bytes).await;
-        res?
-    } else {
-        return Err(io::Error::other(
-            "failed to serialize username request",
-        ));
-    }
 
-    println!("Waiting for server to accept username");
-    let mut user_res_buffer = [0u8; 128];
-    if let Ok(n) = reader.read(&mut user_res_buffer).await {
-        if n == 0 {
-            return Err(io::Error::other(
-                "server closed connection",
-            ));
-        } else {
-            let c = ServerResponse::from_bytes(&user_res_buffer[..n]);
-            if let Some(ServerResponse::UsernameAccepted) = c {
-                println!("Server accepted username {}", args.user);
-            } else if let Some(ServerResponse::ConnectionRefused) = c {
-                return Err(io::Error::other("connection refused"));
-            } else if let Some(ServerResponse::UsernameExists) = c {
-                return Err(io::Error::other(
-                    "username already exists",
-                ));
-            } else {
-                return Err(io::Error::other(
-                    "failed to parse server response",
-                ));
-            }
-        }
-    }
+    // connect to server and have it accept the username
+    println!("Connecting to {addr} with protocol version {PROTOCOL_VERSION}");
+    let (connection, capabilities, _) = log_in(&login, None).await?;
+    println!(
+        "Server accepted username {} with capabilities {:#x}",
+        args.user,
+        capabilities.bits()
+    );
 
     // Once the initial connection is established, we can setup the terminal
     // Setup terminal
@@ -105,29 +712,17 @@ async fn main() -> io::Result<()> {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    // Task: read from server
-    let tx_clone = tx.clone();
-    tokio::spawn(async move {
-        let mut buffer = [0u8; 1024];
-        while let Ok(n) = reader.read(&mut buffer).await {
-            if n == 0 {
-                break;
-            } else {
-                let c = ServerResponse::from_bytes(&buffer[..n]);
-                if let Some(ServerResponse::Broadcast { username, message }) = c {
-                    if let Err(e) = tx_clone
-                        .send(Event::ServerMsg {
-                            from: username,
-                            message: message.clone(),
-                        })
-                        .await
-                    {
-                        eprintln!("failed to send message to UI : {e:?}");
-                    }
-                }
-            }
-        }
-    });
+    // Task: talk to the server, reconnecting whenever it is lost
+    let (outgoing, outgoing_rx) = mpsc::channel::<ClientMessage>(100);
+    let task = ConnectionTask {
+        login,
+        session: Session::default(),
+        queued: VecDeque::new(),
+        outgoing: outgoing_rx,
+        events: tx.clone(),
+        leaving: false,
+    };
+    tokio::spawn(task.run(connection, capabilities));
 
     // Task: poll keyboard
     let tx_clone = tx.clone();
@@ -140,28 +735,62 @@ async fn main() -> io::Result<()> {
                             // handled in main loop
                             let _ = tx_clone.send(Event::Input("\n".to_string())).await;
                         }
-                        KeyCode::Char(c) => {
-                            let _ = tx_clone.send(Event::Input(c.to_string())).await;
-                        }
                         KeyCode::Backspace => {
                             let _ = tx_clone.send(Event::Input("\x08".to_string())).await;
                         }
-                        KeyCode::Esc => {
-                            let _ = tx_clone.send(Event::End).await;
-                            break;
+                        KeyCode::Up => {
+                            let _ = tx_clone.send(Event::Select { older: true }).await;
+                        }
+                        KeyCode::Down => {
+                            let _ = tx_clone.send(Event::Select { older: false }).await;
+                        }
+                        KeyCode::Delete => {
+                            let _ = tx_clone.send(Event::Delete).await;
+                        }
+                        KeyCode::PageUp => {
+                            let _ = tx_clone.send(Event::Scroll { up: true }).await;
+                        }
+                        KeyCode::PageDown => {
+                            let _ = tx_clone.send(Event::Scroll { up: false }).await;
                         }
-                        _ => {
-                            let _ = tx_clone.send(Event::End).await;
-                            break;
+                        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            let _ = tx_clone.send(Event::Leave).await;
                         }
+                        KeyCode::Char(c) => {
+                            let _ = tx_clone.send(Event::Input(c.to_string())).await;
+                        }
+                        KeyCode::Esc => {
+                            let _ = tx_clone.send(Event::Leave).await;
+                        }
+                        _ => {}
                     }
                 }
             }
         }
     });
 
-    let mut messages: Vec<String> = vec![];
+    let mut messages = ChatLog::new();
     let mut input = String::new();
+    // rooms we are a member of, the last one joined is where messages go
+    let mut rooms: Vec<String> = vec![];
+    let mut users: BTreeSet<String> = BTreeSet::new();
+    // the oldest message we know of in each room, scrolling past the top
+    // fetches what came before it
+    let mut oldest_seq: HashMap<String, u64> = HashMap::new();
+    // rooms whose history has been fetched all the way back
+    let mut history_exhausted: HashSet<String> = HashSet::new();
+    let mut fetching_history = false;
+    // height of the chat window as of the last draw
+    let mut chat_height = 0;
+    let mut status = ServerStatus::Up;
+    // waiting for the server to see us out
+    let mut leaving = false;
+    // why the server saw us out, shown once the terminal is restored
+    let mut farewell: Option<String> = None;
+    // tells the server's answers to our messages apart
+    let mut next_nonce: u64 = 0;
+    // the id of our message the input is editing
+    let mut editing: Option<u64> = None;
 
     // Main UI loop
     loop {
@@ -172,15 +801,49 @@ async fn main() -> io::Result<()> {
                 .margin(1)
                 .constraints([Constraint::Min(5), Constraint::Length(3)].as_ref())
                 .split(f.size());
+            let top = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Min(20), Constraint::Length(24)].as_ref())
+                .split(chunks[0]);
 
-            let chat_text: Vec<Line> = messages.iter().map(|m| Line::from(m.clone())).collect();
+            chat_height = top[0].height.saturating_sub(2) as usize;
+            let chat_text: Vec<Line> = messages
+                .lines
+                .iter()
+                .map(|line| line.to_line(editing.is_some() && line.id() == editing))
+                .collect();
+            let mut title = match rooms.last() {
+                Some(room) => format!("Chat #{}", sanitize(room)),
+                None => "Chat (no room, join one)".to_string(),
+            };
+            match status {
+                ServerStatus::Up => {}
+                ServerStatus::NotResponding => title.push_str(" - server not responding"),
+                ServerStatus::Reconnecting => title.push_str(" - reconnecting"),
+            }
             let chat_box = Paragraph::new(chat_text)
-                .block(Block::default().borders(Borders::ALL).title("Chat"));
-            f.render_widget(chat_box, chunks[0]);
+                .block(Block::default().borders(Borders::ALL).title(title))
+                .scroll((messages.offset(chat_height), 0));
+            f.render_widget(chat_box, top[0]);
+
+            let user_text: Vec<Line> = users
+                .iter()
+                .map(|u| Line::from(sanitize(u).into_owned()))
+                .collect();
+            let users_box = Paragraph::new(user_text).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(format!("Online ({})", users.len())),
+            );
+            f.render_widget(users_box, top[1]);
 
+            let input_title = match editing {
+                Some(_) => "Edit (Enter saves, Del deletes, Esc cancels)",
+                None => "Input",
+            };
             let input_box = Paragraph::new(input.clone())
                 .style(Style::default().fg(Color::Yellow))
-                .block(Block::default().borders(Borders::ALL).title("Input"));
+                .block(Block::default().borders(Borders::ALL).title(input_title));
             f.render_widget(input_box, chunks[1]);
         })?;
 
@@ -192,23 +855,226 @@ async fn main() -> io::Result<()> {
         // Handle events
         let next_action = if let Some(event) = rx.recv().await {
             match event {
-                Event::ServerMsg { from, message } => {
-                    messages.push(format!("{from}: {message}"));
+                Event::Server(response) => {
+                    match response {
+                        ServerResponse::RoomJoined { room } => {
+                            messages
+                                .push(ChatLine::new(LineKind::Info, format!("* joined #{room}")));
+                            rooms.retain(|r| *r != room);
+                            rooms.push(room);
+                        }
+                        ServerResponse::RoomLeft { room } => {
+                            messages.push(ChatLine::new(LineKind::Info, format!("* left #{room}")));
+                            rooms.retain(|r| *r != room);
+                        }
+                        ServerResponse::HistoryReplay {
+                            room,
+                            entries,
+                            has_more,
+                        } => {
+                            messages.push(ChatLine::new(
+                                LineKind::Info,
+                                format!("* last {} messages in #{room}", entries.len()),
+                            ));
+                            if let Some(first) = entries.first() {
+                                note_seq(&mut oldest_seq, &room, first.seq);
+                            }
+                            for entry in &entries {
+                                messages.push(history_line(entry));
+                            }
+                            // more was missed than fits a page, read on
+                            if let (true, Some(last)) = (has_more, entries.last()) {
+                                let request = ClientMessage::FetchHistory {
+                                    room,
+                                    before_seq: None,
+                                    after_seq: Some(last.seq),
+                                    limit: HISTORY_PAGE,
+                                };
+                                if let Err(e) = outgoing.send(request).await {
+                                    eprintln!("failed to request history : {e}");
+                                }
+                            }
+                        }
+                        ServerResponse::HistoryPage {
+                            room,
+                            query: None,
+                            entries,
+                            has_more,
+                        } => {
+                            fetching_history = false;
+                            if let Some(first) = entries.first() {
+                                note_seq(&mut oldest_seq, &room, first.seq);
+                            }
+                            let mut lines: Vec<ChatLine> = vec![];
+                            if !has_more {
+                                history_exhausted.insert(room.clone());
+                                lines.push(ChatLine::new(
+                                    LineKind::Info,
+                                    format!("* start of #{room}"),
+                                ));
+                            }
+                            lines.extend(entries.iter().map(history_line));
+                            messages.prepend(lines);
+                        }
+                        ServerResponse::HistoryPage {
+                            room,
+                            query: Some(query),
+                            entries,
+                            has_more,
+                        } => {
+                            let more = if has_more { ", showing the latest" } else { "" };
+                            messages.push(ChatLine::new(
+                                LineKind::Info,
+                                format!(
+                                    "* {} matches for '{query}' in #{room}{more}",
+                                    entries.len()
+                                ),
+                            ));
+                            for entry in &entries {
+                                messages.push(history_line(entry));
+                            }
+                        }
+                        ServerResponse::UserList(list) => {
+                            users = list.into_iter().collect();
+                        }
+                        ServerResponse::UserJoined { username } => {
+                            messages.push(ChatLine::new(
+                                LineKind::Info,
+                                format!("* {username} is online"),
+                            ));
+                            users.insert(username);
+                        }
+                        ServerResponse::UserLeft { username, reason } => {
+                            let text = match reason {
+                                Some(reason) => format!("* {username} left: {reason}"),
+                                None => format!("* {username} went offline"),
+                            };
+                            messages.push(ChatLine::new(LineKind::Info, text));
+                            users.remove(&username);
+                        }
+                        ServerResponse::NickChanged { old, new } => {
+                            messages.push(ChatLine::new(
+                                LineKind::Info,
+                                format!("* {old} is now known as {new}"),
+                            ));
+                            users.remove(&old);
+                            users.insert(new);
+                        }
+                        ServerResponse::Kicked { reason } => {
+                            let text = match reason {
+                                Some(reason) => {
+                                    format!("! removed by an operator: {reason}")
+                                }
+                                None => "! removed by an operator".to_string(),
+                            };
+                            let line = ChatLine::new(LineKind::Error, text);
+                            farewell = Some(line.text.clone());
+                            messages.push(line);
+                        }
+                        ServerResponse::Ack { nonce, seq } => messages.confirm(nonce, seq),
+                        ServerResponse::Nack { nonce, reason } => {
+                            messages.fail(nonce);
+                            messages.push(ChatLine::new(
+                                LineKind::Error,
+                                format!("! not sent, {reason}"),
+                            ));
+                        }
+                        ServerResponse::MessageEdited {
+                            envelope, message, ..
+                        } => messages.amend(envelope.id, Some(&message)),
+                        ServerResponse::MessageDeleted { envelope, .. } => {
+                            messages.amend(envelope.id, None);
+                            if editing == Some(envelope.id) {
+                                editing = None;
+                                input.clear();
+                            }
+                        }
+                        other => {
+                            if let ServerResponse::Broadcast { room, envelope, .. } = &other {
+                                note_seq(&mut oldest_seq, room, envelope.seq);
+                            }
+                            if let Some(line) = describe(&other) {
+                                messages.push(line);
+                            }
+                        }
+                    }
                     NextAction::Continue
                 }
                 Event::Input(s) => {
                     if s == "\n" {
-                        if !input.is_empty() {
+                        if let Some(id) = editing {
+                            if !input.trim().is_empty() {
+                                editing = None;
+                                let new_text = std::mem::take(&mut input).trim().to_string();
+                                let request = ClientMessage::EditMessage { id, new_text };
+                                if let Err(e) = outgoing.send(request).await {
+                                    eprintln!("failed to send edit : {e}");
+                                }
+                            }
+                        } else if !input.is_empty() {
                             // Send to server
-                            let client_message = ClientMessage::Message(input.clone());
-                            let bytes = client_message.to_bytes();
-                            if let Some(bytes) = bytes {
-                                writer.write_all(&bytes).await.unwrap();
-                                writer.write_all(b"\n").await.unwrap();
-                                messages.push(format!("Me: {input}"));
-                                input.clear();
-                            } else {
-                                eprintln!("failed to serialize message");
+                            let line = std::mem::take(&mut input);
+                            match parse_input(&line, rooms.last()) {
+                                Ok(Command::Help) => {
+                                    for line in HELP {
+                                        messages
+                                            .push(ChatLine::new(LineKind::Info, line.to_string()));
+                                    }
+                                }
+                                Ok(Command::Request(mut client_message)) => {
+                                    let echo = match &mut client_message {
+                                        ClientMessage::Message {
+                                            room,
+                                            message,
+                                            nonce,
+                                        } => {
+                                            next_nonce += 1;
+                                            *nonce = Some(next_nonce);
+                                            Some(
+                                                ChatLine::posted(
+                                                    LineKind::Own,
+                                                    format!(
+                                                        "{} [{room}] Me: ",
+                                                        local_time(now_millis())
+                                                    ),
+                                                    message,
+                                                    None,
+                                                )
+                                                .sent(next_nonce),
+                                            )
+                                        }
+                                        ClientMessage::DirectMessage { to, message, nonce } => {
+                                            next_nonce += 1;
+                                            *nonce = Some(next_nonce);
+                                            Some(
+                                                ChatLine::new(
+                                                    LineKind::Direct,
+                                                    format!(
+                                                        "{} -> {to}: {message}",
+                                                        local_time(now_millis())
+                                                    ),
+                                                )
+                                                .sent(next_nonce),
+                                            )
+                                        }
+                                        ClientMessage::Leave { .. } => {
+                                            leaving = true;
+                                            Some(ChatLine::new(
+                                                LineKind::Info,
+                                                "* leaving".to_string(),
+                                            ))
+                                        }
+                                        _ => None,
+                                    };
+                                    if let Err(e) = outgoing.send(client_message).await {
+                                        eprintln!("failed to send message : {e}");
+                                    } else if let Some(echo) = echo {
+                                        messages.push(echo);
+                                    }
+                                }
+                                Err(e) => {
+                                    messages.push(ChatLine::new(LineKind::Error, format!("! {e}")))
+                                }
                             }
                         }
                     } else if s == "\x08" {
@@ -218,6 +1084,81 @@ async fn main() -> io::Result<()> {
                     }
                     NextAction::Continue
                 }
+                Event::Select { older } => {
+                    let picked = messages.pick(editing, older);
+                    if picked.is_some() || editing.is_some() {
+                        editing = picked;
+                        input = picked
+                            .and_then(|id| messages.message(id))
+                            .unwrap_or_default()
+                            .to_string();
+                    }
+                    NextAction::Continue
+                }
+                Event::Delete => {
+                    if let Some(id) = editing.take() {
+                        input.clear();
+                        if let Err(e) = outgoing.send(ClientMessage::DeleteMessage { id }).await {
+                            eprintln!("failed to send delete : {e}");
+                        }
+                    }
+                    NextAction::Continue
+                }
+                // stop editing rather than quit
+                Event::Leave if editing.is_some() => {
+                    editing = None;
+                    input.clear();
+                    NextAction::Continue
+                }
+                Event::Leave if leaving => NextAction::Continue,
+                Event::Leave => {
+                    leaving = true;
+                    messages.push(ChatLine::new(LineKind::Info, "* leaving".to_string()));
+                    match outgoing.send(ClientMessage::Leave { reason: None }).await {
+                        Ok(()) => NextAction::Continue,
+                        // nobody left to say goodbye to
+                        Err(_) => NextAction::Break,
+                    }
+                }
+                Event::Scroll { up: true } => {
+                    let page = (chat_height / 2).max(1);
+                    let at_top = !messages.scroll_up(page, chat_height);
+                    // reaching the top asks for what came before
+                    if let (true, false, Some(room)) = (at_top, fetching_history, rooms.last()) {
+                        if !history_exhausted.contains(room) {
+                            let request = ClientMessage::FetchHistory {
+                                room: room.clone(),
+                                before_seq: oldest_seq.get(room).copied(),
+                                after_seq: None,
+                                limit: HISTORY_PAGE,
+                            };
+                            if let Err(e) = outgoing.send(request).await {
+                                eprintln!("failed to request history : {e}");
+                            } else {
+                                fetching_history = true;
+                            }
+                        }
+                    }
+                    NextAction::Continue
+                }
+                Event::Scroll { up: false } => {
+                    messages.scroll_down((chat_height / 2).max(1));
+                    NextAction::Continue
+                }
+                Event::Status(new_status) => {
+                    // an answer to a request made before the connection was
+                    // lost is not coming
+                    if new_status == ServerStatus::Reconnecting {
+                        fetching_history = false;
+                        messages.fail_pending();
+                    }
+                    status = new_status;
+                    NextAction::Continue
+                }
+                Event::Notice(line) => {
+                    messages.push(line);
+                    NextAction::Continue
+                }
                 Event::End => NextAction::Break,
             }
         } else {
@@ -232,9 +1173,377 @@ async fn main() -> io::Result<()> {
     }
 
     // Cleanup
-    // (unreachable in this infinite loop unless Esc handling is added properly)
     disable_raw_mode()?;
     execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
     terminal.show_cursor()?;
+    if let Some(farewell) = farewell {
+        println!("{farewell}");
+    }
     Ok(())
 }
+
+/// What a line typed at the prompt asks for.
+enum Command {
+    Request(ClientMessage),
+    Help,
+}
+
+/// Shown by `help`.
+const HELP: &[&str] = &[
+    "* send <text>        message the current room",
+    "* msg <user> <text>  message a single user",
+    "* join <room>        join a room, or switch to it",
+    "* part [room]        leave a room, the current one by default",
+    "* rooms              list the rooms",
+    "* nick <name>        change your username",
+    "* Up and Down        pick one of your messages to edit, Del deletes it",
+    "* search <text>      search the history of the current room",
+    "* leave [reason]     say goodbye and quit, as does Esc",
+    "* kick <user> [reason], ban <user> [reason], banip <user> [reason],",
+    "  mute <user> <seconds> and unmute <user> are for operators",
+    "* help               show this list",
+    "* commands may also be written with a leading /",
+];
+
+/// Turns a line typed at the prompt into a command. A line is a command name,
+/// optionally preceded by a `/`, followed by its arguments.
+fn parse_input(line: &str, current_room: Option<&String>) -> Result<Command, String> {
+    let line = line.trim();
+    let command = line.strip_prefix('/').unwrap_or(line);
+    let (name, arg) = match command.split_once(' ') {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (command, ""),
+    };
+    let request = match name {
+        "send" => match (arg, current_room) {
+            ("", _) => return Err("usage: send <text>".to_string()),
+            (_, None) => return Err("you are not in any room, join one first".to_string()),
+            (message, Some(room)) => ClientMessage::Message {
+                room: room.clone(),
+                message: message.to_string(),
+                nonce: None,
+            },
+        },
+        "leave" => ClientMessage::Leave {
+            reason: (!arg.is_empty()).then(|| arg.to_string()),
+        },
+        "help" => return Ok(Command::Help),
+        "join" if arg.is_empty() => return Err("usage: join <room>".to_string()),
+        "join" => ClientMessage::JoinRoom(arg.to_string()),
+        "part" => match (arg, current_room) {
+            ("", Some(room)) => ClientMessage::LeaveRoom(room.clone()),
+            ("", None) => return Err("usage: part <room>".to_string()),
+            (room, _) => ClientMessage::LeaveRoom(room.to_string()),
+        },
+        "rooms" => ClientMessage::ListRooms,
+        "nick" if arg.is_empty() || arg.contains(' ') => {
+            return Err("usage: nick <name>".to_string())
+        }
+        "nick" => ClientMessage::ChangeNick {
+            new_name: arg.to_string(),
+        },
+        "kick" | "ban" | "banip" => {
+            let (username, reason) = match arg.split_once(' ') {
+                Some((username, reason)) => (username, Some(reason.trim().to_string())),
+                None => (arg, None),
+            };
+            if username.is_empty() {
+                return Err(format!("usage: {name} <user> [reason]"));
+            }
+            let username = username.to_string();
+            match name {
+                "kick" => ClientMessage::Kick { username, reason },
+                _ => ClientMessage::Ban {
+                    username,
+                    reason,
+                    ip: name == "banip",
+                },
+            }
+        }
+        "mute" => match arg
+            .split_once(' ')
+            .map(|(u, s)| (u, s.trim().parse::<u64>()))
+        {
+            Some((username, Ok(secs))) if secs > 0 => ClientMessage::Mute {
+                username: username.to_string(),
+                duration: Duration::from_secs(secs),
+            },
+            _ => return Err("usage: mute <user> <seconds>".to_string()),
+        },
+        "unmute" if arg.is_empty() => return Err("usage: unmute <user>".to_string()),
+        "unmute" => ClientMessage::Mute {
+            username: arg.to_string(),
+            duration: Duration::ZERO,
+        },
+        "search" => match (arg, current_room) {
+            ("", _) => return Err("usage: search <text>".to_string()),
+            (_, None) => return Err("you are not in any room, join one first".to_string()),
+            (query, Some(room)) => ClientMessage::SearchHistory {
+                room: room.clone(),
+                query: query.to_string(),
+                limit: HISTORY_PAGE,
+            },
+        },
+        "msg" => match arg.split_once(' ') {
+            Some((to, message)) if !message.trim().is_empty() => ClientMessage::DirectMessage {
+                to: to.to_string(),
+                message: message.trim().to_string(),
+                nonce: None,
+            },
+            _ => return Err("usage: msg <user> <text>".to_string()),
+        },
+        _ => return Err(format!("unknown command '{name}', type help for a list")),
+    };
+    Ok(Command::Request(request))
+}
+
+/// Remembers `seq` if it is the oldest message seen so far in `room`.
+fn note_seq(oldest_seq: &mut HashMap<String, u64>, room: &str, seq: u64) {
+    oldest_seq
+        .entry(room.to_string())
+        .and_modify(|oldest| *oldest = (*oldest).min(seq))
+        .or_insert(seq);
+}
+
+fn history_line(entry: &HistoryEntry) -> ChatLine {
+    let mut line = ChatLine::posted(
+        LineKind::History,
+        format!(
+            "{} [{}] {}: ",
+            local_time(entry.timestamp_ms),
+            entry.room,
+            entry.username
+        ),
+        &entry.message,
+        Some(entry.seq),
+    );
+    if let Some(posted) = line.posted.as_mut() {
+        posted.edited = entry.edited_ms.is_some();
+    }
+    line
+}
+
+/// The server's timestamp for a message in the user's own time zone.
+fn local_time(timestamp_ms: u64) -> String {
+    match DateTime::from_timestamp_millis(timestamp_ms as i64) {
+        Some(time) => time.with_timezone(&Local).format("%H:%M").to_string(),
+        None => "--:--".to_string(),
+    }
+}
+
+/// The line shown in the chat window for a response from the server, if any.
+fn describe(response: &ServerResponse) -> Option<ChatLine> {
+    let (kind, text) = match response {
+        ServerResponse::Broadcast {
+            room,
+            envelope,
+            username,
+            message,
+        } => {
+            return Some(ChatLine::posted(
+                LineKind::Chat,
+                format!(
+                    "{} [{room}] {username}: ",
+                    local_time(envelope.timestamp_ms)
+                ),
+                message,
+                Some(envelope.id),
+            ))
+        }
+        ServerResponse::Direct {
+            envelope,
+            from,
+            message,
+        } => (
+            LineKind::Direct,
+            format!("{} <- {from}: {message}", local_time(envelope.timestamp_ms)),
+        ),
+        ServerResponse::RoomList(rooms) => {
+            let rooms: Vec<String> = rooms
+                .iter()
+                .map(|room| format!("#{} ({})", room.name, room.members))
+                .collect();
+            (LineKind::Info, format!("* rooms: {}", rooms.join(", ")))
+        }
+        ServerResponse::NotInRoom { room } => {
+            (LineKind::Error, format!("! you are not in #{room}"))
+        }
+        ServerResponse::InvalidRoomName { room } => (
+            LineKind::Error,
+            format!("! '{room}' is not a valid room name"),
+        ),
+        ServerResponse::UserNotFound { username } => (
+            LineKind::Error,
+            format!("! no user named {username} is online"),
+        ),
+        ServerResponse::MessagesDropped { count } => (
+            LineKind::Error,
+            format!("! {count} messages were dropped because the client fell behind"),
+        ),
+        ServerResponse::ServerShuttingDown {
+            reason,
+            reconnect_after,
+        } => {
+            let text = match reconnect_after {
+                Some(after) => format!(
+                    "! server shutting down: {reason}, back in about {}s",
+                    after.as_secs()
+                ),
+                None => format!("! server shutting down: {reason}"),
+            };
+            (LineKind::Error, text)
+        }
+        ServerResponse::Muted { until } => {
+            let left = until.saturating_sub(now_millis()).div_ceil(1000);
+            if left == 0 {
+                (LineKind::Info, "* you are no longer muted".to_string())
+            } else {
+                (
+                    LineKind::Error,
+                    format!("! you are muted for another {left}s"),
+                )
+            }
+        }
+        ServerResponse::MessageNotFound { .. } => (
+            LineKind::Error,
+            "! that message is no longer kept".to_string(),
+        ),
+        ServerResponse::NotPermitted => (
+            LineKind::Error,
+            "! you are not allowed to do that".to_string(),
+        ),
+        ServerResponse::ModerationApplied { username } => {
+            (LineKind::Info, format!("* done with {username}"))
+        }
+        ServerResponse::UsernameExists => (
+            LineKind::Error,
+            "! somebody else has that username".to_string(),
+        ),
+        ServerResponse::UsernameRejected { reason } => (LineKind::Error, format!("! {reason}")),
+        ServerResponse::MessageRejected { reason } => {
+            (LineKind::Error, format!("! not sent, {reason}"))
+        }
+        ServerResponse::RateLimited { retry_after } => (
+            LineKind::Error,
+            format!(
+                "! slow down, that went nowhere, try again in {:.1}s",
+                retry_after.as_secs_f64()
+            ),
+        ),
+        _ => return None,
+    };
+    Some(ChatLine::new(kind, text))
+}
+
+/// Connects to the server and logs in, taking back the session with the
+/// given token if the server still has it. Returns the connection, what the
+/// server can do and whether the session was resumed.
+async fn log_in(
+    login: &Login,
+    session: Option<(&str, Option<u64>)>,
+) -> io::Result<(ServerConnection, Capabilities, bool)> {
+    let stream = TcpStream::connect(login.addr).await?;
+    let stream: BoxedStream = match &login.connector {
+        Some(connector) => {
+            let stream = connector
+                .connect(tls::server_name(&login.server_name)?, stream)
+                .await
+                .map_err(|e| io::Error::other(format!("tls handshake failed: {e}")))?;
+            Box::new(stream)
+        }
+        None => Box::new(stream),
+    };
+    let mut connection = Framed::new(stream, ClientCodec::new());
+
+    // agree on a protocol version before anything else
+    let hello = ClientMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: Capabilities::supported(),
+    };
+    send(&mut connection, hello).await?;
+    let capabilities = match read_response(&mut connection).await? {
+        ServerResponse::HelloAck { capabilities, .. } => capabilities,
+        ServerResponse::ConnectionRefused { reason } => {
+            return Err(io::Error::other(format!("connection refused: {reason}")));
+        }
+        _ => {
+            return Err(io::Error::other("unexpected server response"));
+        }
+    };
+
+    if let (Some((token, last_seq)), true) = (session, capabilities.contains(Capabilities::RESUME))
+    {
+        let resume = ClientMessage::Resume {
+            token: token.to_string(),
+            last_seq,
+        };
+        send(&mut connection, resume).await?;
+        match read_response(&mut connection).await? {
+            ServerResponse::SessionResumed { .. } => return Ok((connection, capabilities, true)),
+            // too late, log in as if for the first time
+            ServerResponse::SessionExpired => {}
+            _ => {
+                return Err(io::Error::other("unexpected server response"));
+            }
+        }
+    }
+
+    // request the server to confirm the username, proving we own it if the
+    // server asks for that
+    let user_req = if capabilities.contains(Capabilities::AUTHENTICATION) {
+        let credential = login
+            .credential
+            .clone()
+            .ok_or_else(|| io::Error::other("server requires authentication, pass --credential"))?;
+        ClientMessage::Authenticate {
+            username: login.user.clone(),
+            credential,
+        }
+    } else {
+        ClientMessage::UserName(login.user.clone())
+    };
+    send(&mut connection, user_req).await?;
+    match read_response(&mut connection).await? {
+        ServerResponse::UsernameAccepted => Ok((connection, capabilities, false)),
+        ServerResponse::ConnectionRefused { reason } => {
+            Err(io::Error::other(format!("connection refused: {reason}")))
+        }
+        ServerResponse::UsernameExists => Err(io::Error::other("username already exists")),
+        ServerResponse::UsernameRejected { reason } => {
+            Err(io::Error::other(format!("username refused: {reason}")))
+        }
+        ServerResponse::InvalidCredentials => {
+            Err(io::Error::other("invalid username or credential"))
+        }
+        ServerResponse::AuthenticationRequired => {
+            Err(io::Error::other("server requires authentication"))
+        }
+        _ => Err(io::Error::other("unexpected server response")),
+    }
+}
+
+async fn send(connection: &mut ServerConnection, msg: ClientMessage) -> io::Result<()> {
+    connection
+        .send(msg)
+        .await
+        .map_err(|e| io::Error::other(format!("failed to send to server: {e}")))
+}
+
+/// Waits for the next frame from the server while logging in, answering any
+/// pings on the way.
+async fn read_response(connection: &mut ServerConnection) -> io::Result<ServerResponse> {
+    loop {
+        match connection.next().await {
+            Some(Ok(ServerResponse::Ping(value))) => {
+                send(connection, ClientMessage::Pong(value)).await?;
+            }
+            Some(Ok(response)) => return Ok(response),
+            Some(Err(e)) => {
+                return Err(io::Error::other(format!(
+                    "failed to read server response: {e}"
+                )))
+            }
+            None => return Err(io::Error::other("server closed connection")),
+        }
+    }
+}
@@ -1,5 +1,138 @@
-use simple_lib::{actor_impl::server_impl::init_central_controller};
-use tokio::{io, net::TcpListener};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{Parser, ValueEnum};
+use simple_lib::{
+    actor::{outbound::BackpressurePolicy, rate_limit::RateLimit, tcp_handler::Heartbeat},
+    actor_impl::server_impl::{default_shards, ServerConfig},
+    auth::{
+        password::hash_password, Authenticator, PasswordFileAuthenticator, StaticTokenAuthenticator,
+    },
+    history::{FileHistoryStore, HistoryStore, MemoryHistoryStore},
+    moderation::BanList,
+    msg::validate::{MessageRules, DEFAULT_MAX_MESSAGE_LEN},
+    server::ChatServer,
+    transport::tls,
+};
+use tokio::io;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum HistoryBackend {
+    /// Keep recent messages in memory only
+    Memory,
+    /// Append every message to a file and keep recent ones in memory
+    File,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Backpressure {
+    /// Forget the oldest queued message to make room for a new one
+    DropOldest,
+    /// Forget new messages until the client catches up
+    DropNewest,
+    /// Forget new messages and disconnect the client if it loses too many
+    Disconnect,
+}
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// Where broadcast messages are recorded
+    #[arg(long, value_enum, default_value_t = HistoryBackend::Memory)]
+    history: HistoryBackend,
+    /// File used by the file history backend
+    #[arg(long, default_value = "history.jsonl")]
+    history_file: PathBuf,
+    /// Number of messages kept in memory
+    #[arg(long, default_value_t = 1000)]
+    history_capacity: usize,
+    /// Number of past messages replayed to a user joining a room
+    #[arg(long, default_value_t = 50)]
+    replay: usize,
+    /// Require users to log in with a password from this file of
+    /// `username:argon2-hash` lines
+    #[arg(long, conflicts_with = "auth_tokens")]
+    auth_users: Option<PathBuf>,
+    /// Require users to log in with a token from this file of
+    /// `username:token` lines
+    #[arg(long)]
+    auth_tokens: Option<PathBuf>,
+    /// Serve over tls with the certificate chain in this PEM file
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// Private key in PEM format for the tls certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Number of messages that may wait to be written to a single client
+    #[arg(long, default_value_t = 1024)]
+    queue_capacity: usize,
+    /// What to do with messages for a client whose queue is full
+    #[arg(long, value_enum, default_value_t = Backpressure::DropOldest)]
+    backpressure: Backpressure,
+    /// Messages a client may lose before `--backpressure disconnect` drops it
+    #[arg(long, default_value_t = 256)]
+    disconnect_after: u64,
+    /// Seconds between pings to clients that answer them, 0 for none
+    #[arg(long, default_value_t = 30)]
+    heartbeat_interval: u64,
+    /// Pings in a row a client may leave unanswered before it is dropped
+    #[arg(long, default_value_t = 2)]
+    missed_heartbeats: u32,
+    /// Seconds a client may stay silent before it is dropped, 0 for no limit
+    #[arg(long, default_value_t = 300)]
+    idle_timeout: u64,
+    /// Seconds a dropped user keeps its name and rooms while it reconnects,
+    /// 0 to forget it at once
+    #[arg(long, default_value_t = 60)]
+    resume_grace: u64,
+    /// Seconds a client has to pick a username before it is dropped, 0 for
+    /// no limit
+    #[arg(long, default_value_t = 30)]
+    registration_timeout: u64,
+    /// Longest chat message in bytes, longer ones are refused
+    #[arg(long, default_value_t = DEFAULT_MAX_MESSAGE_LEN)]
+    max_message_len: usize,
+    /// Chat messages a second each client may send, 0 for no rate limit
+    #[arg(long, default_value_t = 5.0)]
+    message_rate: f64,
+    /// Chat messages a client may send at once before `--message-rate` applies
+    #[arg(long, default_value_t = 10)]
+    message_burst: u32,
+    /// Bytes of chat messages a second each client may send
+    #[arg(long, default_value_t = 16 * 1024)]
+    byte_rate: u64,
+    /// Bytes of chat messages a client may send at once
+    #[arg(long, default_value_t = 64 * 1024)]
+    byte_burst: u64,
+    /// Seconds a client that keeps going over the rate limit is muted for,
+    /// before it is disconnected for doing it again
+    #[arg(long, default_value_t = 30)]
+    flood_mute: u64,
+    /// Users who may kick, ban and mute others, requires `--auth-users` or
+    /// `--auth-tokens`
+    #[arg(long, value_delimiter = ',')]
+    operators: Vec<String>,
+    /// File of banned `name:<username>` and `ip:<address>` lines, new bans are
+    /// added to it
+    #[arg(long)]
+    bans: Option<PathBuf>,
+    /// Number of room shards, defaults to the number of cpus
+    #[arg(long)]
+    shards: Option<usize>,
+    /// Reason given to connected users when the server shuts down
+    #[arg(long, default_value = "server is shutting down")]
+    shutdown_reason: String,
+    /// Seconds users are told to wait before reconnecting after a shutdown
+    #[arg(long)]
+    reconnect_after: Option<u64>,
+    /// Seconds connections get to flush their queued messages on shutdown
+    #[arg(long, default_value_t = 5)]
+    drain_timeout: u64,
+    /// Read a password from stdin, print its hash for the users file and exit
+    #[arg(long)]
+    hash_password: bool,
+}
 
 // The `#[tokio::main]` macro transforms the `main` function into an asynchronous one,
 // setting up the Tokio runtime and executing the async code.
@@ -7,14 +140,113 @@ use tokio::{io, net::TcpListener};
 async fn main() -> io::Result<()> {
     // let s = ServerHandler::new().await?;
     // s.main_loop().await?;
-    _launch_server().await?;
+    let args = Args::parse();
+    if args.hash_password {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password)?;
+        let hash = hash_password(password.trim_end_matches(['\r', '\n']))
+            .map_err(|e| io::Error::other(format!("failed to hash password: {e}")))?;
+        println!("{hash}");
+        return Ok(());
+    }
+    _launch_server(args).await?;
     Ok(())
 }
 
-async fn _launch_server() -> io::Result<()> {
+async fn _launch_server(args: Args) -> io::Result<()> {
+    let history: Arc<dyn HistoryStore> = match args.history {
+        HistoryBackend::Memory => Arc::new(MemoryHistoryStore::new(args.history_capacity)),
+        HistoryBackend::File => Arc::new(FileHistoryStore::open(
+            &args.history_file,
+            args.history_capacity,
+        )?),
+    };
+    let authenticator: Option<Arc<dyn Authenticator>> = match (args.auth_users, args.auth_tokens) {
+        (Some(path), _) => Some(Arc::new(PasswordFileAuthenticator::load(path)?)),
+        (None, Some(path)) => Some(Arc::new(StaticTokenAuthenticator::load(path)?)),
+        (None, None) => None,
+    };
+    let tls = match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::acceptor(cert, key)?),
+        _ => None,
+    };
+    let bans = match args.bans {
+        Some(path) => BanList::load(path)?,
+        None => BanList::new(),
+    };
+    if !args.operators.is_empty() && authenticator.is_none() {
+        // anyone could claim an operator's name
+        return Err(io::Error::other(
+            "--operators requires --auth-users or --auth-tokens",
+        ));
+    }
+    let rate_limit = (args.message_rate > 0.0).then(|| RateLimit {
+        messages_per_sec: args.message_rate,
+        message_burst: args.message_burst.max(1),
+        bytes_per_sec: args.byte_rate as f64,
+        byte_burst: args.byte_burst,
+        mute_for: Duration::from_secs(args.flood_mute),
+        ..RateLimit::default()
+    });
+    let config = ServerConfig {
+        history,
+        replay_len: args.replay,
+        authenticator,
+        tls,
+        queue_capacity: args.queue_capacity,
+        backpressure: match args.backpressure {
+            Backpressure::DropOldest => BackpressurePolicy::DropOldest,
+            Backpressure::DropNewest => BackpressurePolicy::DropNewest,
+            Backpressure::Disconnect => BackpressurePolicy::Disconnect {
+                threshold: args.disconnect_after,
+            },
+        },
+        shards: args.shards.unwrap_or_else(default_shards),
+        heartbeat: Heartbeat {
+            interval: seconds(args.heartbeat_interval),
+            max_missed: args.missed_heartbeats,
+            idle_timeout: seconds(args.idle_timeout),
+        },
+        resume_grace: seconds(args.resume_grace),
+        registration_timeout: seconds(args.registration_timeout),
+        message_rules: MessageRules {
+            max_len: args.max_message_len,
+        },
+        rate_limit,
+        operators: args.operators.into_iter().collect(),
+        bans,
+    };
     let addr = std::env::var("SIMPLE_CHAT_ADDR").unwrap_or("127.0.0.1:7878".to_string());
-    let listener = TcpListener::bind(addr).await?;
-    let join_handle = init_central_controller(1024, listener).await;
-    join_handle.await?;
+    let server = ChatServer::builder().config(config).bind(addr).await?;
+    println!("Listening on {}", server.local_addr());
+    shutdown_signal().await?;
+    server
+        .shutdown(
+            args.shutdown_reason,
+            args.reconnect_after.map(Duration::from_secs),
+            Duration::from_secs(args.drain_timeout),
+        )
+        .await?;
+    println!("Server stopped");
     Ok(())
 }
+
+/// A duration given in seconds on the command line, where 0 means none.
+fn seconds(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Waits for Ctrl-C, or for SIGTERM where there is such a thing.
+async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
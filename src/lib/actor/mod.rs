@@ -1,2 +1,5 @@
-pub mod tcp_handler;
+pub mod outbound;
+pub mod rate_limit;
+pub mod room_shard;
 pub mod server_actor;
+pub mod tcp_handler;
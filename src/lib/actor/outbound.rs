@@ -0,0 +1,212 @@
+/*
+ *  The queue of responses waiting to be written to a single connection
+ *
+ *  Anyone holding the connection's handle pushes without ever waiting, a
+ *  client that reads too slowly loses messages (or its connection) according
+ *  to the queue's policy instead of holding up whoever is sending to it.
+ */
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard};
+
+use tokio::sync::Notify;
+
+use super::tcp_handler::ControllerMessages;
+use crate::msg::{codec::EncodedFrame, ServerResponse};
+
+/// What happens to a response sent to a connection whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BackpressurePolicy {
+    /// Make room by forgetting the oldest queued response, or close the
+    /// connection if there is none to forget.
+    #[default]
+    DropOldest,
+    /// Forget the response being sent.
+    DropNewest,
+    /// Forget the response being sent, and close the connection once more
+    /// than `threshold` responses were lost before the client caught up.
+    Disconnect { threshold: u64 },
+}
+
+pub struct OutboundQueue {
+    capacity: usize,
+    policy: BackpressurePolicy,
+    inner: Mutex<Inner>,
+    // wakes the connection when something was queued
+    notify: Notify,
+    // wakes the connection when it is cut off in the middle of a write
+    cut: Notify,
+}
+
+struct Inner {
+    items: VecDeque<ControllerMessages>,
+    // dropped responses the client has not been told about yet
+    unreported: u64,
+    // whether the client understands `MessagesDropped`
+    reports: bool,
+    // dropped responses since the queue was last empty
+    streak: u64,
+    // set once `Close` is queued, nothing is queued after it
+    closed: bool,
+    // set once the client fell too far behind to keep its connection
+    cut_off: bool,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize, policy: BackpressurePolicy) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            policy,
+            inner: Mutex::new(Inner {
+                items: VecDeque::with_capacity(capacity.max(1)),
+                unreported: 0,
+                reports: false,
+                streak: 0,
+                closed: false,
+                cut_off: false,
+            }),
+            notify: Notify::new(),
+            cut: Notify::new(),
+        }
+    }
+
+    /// Queues a response for the client, applying the policy if the queue
+    /// is full.
+    pub fn push(&self, msg: ServerResponse) {
+        self.enqueue(ControllerMessages::WriteStream(msg));
+    }
+
+    /// Queues a serialized response, subject to the same policy as `push`.
+    pub fn push_frame(&self, frame: EncodedFrame) {
+        self.enqueue(ControllerMessages::WriteFrame(frame));
+    }
+
+    fn enqueue(&self, msg: ControllerMessages) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.closed {
+            return;
+        }
+        if inner.items.len() >= self.capacity {
+            inner.unreported += 1;
+            inner.streak += 1;
+            match self.policy {
+                BackpressurePolicy::DropOldest => {
+                    if !evict_oldest(&mut inner) {
+                        return self.give_up(inner);
+                    }
+                }
+                BackpressurePolicy::DropNewest => return,
+                BackpressurePolicy::Disconnect { threshold } => {
+                    if inner.streak > threshold {
+                        self.give_up(inner);
+                    }
+                    return;
+                }
+            }
+        }
+        inner.items.push_back(msg);
+        drop(inner);
+        self.notify.notify_one();
+    }
+
+    /// Queues a message for the connection itself, which is never dropped.
+    /// A response is dropped to make room for it if the queue is full, and
+    /// the client is cut off if there is none left to drop.
+    pub fn push_control(&self, msg: ControllerMessages) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.closed {
+            return;
+        }
+        if let ControllerMessages::Close = msg {
+            // the one message let past the capacity, as nothing follows it
+            inner.closed = true;
+        } else if inner.items.len() >= self.capacity {
+            if !evict_oldest(&mut inner) {
+                return self.give_up(inner);
+            }
+            inner.unreported += 1;
+        }
+        inner.items.push_back(msg);
+        drop(inner);
+        self.notify.notify_one();
+    }
+
+    /// Sets whether the client is told how many responses it lost.
+    pub fn report_drops(&self, reports: bool) {
+        self.inner.lock().unwrap().reports = reports;
+    }
+
+    /// Gives up on a client that fell too far behind.
+    fn give_up(&self, mut inner: MutexGuard<Inner>) {
+        // nothing queued is worth writing to a client that is about to be
+        // cut off
+        inner.items.clear();
+        inner.closed = true;
+        inner.cut_off = true;
+        drop(inner);
+        self.notify.notify_one();
+        self.cut.notify_waiters();
+    }
+
+    /// Waits for the next message, telling the client how many responses it
+    /// lost before handing out anything newer.
+    pub async fn pop(&self) -> ControllerMessages {
+        loop {
+            {
+                let mut inner = self.inner.lock().unwrap();
+                if inner.cut_off {
+                    return ControllerMessages::Disconnect;
+                }
+                if inner.unreported > 0 && inner.reports && !inner.closed {
+                    let count = std::mem::take(&mut inner.unreported);
+                    return ControllerMessages::WriteStream(ServerResponse::MessagesDropped {
+                        count,
+                    });
+                }
+                if let Some(msg) = inner.items.pop_front() {
+                    if inner.items.is_empty() {
+                        inner.streak = 0;
+                    }
+                    return msg;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Resolves once the client has been cut off, so that a write stuck on
+    /// a client that stopped reading can be abandoned.
+    pub async fn cut_off(&self) {
+        let notified = self.cut.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if self.inner.lock().unwrap().cut_off {
+            return;
+        }
+        notified.await;
+    }
+
+    /// Number of messages waiting to be written.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Forgets the oldest queued response, returning `false` if only messages
+/// for the connection itself are queued.
+fn evict_oldest(inner: &mut Inner) -> bool {
+    let oldest = inner.items.iter().position(|item| {
+        matches!(
+            item,
+            ControllerMessages::WriteStream(_) | ControllerMessages::WriteFrame(_)
+        )
+    });
+    match oldest {
+        Some(oldest) => inner.items.remove(oldest).is_some(),
+        None => false,
+    }
+}
@@ -0,0 +1,160 @@
+/*
+ *  Keeping a single connection from flooding everyone else with messages
+ *
+ *  Every chat message costs a token from a bucket of messages and one of
+ *  bytes, which refill at a steady rate. A message that finds either bucket
+ *  empty is rejected, and too many rejections in too short a time earn the
+ *  connection a mute and eventually its disconnection.
+ */
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// How much a single connection may say.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    // chat messages per second, and how many may be sent at once
+    pub messages_per_sec: f64,
+    pub message_burst: u32,
+    // bytes of chat messages per second, and how many may be sent at once
+    pub bytes_per_sec: f64,
+    pub byte_burst: u64,
+    // rejected messages, beyond one a second, that earn a mute
+    pub strikes: u32,
+    pub mute_for: Duration,
+    // mutes a connection may earn before the next penalty disconnects it
+    pub mutes_before_disconnect: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            messages_per_sec: 5.0,
+            message_burst: 10,
+            bytes_per_sec: 16.0 * 1024.0,
+            byte_burst: 64 * 1024,
+            strikes: 10,
+            mute_for: Duration::from_secs(30),
+            mutes_before_disconnect: 1,
+        }
+    }
+}
+
+/// Tokens that refill at a steady rate up to a capacity.
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    // tokens added per second
+    rate: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            capacity,
+            tokens: capacity,
+            rate,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    /// How long until `cost` tokens are available, zero if they are now. A
+    /// cost above the capacity is charged as a full bucket.
+    pub fn wait_for(&mut self, cost: f64, now: Instant) -> Duration {
+        self.refill(now);
+        let missing = cost.min(self.capacity) - self.tokens;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else if self.rate > 0.0 {
+            Duration::from_secs_f64(missing / self.rate)
+        } else {
+            Duration::MAX
+        }
+    }
+
+    /// Takes `cost` tokens, which the caller made sure are there.
+    pub fn take(&mut self, cost: f64) {
+        self.tokens = (self.tokens - cost.min(self.capacity)).max(0.0);
+    }
+}
+
+/// What becomes of a chat message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    // over the limit, worth trying again after this long
+    Limited { retry_after: Duration },
+    // flooding, muted for this long
+    Mute { duration: Duration },
+    // still flooding after every mute it was given
+    Disconnect,
+}
+
+/// The rate limit of a single connection.
+#[derive(Debug)]
+pub struct FloodGuard {
+    limit: RateLimit,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    // drained by rejected messages, running dry means flooding
+    strikes: TokenBucket,
+    mutes: u32,
+}
+
+impl FloodGuard {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            messages: TokenBucket::new(limit.messages_per_sec, limit.message_burst as f64),
+            bytes: TokenBucket::new(limit.bytes_per_sec, limit.byte_burst as f64),
+            strikes: TokenBucket::new(1.0, limit.strikes as f64),
+            mutes: 0,
+        }
+    }
+
+    /// Decides on a chat message of `len` bytes, charging for it if it is
+    /// allowed.
+    pub fn check(&mut self, len: usize) -> Verdict {
+        let now = Instant::now();
+        let len = len as f64;
+        let wait = self
+            .messages
+            .wait_for(1.0, now)
+            .max(self.bytes.wait_for(len, now));
+        if wait.is_zero() {
+            self.messages.take(1.0);
+            self.bytes.take(len);
+            return Verdict::Allowed;
+        }
+        self.strike(now)
+            .unwrap_or(Verdict::Limited { retry_after: wait })
+    }
+
+    /// Counts a message rejected for any reason against the connection,
+    /// returning the penalty it earned, if any.
+    pub fn strike(&mut self, now: Instant) -> Option<Verdict> {
+        if self.strikes.wait_for(1.0, now).is_zero() {
+            self.strikes.take(1.0);
+            return None;
+        }
+        if self.mutes >= self.limit.mutes_before_disconnect {
+            return Some(Verdict::Disconnect);
+        }
+        self.mutes += 1;
+        // the strikes that earned this mute do not count towards the next
+        self.strikes = TokenBucket::new(1.0, self.limit.strikes as f64);
+        Some(Verdict::Mute {
+            duration: self.limit.mute_for,
+        })
+    }
+}
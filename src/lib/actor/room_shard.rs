@@ -0,0 +1,405 @@
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+
+use crate::{
+    actor_impl::{
+        server_impl::ServerConfig,
+        shard_impl::{Member, ShardMessage, ShardState},
+    },
+    history::now_millis,
+    msg::{
+        codec::ServerCodec, is_valid_room_name, Capabilities, Envelope, NackReason, RoomInfo,
+        ServerResponse, MAX_HISTORY_PAGE,
+    },
+};
+
+/// The Actor struct, responsible for spawning the actor that receive the
+/// messages and then handle them, the actor itself may have a state that can
+/// be affected by the message
+pub struct RoomShard {
+    // which shard this is, only used for logging
+    id: usize,
+    // a handle to the receiver from mpsc::channel so that we can use it to
+    // receive messages
+    receiver: mpsc::Receiver<ShardMessage>,
+    // a receiver to handle poison pill
+    poison_pill: mpsc::Receiver<()>,
+    // the state of the actor that can be modified by the handle function
+    state: ShardState,
+    // settings the server was started with
+    config: ServerConfig,
+    // serializes responses shared by many connections
+    codec: ServerCodec,
+}
+
+impl RoomShard {
+    pub fn new(
+        id: usize,
+        rx: mpsc::Receiver<ShardMessage>,
+        krx: mpsc::Receiver<()>,
+        config: ServerConfig,
+    ) -> Self {
+        RoomShard {
+            id,
+            receiver: rx,
+            poison_pill: krx,
+            state: ShardState::new(),
+            config,
+            codec: ServerCodec::new(),
+        }
+    }
+    pub async fn start(mut self) -> u8 {
+        loop {
+            tokio::select! {
+                Some(msg) = self.receiver.recv() => {
+                    println!("Shard {} received {msg:?}", self.id);
+                    self.handle_message(msg).await;
+                }
+                Some(_p) = self.poison_pill.recv() => {
+                    eprintln!("killing shard {}", self.id);
+                    return 1;
+                }
+                else => {
+                    eprintln!("all senders dropped");
+                    break;
+                }
+            }
+        }
+        0
+    }
+
+    async fn handle_message(&mut self, msg: ShardMessage) {
+        match msg {
+            ShardMessage::JoinRoom {
+                member,
+                room,
+                since,
+            } => {
+                if !is_valid_room_name(&room) {
+                    member.handle.send(ServerResponse::InvalidRoomName { room });
+                    return;
+                }
+                self.enter_room(&member, room, since);
+            }
+            ShardMessage::LeaveRoom { member, room } => {
+                if self.state.leave_room(member.addr, &room) {
+                    member.handle.send(ServerResponse::RoomLeft { room });
+                } else {
+                    member.handle.send(ServerResponse::NotInRoom { room });
+                }
+            }
+            ShardMessage::UserMessage {
+                member,
+                room,
+                message,
+                nonce,
+            } => {
+                let Some(members) = self
+                    .state
+                    .rooms
+                    .get(&room)
+                    .filter(|members| members.contains_key(&member.addr))
+                else {
+                    member.handle.send(match nonce {
+                        Some(nonce) => ServerResponse::Nack {
+                            nonce,
+                            reason: NackReason::NotInRoom { room },
+                        },
+                        None => ServerResponse::NotInRoom { room },
+                    });
+                    return;
+                };
+                let entry =
+                    self.config
+                        .history
+                        .append(&room, &member.name, &member.author, &message);
+                if let Some(nonce) = nonce {
+                    member.handle.send(ServerResponse::Ack {
+                        nonce,
+                        seq: entry.seq,
+                    });
+                }
+                let response = ServerResponse::Broadcast {
+                    envelope: entry.envelope(),
+                    username: member.name.clone(),
+                    room,
+                    message,
+                };
+                // serialized once, every member gets the same buffer
+                let frame = match self.codec.encode_frame(&response) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        eprintln!("failed to encode {response:?}: {e}");
+                        return;
+                    }
+                };
+                for (addr, other) in members.iter() {
+                    if *addr != member.addr {
+                        other.handle.send_frame(frame.clone());
+                    }
+                }
+            }
+            ShardMessage::FetchHistory {
+                member,
+                room,
+                before_seq,
+                after_seq,
+                limit,
+            } => {
+                let query = match after_seq {
+                    Some(seq) => HistoryQuery::After(seq),
+                    None => HistoryQuery::Before(before_seq),
+                };
+                self.serve_history(&member, room, query, limit);
+            }
+            ShardMessage::SearchHistory {
+                member,
+                room,
+                query,
+                limit,
+            } => {
+                self.serve_history(&member, room, HistoryQuery::Search(query), limit);
+            }
+            ShardMessage::Amend {
+                member,
+                id,
+                new_text,
+                operator,
+            } => self.amend(&member, id, new_text, operator).await,
+            ShardMessage::ListRooms { reply } => {
+                let rooms = self
+                    .state
+                    .rooms
+                    .iter()
+                    .map(|(name, members)| RoomInfo {
+                        name: name.clone(),
+                        members: members.len(),
+                    })
+                    .collect();
+                let _ = reply.send(rooms);
+            }
+            ShardMessage::ConnectionDropped { addr } => {
+                self.state.leave_all(addr);
+            }
+        }
+    }
+
+    /// Puts a member in a room and replays the room's recent history to it
+    /// if it was not already a member, or the first page of what it missed
+    /// after `since`.
+    fn enter_room(&mut self, member: &Arc<Member>, room: String, since: Option<u64>) {
+        let joined = self.state.join_room(member, &room);
+        member
+            .handle
+            .send(ServerResponse::RoomJoined { room: room.clone() });
+        if !joined {
+            return;
+        }
+        let limit = MAX_HISTORY_PAGE as usize;
+        let replay = match since {
+            // one extra entry tells us whether the member has to fetch more
+            Some(seq) => self.config.history.after(&room, seq, limit + 1),
+            None if self.config.replay_len > 0 => {
+                self.config.history.recent(&room, self.config.replay_len)
+            }
+            None => return,
+        };
+        match replay {
+            Ok(mut entries) if !entries.is_empty() => {
+                let has_more = entries.len() > limit;
+                entries.truncate(limit);
+                member.handle.send(ServerResponse::HistoryReplay {
+                    room,
+                    entries,
+                    has_more,
+                });
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("failed to read history of {room}: {e}"),
+        }
+    }
+
+    /// Edits or deletes the message `id` if `member` wrote it or is an
+    /// operator, then tells its room, along with whoever changed it. Every
+    /// change to the messages of a room goes through the loop of its shard,
+    /// which waits for the lookup, so nothing can change the message between
+    /// the check and the change.
+    async fn amend(&self, member: &Member, id: u64, new_text: Option<String>, operator: bool) {
+        let history = self.config.history.clone();
+        // the lookup may have to go to disk
+        let mut entry = match tokio::task::spawn_blocking(move || history.find(id)).await {
+            Ok(Ok(Some(entry))) => entry,
+            Ok(Ok(None)) => {
+                member.handle.send(ServerResponse::MessageNotFound { id });
+                return;
+            }
+            Ok(Err(e)) => {
+                eprintln!("failed to look up message {id}: {e}");
+                return;
+            }
+            Err(e) => {
+                eprintln!("lookup of message {id} panicked: {e}");
+                return;
+            }
+        };
+        // entries recorded before authors were are left to operators
+        if !operator && (entry.author.is_empty() || entry.author != member.author) {
+            member.handle.send(ServerResponse::NotPermitted);
+            return;
+        }
+        entry.edited_ms = Some(now_millis());
+        match new_text {
+            Some(new_text) => entry.message = new_text,
+            None => {
+                entry.deleted = true;
+                entry.message.clear();
+            }
+        }
+        if !self.config.history.amend(&entry) {
+            member.handle.send(ServerResponse::MessageNotFound { id });
+            return;
+        }
+        let envelope = Envelope {
+            id: entry.seq,
+            seq: self.config.history.next_seq(),
+            timestamp_ms: entry.edited_ms.unwrap_or_else(now_millis),
+        };
+        let mut recipients: Vec<_> = self
+            .state
+            .rooms
+            .get(&entry.room)
+            .into_iter()
+            .flat_map(|members| members.values())
+            .filter(|other| other.capabilities.contains(Capabilities::EDITS))
+            .map(|other| other.handle.clone())
+            .collect();
+        // an operator may change what was said in a room it is not in
+        if !self.state.is_member(member.addr, &entry.room) {
+            recipients.push(member.handle.clone());
+        }
+        let response = if entry.deleted {
+            ServerResponse::MessageDeleted {
+                room: entry.room,
+                envelope,
+            }
+        } else {
+            ServerResponse::MessageEdited {
+                room: entry.room,
+                envelope,
+                message: entry.message,
+            }
+        };
+        // serialized once, every recipient gets the same buffer
+        let frame = match self.codec.encode_frame(&response) {
+            Ok(frame) => frame,
+            Err(e) => {
+                eprintln!("failed to encode {response:?}: {e}");
+                return;
+            }
+        };
+        for handle in recipients {
+            handle.send_frame(frame.clone());
+        }
+    }
+
+    /// Answers a history request from a member of `room` with a single page.
+    /// The store is queried on the blocking pool, so a query that has to go
+    /// to disk holds up neither this shard nor the live broadcasts.
+    fn serve_history(&self, member: &Member, room: String, query: HistoryQuery, limit: u32) {
+        let handle = member.handle.clone();
+        if !self.state.is_member(member.addr, &room) {
+            handle.send(ServerResponse::NotInRoom { room });
+            return;
+        }
+        let history = self.config.history.clone();
+        let limit = limit.clamp(1, MAX_HISTORY_PAGE) as usize;
+        tokio::spawn(async move {
+            let lookup = (room.clone(), query.clone());
+            // one extra entry tells us whether there is another page
+            let result = tokio::task::spawn_blocking(move || match lookup {
+                (room, HistoryQuery::Before(seq)) => history.before(&room, seq, limit + 1),
+                (room, HistoryQuery::After(seq)) => history.after(&room, seq, limit + 1),
+                (room, HistoryQuery::Search(q)) => history.search(&room, &q, limit + 1),
+            })
+            .await;
+            match result {
+                Ok(Ok(mut entries)) => {
+                    let has_more = entries.len() > limit;
+                    let response = match query {
+                        // read forward, so the extra entry is the newest
+                        HistoryQuery::After(_) => {
+                            entries.truncate(limit);
+                            ServerResponse::HistoryReplay {
+                                room,
+                                entries,
+                                has_more,
+                            }
+                        }
+                        query => {
+                            if has_more {
+                                entries.remove(0);
+                            }
+                            ServerResponse::HistoryPage {
+                                room,
+                                query: match query {
+                                    HistoryQuery::Search(q) => Some(q),
+                                    _ => None,
+                                },
+                                entries,
+                                has_more,
+                            }
+                        }
+                    };
+                    handle.send(response);
+                }
+                Ok(Err(e)) => eprintln!("failed to read history of {room}: {e}"),
+                Err(e) => eprintln!("history lookup for {room} panicked: {e}"),
+            }
+        });
+    }
+}
+
+/// What a client asked to see of a room's history.
+#[derive(Clone)]
+enum HistoryQuery {
+    Before(Option<u64>),
+    After(u64),
+    Search(String),
+}
+
+/// ActorHandle can be used to send messages to the respective actor.
+#[derive(Clone)]
+pub struct RoomShardHandle {
+    // holds a handle to the sender from mpsc::channel
+    id: mpsc::Sender<ShardMessage>,
+    // holds a handle to send poison pill
+    kid: mpsc::Sender<()>,
+}
+
+impl RoomShardHandle {
+    // when we create a new actor what we only return is the handle, during
+    // it's creation we launch the actor and create a handle to it as well.
+    pub fn new(size: usize, id: usize, config: ServerConfig) -> Self {
+        let (tx, rx): (mpsc::Sender<ShardMessage>, mpsc::Receiver<ShardMessage>) =
+            mpsc::channel(size);
+        let (ktx, krx): (mpsc::Sender<()>, mpsc::Receiver<()>) = mpsc::channel(1);
+
+        let actor = RoomShard::new(id, rx, krx, config);
+        tokio::spawn(async move {
+            let res = actor.start().await;
+            eprintln!("Shard exited with : {res}");
+        });
+        RoomShardHandle { id: tx, kid: ktx }
+    }
+    pub async fn send(&self, msg: ShardMessage) -> () {
+        if let Err(e) = self.id.send(msg).await {
+            eprintln!("{e:?}");
+        }
+    }
+    pub async fn terminate(&self, msg: ()) -> () {
+        if let Err(e) = self.kid.send(msg).await {
+            eprintln!("{e:?}");
+        }
+    }
+}
@@ -1,20 +1,41 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 // use crate::actor::traits::{ActorTrait, ServerActorTrait};
-use tokio::{net::TcpListener, sync::mpsc, task::JoinHandle};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::Instant,
+};
 
 use crate::{
-    actor::tcp_handler::TcpActorHandle,
+    actor::{
+        room_shard::RoomShardHandle,
+        tcp_handler::{Registered, TcpActorHandle},
+    },
     actor_impl::{
-        server_impl::{ConnectionMessage, ServerState, CENTRAL_CONTROLLER_HANDLE},
+        server_impl::{Connection, ConnectionMessage, ServerConfig, ServerState, SuspendedSession},
+        shard_impl::{Member, ShardMap, ShardMessage},
         tcp_impl::SingleConnectionState,
     },
-    msg::ServerResponse,
+    auth::{constant_time_eq, random_token, AuthError},
+    history::now_millis,
+    moderation::{record_bans, Ban, Moderation},
+    msg::{
+        codec::{EncodedFrame, ServerCodec},
+        Capabilities, Envelope, NackReason, RoomInfo, ServerResponse, DEFAULT_ROOM,
+        MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    registration::{check_username, fold, NameRejection, Registration},
 };
 
 /// The Actor struct, responsible for spawning the actor that receive the
 /// messages and then handle them, the actor itself may have a state that can
 /// be affected by the message
+///
+/// This actor keeps the registry of connections and usernames, and handles
+/// everything that is not about a single room. Rooms are spread across the
+/// room shards, which connections talk to directly.
 pub struct ServerActor {
     // a handle to the receiver from mpsc::channel so that we can use it to
     // receive messages
@@ -23,74 +44,94 @@ pub struct ServerActor {
     poison_pill: mpsc::Receiver<()>,
     // the state of the actor that can be modified by the handle function
     state: ServerState,
-    // a tcp stream
-    listener: TcpListener,
+    // the task accepting connections, stopped once the server starts
+    // shutting down
+    acceptor: JoinHandle<()>,
+    // tells the acceptor to close the listener
+    stop_accepting: Option<oneshot::Sender<()>>,
+    // the shards owning the rooms
+    shards: ShardMap,
+    // settings the server was started with
+    config: ServerConfig,
+    // a handle to this actor, cloned into every connection so they can
+    // report back
+    this_handle: ServerActorHandler,
+    // set while connections are closed down before the actor exits
+    draining: Option<Draining>,
+    // serializes responses shared by many connections
+    codec: ServerCodec,
+}
+
+/// A shutdown in progress.
+struct Draining {
+    // what connections that show up meanwhile are told
+    reason: String,
+    reconnect_after: Option<Duration>,
+    // connections still open by then are terminated
+    deadline: Instant,
+    // no connection can show up any more
+    listener_closed: bool,
+    // everyone waiting for the shutdown to finish
+    done: Vec<oneshot::Sender<()>>,
 }
 
 impl ServerActor {
     pub fn new(
         rx: mpsc::Receiver<ConnectionMessage>,
         krx: mpsc::Receiver<()>,
-        stream: TcpListener,
+        acceptor: JoinHandle<()>,
+        stop_accepting: oneshot::Sender<()>,
+        shards: ShardMap,
+        config: ServerConfig,
+        this_handle: ServerActorHandler,
     ) -> Self {
+        let mut state = ServerState::new();
+        state.bans = config.bans.clone();
         ServerActor {
             receiver: rx,
             poison_pill: krx,
-            state: ServerState::new(),
-            listener: stream,
+            state,
+            acceptor,
+            stop_accepting: Some(stop_accepting),
+            shards,
+            config,
+            this_handle,
+            draining: None,
+            codec: ServerCodec::new(),
         }
     }
     pub async fn start(mut self) -> u8 {
         loop {
+            let deadline = self.draining.as_ref().map(|draining| draining.deadline);
+            let expiry = self
+                .state
+                .suspended
+                .values()
+                .map(|session| session.expires)
+                .min()
+                .filter(|_| self.draining.is_none());
             tokio::select! {
                 Some(msg) = self.receiver.recv() => {
                     println!("Received {msg:?}");
-                    match msg {
-                        ConnectionMessage::UserMessage { addr, message } => {
-                            if let Some((_,Some(sender_name))) = self.state.connections.get(&addr) {
-                                for (_addr, (handle,_)) in self.state.connections.iter() {
-                                    if *_addr != addr {
-                                        handle.send(ServerResponse::Broadcast { username: format!("{sender_name:?}"), message: message.clone() }).await;
-                                    }
-                                }
-                            }
-                        },
-                        ConnectionMessage::UserCreationRequest { _addr, _name } => {
-                            if !self.state.user_names.contains(&_name) {
-                                self.state.user_names.insert(_name.clone());
-                                self.state.connections.entry(_addr).and_modify(|(_,name)| {
-                                   *name = Some(_name);
-                                });
-                                if let Some((handle,_)) = self.state.connections.get_mut(&_addr) {
-                                    handle.send(ServerResponse::UsernameAccepted).await;
-                                }
-                            } else if let Some((handle,_)) = self.state.connections.get_mut(&_addr) {
-                                handle.send(ServerResponse::UsernameExists).await;
-                                self.state.user_names.insert(_name.clone());
-                            }
-                        }
-                        ConnectionMessage::ConnectionDropped { addr } => {
-                            println!("Connection dropped : {addr:?}");
-                            if let Some((_,Some(name))) = self.state.connections.get(&addr) {
-                                self.state.user_names.remove(name);
-                            }
-                            self.state.connections.remove(&addr);
-                        }
+                    self.handle_message(msg);
+                    if self.is_drained() {
+                        break;
                     }
-                    
                 }
                 Some(_p) = self.poison_pill.recv() => {
                     eprintln!("killing actor");
+                    self.stop_workers().await;
                     return 1;
                 }
-                Ok((stream, addr)) = self.listener.accept() => {
-                    // <A as ServerActorTrait>::handle_connection(&mut self.state, stream, addr);
-                    let this_handle = Arc::new(CENTRAL_CONTROLLER_HANDLE
-                        .get()
-                        .unwrap());
-                    println!("Connection request from : {addr:?}");
-                    let this_connection : TcpActorHandle = TcpActorHandle::new(1024, stream, SingleConnectionState::new(this_handle, addr));
-                    self.state.connections.insert(addr, (this_connection,None));
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    eprintln!("{} connections did not close in time, terminating them", self.state.connections.len());
+                    for (_, conn) in self.state.connections.drain() {
+                        conn.handle.terminate(()).await;
+                    }
+                    break;
+                }
+                _ = tokio::time::sleep_until(expiry.unwrap_or_else(Instant::now)), if expiry.is_some() => {
+                    self.expire_sessions();
                 }
                 else => {
                     eprintln!("all senders dropped");
@@ -102,8 +143,913 @@ impl ServerActor {
                 }
             }
         }
+        self.stop_workers().await;
+        if let Some(draining) = self.draining.take() {
+            for done in draining.done {
+                let _ = done.send(());
+            }
+        }
         0
     }
+
+    fn handle_message(&mut self, msg: ConnectionMessage) {
+        if self.draining.is_some() {
+            return self.handle_draining(msg);
+        }
+        match msg {
+            ConnectionMessage::Connected { addr, handle } => {
+                let conn = Connection::new(handle);
+                if self.state.bans.is_ip_banned(addr.ip()) {
+                    println!("Refusing banned address {addr:?}");
+                    refuse(&conn, "this address is banned");
+                }
+                self.state.connections.insert(addr, conn);
+            }
+            // the listener only closes once the server is shutting down
+            ConnectionMessage::ListenerClosed => {}
+            ConnectionMessage::Hello {
+                addr,
+                protocol_version,
+                capabilities,
+            } => {
+                let offered = self.capabilities();
+                let Some(conn) = self.state.connections.get_mut(&addr) else {
+                    return;
+                };
+                if conn.capabilities.is_some() {
+                    return refuse(conn, "handshake already completed");
+                }
+                if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+                    let reason = format!(
+                        "unsupported protocol version {protocol_version}, \
+                         server speaks {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}"
+                    );
+                    return refuse(conn, &reason);
+                }
+                let negotiated = capabilities.intersection(offered);
+                conn.capabilities = Some(negotiated);
+                conn.handle.negotiated(negotiated);
+                conn.handle.send(ServerResponse::HelloAck {
+                    protocol_version: PROTOCOL_VERSION,
+                    capabilities: negotiated,
+                });
+            }
+            ConnectionMessage::ListRooms { addr } => {
+                let Some(conn) = self.state.connections.get(&addr) else {
+                    return;
+                };
+                // every shard knows only its own rooms, they are asked off
+                // the actor so that it keeps going meanwhile
+                let handle = conn.handle.clone();
+                let shards = self.shards.clone();
+                tokio::spawn(async move {
+                    let mut rooms: Vec<RoomInfo> = Vec::new();
+                    for shard in shards.all() {
+                        let (reply, listed) = oneshot::channel();
+                        shard.send(ShardMessage::ListRooms { reply }).await;
+                        if let Ok(listed) = listed.await {
+                            rooms.extend(listed);
+                        }
+                    }
+                    if !rooms.iter().any(|room| room.name == DEFAULT_ROOM) {
+                        rooms.push(RoomInfo {
+                            name: DEFAULT_ROOM.to_string(),
+                            members: 0,
+                        });
+                    }
+                    rooms.sort_by(|a, b| a.name.cmp(&b.name));
+                    handle.send(ServerResponse::RoomList(rooms));
+                });
+            }
+            ConnectionMessage::DirectMessage {
+                addr,
+                to,
+                message,
+                nonce,
+            } => {
+                let Some(Connection {
+                    registration: Registration::Named { name: sender_name },
+                    handle,
+                    ..
+                }) = self.state.connections.get(&addr)
+                else {
+                    return;
+                };
+                let recipient = self
+                    .state
+                    .user_names
+                    .get(&fold(&to))
+                    .and_then(|to_addr| self.state.connections.get(to_addr));
+                match recipient {
+                    Some(recipient) => {
+                        let seq = self.config.history.next_seq();
+                        recipient.handle.send(ServerResponse::Direct {
+                            envelope: Envelope::new(seq, now_millis()),
+                            from: sender_name.clone(),
+                            message,
+                        });
+                        if let Some(nonce) = nonce {
+                            handle.send(ServerResponse::Ack { nonce, seq });
+                        }
+                    }
+                    None => handle.send(match nonce {
+                        Some(nonce) => ServerResponse::Nack {
+                            nonce,
+                            reason: NackReason::UserNotFound { username: to },
+                        },
+                        None => ServerResponse::UserNotFound { username: to },
+                    }),
+                }
+            }
+            ConnectionMessage::UserCreationRequest { _addr, _name } => {
+                let Some(conn) = self.state.connections.get(&_addr) else {
+                    return;
+                };
+                if conn.capabilities.is_none() {
+                    return refuse(conn, "handshake required before registration");
+                }
+                if self.config.authenticator.is_some() {
+                    conn.handle.send(ServerResponse::AuthenticationRequired);
+                    conn.handle.registration(None);
+                    return;
+                }
+                self.register(_addr, _name);
+            }
+            ConnectionMessage::Authenticate {
+                addr,
+                username,
+                credential,
+            } => {
+                let Some(conn) = self.state.connections.get(&addr) else {
+                    return;
+                };
+                if conn.capabilities.is_none() {
+                    return refuse(conn, "handshake required before registration");
+                }
+                let Some(authenticator) = self.config.authenticator.clone() else {
+                    // an open server takes anyone at their word
+                    return self.register(addr, username);
+                };
+                // password hashing is slow on purpose, so it runs off the
+                // actor and the outcome comes back as a message
+                let this_handle = self.this_handle.clone();
+                tokio::spawn(async move {
+                    let name = username.clone();
+                    let result = tokio::task::spawn_blocking(move || {
+                        authenticator.authenticate(&name, &credential.0)
+                    })
+                    .await;
+                    let result = match result {
+                        Ok(result) => result,
+                        Err(e) => {
+                            eprintln!("authentication of {username} panicked: {e}");
+                            Err(AuthError::InvalidCredential)
+                        }
+                    };
+                    this_handle
+                        .send(ConnectionMessage::AuthenticationResult {
+                            addr,
+                            username,
+                            result,
+                        })
+                        .await;
+                });
+            }
+            ConnectionMessage::AuthenticationResult {
+                addr,
+                username,
+                result,
+            } => match result {
+                Ok(()) => self.register(addr, username),
+                Err(e) => {
+                    println!("Authentication of {username} from {addr:?} failed: {e}");
+                    if let Some(conn) = self.state.connections.get(&addr) {
+                        conn.handle.send(ServerResponse::InvalidCredentials);
+                        conn.handle.registration(None);
+                    }
+                }
+            },
+            ConnectionMessage::ListUsers { addr } => {
+                let Some(conn) = self.state.connections.get(&addr) else {
+                    return;
+                };
+                // users who may be back any moment are still listed
+                let mut users: Vec<String> = self
+                    .state
+                    .connections
+                    .values()
+                    .filter_map(|conn| conn.registration.name())
+                    .chain(self.state.suspended.values().map(|s| s.name.as_str()))
+                    .map(str::to_string)
+                    .collect();
+                users.sort();
+                conn.handle.send(ServerResponse::UserList(users));
+            }
+            ConnectionMessage::Resume {
+                addr,
+                token,
+                last_seq,
+            } => {
+                let Some(conn) = self.state.connections.get(&addr) else {
+                    return;
+                };
+                if conn.capabilities.is_none() {
+                    return refuse(conn, "handshake required before registration");
+                }
+                let now = Instant::now();
+                let key = self
+                    .state
+                    .suspended
+                    .iter()
+                    .find(|(_, session)| {
+                        constant_time_eq(session.token.as_bytes(), token.0.as_bytes())
+                    })
+                    .filter(|(_, session)| {
+                        session.expires > now && conn.registration == Registration::Connected
+                    })
+                    .map(|(key, _)| key.clone());
+                let Some(key) = key else {
+                    conn.handle.send(ServerResponse::SessionExpired);
+                    conn.handle.registration(None);
+                    return;
+                };
+                let Some(session) = self.state.suspended.remove(&key) else {
+                    return;
+                };
+                let name = session.name;
+                println!("Session of {name} resumed from {addr:?}");
+                let Some(conn) = self.state.connections.get_mut(&addr) else {
+                    return;
+                };
+                if let Err(e) = conn.registration.named(name.clone()) {
+                    eprintln!("cannot resume {name} on {addr:?}: {e}");
+                    return;
+                }
+                self.state.user_names.insert(key.clone(), addr);
+                conn.session = Some(session.token);
+                conn.author = Some(session.author.clone());
+                // ahead of the registration, which lets the client be heard
+                conn.handle.muted(self.state.muted.get(&key).copied());
+                // nobody was told it left, so nobody is told it is back
+                conn.handle.registration(Some(Registered {
+                    member: Arc::new(Member {
+                        addr,
+                        name: name.clone(),
+                        author: session.author,
+                        handle: conn.handle.clone(),
+                        capabilities: conn.capabilities.unwrap_or(Capabilities::empty()),
+                    }),
+                    rooms: session.rooms,
+                    since: last_seq,
+                }));
+                conn.handle
+                    .send(ServerResponse::SessionResumed { username: name });
+            }
+            ConnectionMessage::Leave { addr, reason } => self.remove_user(addr, reason),
+            ConnectionMessage::Moderate {
+                addr,
+                username,
+                action,
+            } => self.moderate(addr, username, action),
+            ConnectionMessage::ChangeNick { addr, new_name } => self.change_nick(addr, new_name),
+            ConnectionMessage::Amend {
+                member,
+                id,
+                new_text,
+            } => self.amend(member, id, new_text),
+            ConnectionMessage::RegistrationTimeout { addr } => {
+                let Some(conn) = self.state.connections.get_mut(&addr) else {
+                    return;
+                };
+                if conn.registration == Registration::Connected {
+                    println!("{addr:?} did not register in time");
+                    let _ = conn.registration.leave();
+                    refuse(conn, "no username was given in time");
+                }
+            }
+            ConnectionMessage::FloodMuted { addr, until } => {
+                let Some(name) = self
+                    .state
+                    .connections
+                    .get(&addr)
+                    .and_then(|conn| conn.registration.name())
+                else {
+                    return;
+                };
+                // kept with the operator mutes so a new connection under the
+                // same name is no way out of it either
+                let muted = self.state.muted.entry(fold(name)).or_insert(until);
+                *muted = (*muted).max(until);
+            }
+            ConnectionMessage::Flooded { addr } => {
+                self.kick(
+                    addr,
+                    "disconnected".to_string(),
+                    Some("flooding".to_string()),
+                );
+            }
+            ConnectionMessage::ConnectionDropped { addr, rooms } => {
+                println!("Connection dropped : {addr:?}");
+                // the connection took itself out of its rooms already
+                let Some(Connection {
+                    registration: Registration::Named { name },
+                    author: Some(author),
+                    session,
+                    ..
+                }) = self.state.connections.remove(&addr)
+                else {
+                    return;
+                };
+                let key = fold(&name);
+                self.state.user_names.remove(&key);
+                match (session, self.config.resume_grace) {
+                    (Some(token), Some(grace)) => {
+                        println!("Session of {name} suspended for {grace:?}");
+                        self.state.suspended.insert(
+                            key,
+                            SuspendedSession {
+                                name,
+                                author,
+                                token,
+                                rooms,
+                                expires: Instant::now() + grace,
+                            },
+                        );
+                    }
+                    _ => self.notify_users(
+                        Some(addr),
+                        ServerResponse::UserLeft {
+                            username: name,
+                            reason: None,
+                        },
+                    ),
+                }
+            }
+            ConnectionMessage::Shutdown {
+                reason,
+                reconnect_after,
+                drain_timeout,
+                done,
+            } => {
+                println!("Shutting down: {reason}");
+                // new connections are refused from here on, the ones already
+                // on their way in are told like everyone else
+                if let Some(stop) = self.stop_accepting.take() {
+                    let _ = stop.send(());
+                }
+                for conn in self.state.connections.values() {
+                    conn.handle.send(ServerResponse::ServerShuttingDown {
+                        reason: reason.clone(),
+                        reconnect_after,
+                    });
+                    conn.handle.close();
+                }
+                self.draining = Some(Draining {
+                    reason,
+                    reconnect_after,
+                    deadline: Instant::now() + drain_timeout,
+                    listener_closed: false,
+                    done: vec![done],
+                });
+            }
+        }
+    }
+
+    /// While shutting down only the connections closing matter, nobody is
+    /// told about anyone else leaving.
+    fn handle_draining(&mut self, msg: ConnectionMessage) {
+        let Some(draining) = self.draining.as_mut() else {
+            return;
+        };
+        match msg {
+            // accepted just before the listener was closed
+            ConnectionMessage::Connected { addr, handle } => {
+                handle.send(ServerResponse::ServerShuttingDown {
+                    reason: draining.reason.clone(),
+                    reconnect_after: draining.reconnect_after,
+                });
+                handle.close();
+                self.state.connections.insert(addr, Connection::new(handle));
+            }
+            ConnectionMessage::ConnectionDropped { addr, .. } => {
+                self.state.connections.remove(&addr);
+            }
+            ConnectionMessage::ListenerClosed => draining.listener_closed = true,
+            ConnectionMessage::Shutdown { done, .. } => draining.done.push(done),
+            _ => {}
+        }
+    }
+
+    /// Whether a shutdown is in progress and every connection has closed,
+    /// including the ones accepted before the listener was.
+    fn is_drained(&self) -> bool {
+        self.draining
+            .as_ref()
+            .is_some_and(|draining| draining.listener_closed)
+            && self.state.connections.is_empty()
+    }
+
+    /// The capabilities this server offers during the handshake.
+    fn capabilities(&self) -> Capabilities {
+        let mut offered = Capabilities::supported();
+        if self.config.authenticator.is_none() {
+            offered = offered.difference(Capabilities::AUTHENTICATION);
+        }
+        if self.config.resume_grace.is_none() {
+            offered = offered.difference(Capabilities::RESUME);
+        }
+        offered
+    }
+
+    /// Gives a connection the username it asked for, unless it breaks the
+    /// rules or somebody else already has it, and lets everyone else know it
+    /// arrived.
+    fn register(&mut self, addr: SocketAddr, name: String) {
+        let Some(conn) = self.state.connections.get_mut(&addr) else {
+            return;
+        };
+        let rejection = match &conn.registration {
+            Registration::Connected => check_username(&name).err(),
+            Registration::Named { .. } => Some(NameRejection::AlreadyRegistered),
+            // on its way out already
+            Registration::Left => return,
+        };
+        if let Some(reason) = rejection {
+            println!("Refusing username {name:?} from {addr:?}: {reason}");
+            reject_name(conn, reason);
+            conn.handle.registration(None);
+            return;
+        }
+        if self.state.bans.is_name_banned(&name) {
+            println!("Refusing banned username {name}");
+            return refuse(conn, "this username is banned");
+        }
+        let key = fold(&name);
+        if self.state.user_names.contains_key(&key) || self.state.suspended.contains_key(&key) {
+            conn.handle.send(ServerResponse::UsernameExists);
+            conn.handle.registration(None);
+            return;
+        }
+        if let Err(e) = conn.registration.named(name.clone()) {
+            eprintln!("cannot register {name} on {addr:?}: {e}");
+            return;
+        }
+        self.state.user_names.insert(key.clone(), addr);
+        // a login proves who the user is for good, a username alone only for
+        // as long as the session lasts
+        let author = match self.config.authenticator {
+            Some(_) => format!("account:{key}"),
+            None => format!("session:{}", random_token()),
+        };
+        conn.author = Some(author.clone());
+        // ahead of the registration, which lets the client be heard
+        conn.handle.muted(self.state.muted.get(&key).copied());
+        // the connection joins the default room itself once it knows who it is
+        conn.handle.registration(Some(Registered {
+            member: Arc::new(Member {
+                addr,
+                name: name.clone(),
+                author,
+                handle: conn.handle.clone(),
+                capabilities: conn.capabilities.unwrap_or(Capabilities::empty()),
+            }),
+            rooms: vec![DEFAULT_ROOM.to_string()],
+            since: None,
+        }));
+        conn.handle.send(ServerResponse::UsernameAccepted);
+        let resumable = conn.negotiated(Capabilities::RESUME);
+        if let (true, Some(grace)) = (resumable, self.config.resume_grace) {
+            let token = random_token();
+            conn.session = Some(token.clone());
+            conn.handle
+                .send(ServerResponse::SessionToken { token, grace });
+        }
+        self.notify_users(Some(addr), ServerResponse::UserJoined { username: name });
+    }
+
+    /// Gives a registered user another username, unless it breaks the rules
+    /// or somebody else already has it, and lets everyone know.
+    fn change_nick(&mut self, addr: SocketAddr, new_name: String) {
+        let Some(conn) = self.state.connections.get_mut(&addr) else {
+            return;
+        };
+        let Some(old) = conn.registration.name().map(str::to_string) else {
+            return;
+        };
+        if new_name == old {
+            // nothing changes, nobody else needs to hear about it
+            conn.handle
+                .send(ServerResponse::NickChanged { old, new: new_name });
+            return;
+        }
+        let rejection = if self.config.authenticator.is_some() {
+            Some(NameRejection::Locked)
+        } else if let Err(reason) = check_username(&new_name) {
+            Some(reason)
+        } else {
+            self.state
+                .bans
+                .is_name_banned(&new_name)
+                .then_some(NameRejection::Banned)
+        };
+        if let Some(reason) = rejection {
+            println!("Refusing to rename {old} to {new_name:?}: {reason}");
+            return reject_name(conn, reason);
+        }
+        let (old_key, new_key) = (fold(&old), fold(&new_name));
+        // changing only the case of a name keeps it
+        let taken = self.state.user_names.contains_key(&new_key)
+            || self.state.suspended.contains_key(&new_key);
+        if new_key != old_key && taken {
+            conn.handle.send(ServerResponse::UsernameExists);
+            return;
+        }
+        if let Err(e) = conn.registration.rename(new_name.clone()) {
+            eprintln!("cannot rename {old} on {addr:?}: {e}");
+            return;
+        }
+        println!("{old} is now known as {new_name}");
+        self.state.user_names.remove(&old_key);
+        self.state.user_names.insert(new_key.clone(), addr);
+        // a new name is no way out of a mute
+        if let Some(until) = self.state.muted.remove(&old_key) {
+            self.state.muted.insert(new_key, until);
+        }
+        conn.handle.renamed(Arc::new(Member {
+            addr,
+            name: new_name.clone(),
+            author: conn.author.clone().unwrap_or_default(),
+            handle: conn.handle.clone(),
+            capabilities: conn.capabilities.unwrap_or(Capabilities::empty()),
+        }));
+        self.announce_rename(old, new_name);
+    }
+
+    /// Tells every user about a new name, as a user leaving and another one
+    /// joining to those who do not know about renames.
+    fn announce_rename(&self, old: String, new: String) {
+        let renamed = self.encode(&ServerResponse::NickChanged {
+            old: old.clone(),
+            new: new.clone(),
+        });
+        let left = self.encode(&ServerResponse::UserLeft {
+            username: old,
+            reason: Some(format!("now known as {new}")),
+        });
+        let joined = self.encode(&ServerResponse::UserJoined { username: new });
+        for conn in self.state.connections.values() {
+            if !conn.registration.is_named() {
+                continue;
+            }
+            let frames = if conn.negotiated(Capabilities::NICK_CHANGES) {
+                vec![&renamed]
+            } else {
+                vec![&left, &joined]
+            };
+            for frame in frames.into_iter().flatten() {
+                conn.handle.send_frame(frame.clone());
+            }
+        }
+    }
+
+    /// Takes a user off the server for good: its session is not kept,
+    /// everyone is told why at once and the connection is closed after.
+    fn remove_user(&mut self, addr: SocketAddr, reason: Option<String>) {
+        let Some(conn) = self.state.connections.get_mut(&addr) else {
+            return;
+        };
+        conn.session = None;
+        let name = conn.registration.leave().ok().flatten();
+        conn.handle.close();
+        if let Some(name) = name {
+            println!("{name} left : {reason:?}");
+            self.state.user_names.remove(&fold(&name));
+            self.notify_users(
+                Some(addr),
+                ServerResponse::UserLeft {
+                    username: name,
+                    reason,
+                },
+            );
+        }
+    }
+
+    /// Carries out an operator's kick, ban or mute of `username`.
+    fn moderate(&mut self, addr: SocketAddr, username: String, action: Moderation) {
+        let Some(conn) = self.state.connections.get(&addr) else {
+            return;
+        };
+        let Some(operator) = conn.registration.name().map(str::to_string) else {
+            return;
+        };
+        let handle = conn.handle.clone();
+        let told = conn.negotiated(Capabilities::MODERATION);
+        if !self.is_operator(&operator) || self.is_operator(&username) {
+            if told {
+                handle.send(ServerResponse::NotPermitted);
+            }
+            return;
+        }
+        let key = fold(&username);
+        let target = self.state.user_names.get(&key).copied();
+        let known = target.is_some() || self.state.suspended.contains_key(&key);
+        if !known && !matches!(action, Moderation::Ban { .. }) {
+            handle.send(ServerResponse::UserNotFound { username });
+            return;
+        }
+        println!("{operator} moderates {username} : {action:?}");
+        match action {
+            Moderation::Kick { reason } => {
+                if let Some(target) = target {
+                    self.kick(target, format!("kicked by {operator}"), reason);
+                } else {
+                    self.forget_suspended(&key, format!("kicked by {operator}"));
+                }
+            }
+            Moderation::Ban { reason, ip } => {
+                let mut bans = vec![Ban::Name(username.clone())];
+                if let (true, Some(target)) = (ip, target) {
+                    bans.push(Ban::Ip(target.ip()));
+                }
+                bans.retain(|ban| self.state.bans.insert(ban.clone()));
+                if let (Some(path), false) = (self.state.bans.file(), bans.is_empty()) {
+                    let path = path.to_path_buf();
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = record_bans(&path, &bans) {
+                            eprintln!("failed to record bans in {}: {e}", path.display());
+                        }
+                    });
+                }
+                if let Some(target) = target {
+                    self.kick(target, format!("banned by {operator}"), reason);
+                } else {
+                    self.forget_suspended(&key, format!("banned by {operator}"));
+                }
+            }
+            Moderation::Mute { duration } => {
+                let until = (!duration.is_zero())
+                    .then(|| now_millis().saturating_add(duration.as_millis() as u64));
+                match until {
+                    Some(until) => self.state.muted.insert(key, until),
+                    None => self.state.muted.remove(&key),
+                };
+                let target = target.and_then(|target| self.state.connections.get(&target));
+                if let Some(target) = target {
+                    target.handle.muted(until);
+                    if target.negotiated(Capabilities::MODERATION) {
+                        target.handle.send(ServerResponse::Muted {
+                            until: until.unwrap_or_else(now_millis),
+                        });
+                    }
+                }
+            }
+        }
+        if told {
+            handle.send(ServerResponse::ModerationApplied { username });
+        }
+    }
+
+    /// Looks up which room the message `id` was said in and leaves editing
+    /// or deleting it to the shard of that room.
+    fn amend(&self, member: Arc<Member>, id: u64, new_text: Option<String>) {
+        let operator = self.is_operator(&member.name);
+        let history = self.config.history.clone();
+        let shards = self.shards.clone();
+        // the lookup may have to go to disk
+        tokio::spawn(async move {
+            let room = match tokio::task::spawn_blocking(move || history.find(id)).await {
+                Ok(Ok(Some(entry))) => entry.room,
+                Ok(Ok(None)) => {
+                    member.handle.send(ServerResponse::MessageNotFound { id });
+                    return;
+                }
+                Ok(Err(e)) => {
+                    eprintln!("failed to look up message {id}: {e}");
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("lookup of message {id} panicked: {e}");
+                    return;
+                }
+            };
+            // the message never moves to another room, its shard checks the
+            // rest again when it gets to it
+            shards
+                .for_room(&room)
+                .send(ShardMessage::Amend {
+                    member,
+                    id,
+                    new_text,
+                    operator,
+                })
+                .await;
+        });
+    }
+
+    fn is_operator(&self, name: &str) -> bool {
+        let name = fold(name);
+        self.config.operators.iter().any(|op| fold(op) == name)
+    }
+
+    /// Tells a user why it is being removed, then removes it.
+    fn kick(&mut self, addr: SocketAddr, what: String, reason: Option<String>) {
+        if let Some(conn) = self.state.connections.get(&addr) {
+            if conn.negotiated(Capabilities::MODERATION) {
+                conn.handle.send(ServerResponse::Kicked {
+                    reason: reason.clone(),
+                });
+            }
+        }
+        let reason = match reason {
+            Some(reason) => format!("{what}: {reason}"),
+            None => what,
+        };
+        self.remove_user(addr, Some(reason));
+    }
+
+    /// Drops the session of a user who is away, so that it cannot come back
+    /// to it.
+    fn forget_suspended(&mut self, key: &str, reason: String) {
+        if let Some(session) = self.state.suspended.remove(key) {
+            self.notify_users(
+                None,
+                ServerResponse::UserLeft {
+                    username: session.name,
+                    reason: Some(reason),
+                },
+            );
+        }
+    }
+
+    /// Forgets the sessions nobody came back for and lets everyone know their
+    /// users are gone.
+    fn expire_sessions(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .state
+            .suspended
+            .iter()
+            .filter(|(_, session)| session.expires <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            let Some(session) = self.state.suspended.remove(&key) else {
+                continue;
+            };
+            println!("Session of {} expired", session.name);
+            self.notify_users(
+                None,
+                ServerResponse::UserLeft {
+                    username: session.name,
+                    reason: None,
+                },
+            );
+        }
+    }
+
+    /// Stops accepting connections and stops the room shards.
+    async fn stop_workers(&self) {
+        self.acceptor.abort();
+        for shard in self.shards.all() {
+            shard.terminate(()).await;
+        }
+    }
+
+    /// Sends a response to every connection with a registered username,
+    /// other than `skip`.
+    fn notify_users(&self, skip: Option<SocketAddr>, response: ServerResponse) {
+        let Some(frame) = self.encode(&response) else {
+            return;
+        };
+        for (addr, conn) in self.state.connections.iter() {
+            if Some(*addr) != skip && conn.registration.is_named() {
+                conn.handle.send_frame(frame.clone());
+            }
+        }
+    }
+
+    /// Serializes a response that is about to be sent to many connections.
+    fn encode(&self, response: &ServerResponse) -> Option<EncodedFrame> {
+        match self.codec.encode_frame(response) {
+            Ok(frame) => Some(frame),
+            Err(e) => {
+                eprintln!("failed to encode {response:?}: {e}");
+                None
+            }
+        }
+    }
+}
+
+/// Accepts connections until told to stop, then takes on the ones still
+/// waiting to be accepted before closing the listener. Every connection
+/// announces itself to the server once it is set up, and the server hears
+/// that the listener is closed only after all of them did.
+async fn accept_connections(
+    listener: TcpListener,
+    config: ServerConfig,
+    controller: ServerActorHandler,
+    shards: ShardMap,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let (unannounced, mut announced) = mpsc::channel::<()>(1);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut stop => break,
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // usually out of file descriptors, give some a chance to close
+                eprintln!("failed to accept a connection: {e}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        serve_connection(stream, addr, &config, &controller, &shards, &unannounced);
+    }
+    // the os may have completed handshakes the loop never got to, those
+    // peers believe they are connected and are owed a goodbye
+    match listener.into_std() {
+        Ok(listener) => {
+            while let Ok((stream, addr)) = listener.accept() {
+                let stream = stream
+                    .set_nonblocking(true)
+                    .and_then(|()| TcpStream::from_std(stream));
+                match stream {
+                    Ok(stream) => {
+                        serve_connection(stream, addr, &config, &controller, &shards, &unannounced)
+                    }
+                    Err(e) => {
+                        eprintln!("failed to set up connection from addr: {addr}, error: {e}")
+                    }
+                }
+            }
+        }
+        Err(e) => eprintln!("failed to take on pending connections: {e}"),
+    }
+    drop(unannounced);
+    // nothing is ever sent, this returns once every sender is gone
+    announced.recv().await;
+    controller.send(ConnectionMessage::ListenerClosed).await;
+}
+
+/// Starts the actor serving a connection that was just accepted.
+fn serve_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    config: &ServerConfig,
+    controller: &ServerActorHandler,
+    shards: &ShardMap,
+    unannounced: &mpsc::Sender<()>,
+) {
+    println!("Connection request from : {addr:?}");
+    let mut init_params = SingleConnectionState::new(
+        controller.clone(),
+        shards.clone(),
+        addr,
+        config.heartbeat,
+        config.rate_limit,
+        config.message_rules,
+        config.registration_timeout,
+    );
+    init_params.unannounced = Some(unannounced.clone());
+    match &config.tls {
+        Some(acceptor) => TcpActorHandle::accept_tls(
+            config.queue_capacity,
+            config.backpressure,
+            stream,
+            acceptor.clone(),
+            init_params,
+        ),
+        None => TcpActorHandle::new(
+            config.queue_capacity,
+            config.backpressure,
+            stream,
+            init_params,
+        ),
+    };
+}
+
+/// Tells a connection it cannot have the username it asked for, it may ask
+/// for another.
+fn reject_name(conn: &Connection, reason: NameRejection) {
+    if conn.negotiated(Capabilities::NAME_RULES) {
+        conn.handle
+            .send(ServerResponse::UsernameRejected { reason });
+    } else {
+        // the nearest thing older clients understand
+        conn.handle.send(ServerResponse::UsernameExists);
+    }
+}
+
+/// Tells the peer why it is being turned away and closes the connection once
+/// the reason has been written.
+fn refuse(conn: &Connection, reason: &str) {
+    conn.handle.send(ServerResponse::ConnectionRefused {
+        reason: reason.to_string(),
+    });
+    conn.handle.close();
 }
 
 /// ActorHandle can be used to send messages to the respective actor.
@@ -118,23 +1064,40 @@ pub struct ServerActorHandler {
 impl ServerActorHandler {
     // when we create a new actor what we only return is the handle, during
     // it's creation we launch the actor and create a handle to it as well.
-    pub fn new(
-        size: usize,
-        listener: TcpListener,
-        // init_params: <A as ActorTrait>::InitParams,
-    ) -> (Self, JoinHandle<()>) {
+    pub fn new(size: usize, listener: TcpListener, config: ServerConfig) -> (Self, JoinHandle<()>) {
         let (tx, rx): (
             mpsc::Sender<ConnectionMessage>,
             mpsc::Receiver<ConnectionMessage>,
         ) = mpsc::channel(size);
         let (ktx, krx): (mpsc::Sender<()>, mpsc::Receiver<()>) = mpsc::channel(1);
 
-        let actor: ServerActor = ServerActor::new(rx, krx, listener);
+        let handle = ServerActorHandler { id: tx, kid: ktx };
+        let shards = ShardMap::new(
+            (0..config.shards.max(1))
+                .map(|id| RoomShardHandle::new(size, id, config.clone()))
+                .collect(),
+        );
+        let (stop_accepting, stop) = oneshot::channel();
+        let acceptor = tokio::spawn(accept_connections(
+            listener,
+            config.clone(),
+            handle.clone(),
+            shards.clone(),
+            stop,
+        ));
+        let actor: ServerActor = ServerActor::new(
+            rx,
+            krx,
+            acceptor,
+            stop_accepting,
+            shards,
+            config,
+            handle.clone(),
+        );
         let join_handle = tokio::spawn(async move {
             let res = actor.start().await;
             eprintln!("Actor exited with : {res}");
         });
-        let handle = ServerActorHandler { id: tx, kid: ktx };
         (handle, join_handle)
     }
     pub async fn send(&self, msg: ConnectionMessage) -> () {
@@ -147,4 +1110,22 @@ impl ServerActorHandler {
             eprintln!("{e:?}");
         }
     }
+    /// Shuts the server down gracefully and waits until every connection
+    /// has been closed, or terminated once `drain_timeout` has passed.
+    pub async fn shutdown(
+        &self,
+        reason: impl Into<String>,
+        reconnect_after: Option<Duration>,
+        drain_timeout: Duration,
+    ) {
+        let (done, finished) = oneshot::channel();
+        self.send(ConnectionMessage::Shutdown {
+            reason: reason.into(),
+            reconnect_after,
+            drain_timeout,
+            done,
+        })
+        .await;
+        let _ = finished.await;
+    }
 }
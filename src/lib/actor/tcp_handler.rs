@@ -1,66 +1,183 @@
+use std::{fmt, sync::Arc, time::Duration};
+
 use crate::{
-    actor_impl::{server_impl::ConnectionMessage, tcp_impl::SingleConnectionState},
-    msg::{ClientMessage, ServerResponse, TcpMessage},
+    actor::{
+        outbound::{BackpressurePolicy, OutboundQueue},
+        rate_limit::Verdict,
+    },
+    actor_impl::{
+        server_impl::ConnectionMessage,
+        shard_impl::{Member, ShardMessage},
+        tcp_impl::SingleConnectionState,
+    },
+    auth::Credential,
+    history::now_millis,
+    moderation::Moderation,
+    msg::{
+        codec::{CodecError, EncodedFrame, ServerCodec},
+        Capabilities, ClientMessage, NackReason, ServerResponse,
+    },
+    transport::{
+        tls::{TlsAcceptor, HANDSHAKE_TIMEOUT},
+        Stream,
+    },
 };
+use futures::{Future, Sink, SinkExt, StreamExt};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io,
     net::TcpStream,
     sync::mpsc,
+    time::{Instant, Interval, MissedTickBehavior},
 };
+use tokio_util::codec::Framed;
 
 /// The Actor struct, responsible for spawning the actor that receive the
 /// messages and then handle them, the actor itself may have a state that can
 /// be affected by the message
-pub struct TcpActor {
-    // a handle to the receiver from mpsc::channel so that we can use it to
-    // receive messages
-    receiver: mpsc::Receiver<ControllerMessages>,
+pub struct TcpActor<S> {
+    // responses and control messages waiting to be handled, shared with
+    // every handle to this actor
+    queue: Arc<OutboundQueue>,
     // a receiver to handle poison pill
     poison_pill: mpsc::Receiver<()>,
     // the state of the actor that can be modified by the handle function
     state: SingleConnectionState,
-    // a tcp stream
-    stream: TcpStream,
+    // a plain or tls stream framed into client messages and server responses
+    stream: Framed<S, ServerCodec>,
+}
+
+/// How a connection makes sure its peer is still there.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Heartbeat {
+    // how often peers that speak the heartbeat are pinged, `None` for never
+    pub interval: Option<Duration>,
+    // pings in a row the peer may leave unanswered before it is dropped
+    pub max_missed: u32,
+    // how long any peer may stay silent before it is dropped, `None` for
+    // as long as it likes
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Some(Duration::from_secs(30)),
+            max_missed: 2,
+            idle_timeout: Some(Duration::from_secs(300)),
+        }
+    }
+}
+
+/// What a connection is told once it has a username.
+pub struct Registered {
+    pub member: Arc<Member>,
+    // rooms to join, just the default room for a new session
+    pub rooms: Vec<String>,
+    // replay what the rooms had after this message rather than their recent
+    // history, for a resumed session
+    pub since: Option<u64>,
 }
 
 pub enum ControllerMessages {
     WriteStream(ServerResponse),
+    // a response already serialized once for many connections
+    WriteFrame(EncodedFrame),
+    // shut the connection down once everything queued before it is written
+    Close,
+    // drop the connection at once, without writing anything else
+    Disconnect,
+    // the outcome of asking for a username, once accepted room traffic goes
+    // to the shards
+    Registration(Option<Registered>),
+    // the features agreed on during the handshake
+    Negotiated(Capabilities),
+    // the connection goes by another name
+    Renamed(Arc<Member>),
+    // the end of a mute, in milliseconds since the unix epoch, `None` once
+    // it is lifted
+    Muted(Option<u64>),
     Null,
 }
 
-impl TcpActor {
+impl<S: Stream> TcpActor<S> {
     pub fn new(
-        rx: mpsc::Receiver<ControllerMessages>,
+        queue: Arc<OutboundQueue>,
         krx: mpsc::Receiver<()>,
-        stream: TcpStream,
+        stream: S,
         init_params: SingleConnectionState,
     ) -> Self {
         TcpActor {
-            receiver: rx,
+            queue,
             poison_pill: krx,
             state: init_params,
-            stream,
+            stream: Framed::new(stream, ServerCodec::new()),
         }
     }
     pub async fn start(mut self) -> u8 {
-        let mut buffer = [0; 512];
+        let mut ticker = self.state.heartbeat.interval.map(|period| {
+            let mut ticker = tokio::time::interval_at(Instant::now() + period, period);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticker
+        });
         loop {
+            let idle_deadline = self
+                .state
+                .heartbeat
+                .idle_timeout
+                .map(|timeout| self.state.last_heard + timeout);
+            let registration_deadline = self.state.registration_deadline;
             tokio::select! {
-                Some(msg) = self.receiver.recv() => {
+                msg = self.queue.pop() => {
                     match msg {
                         ControllerMessages::WriteStream(msg) => {
+                            self.state.track_rooms(&msg);
                             // let _ = <A as TcpConnectionHandlerActor>::handle_controller_message(&mut self.state, msg, &mut self.stream).await;
-                            let bytes = msg.to_bytes();
-                            if let Some(bytes) = bytes {
-                                if let Err(e) = self.stream.write_all(&bytes).await {
-                                    eprintln!("failed to write to addr: {}, error: {}", self.state.addr, e);
-                                } else {
-                                    println!("TCP handler {} writing to stream, msg: {:?}",self.state.addr,msg);
+                            let log = format!("{msg:?}");
+                            if !self.write(msg, log).await {
+                                self.state.dropped().await;
+                                break;
+                            }
+                        }
+                        ControllerMessages::WriteFrame(frame) => {
+                            let log = format!("frame of {} bytes", frame.len());
+                            if !self.write(frame, log).await {
+                                self.state.dropped().await;
+                                break;
+                            }
+                        }
+                        ControllerMessages::Disconnect => {
+                            eprintln!("disconnecting addr: {}, it fell too far behind", self.state.addr);
+                            self.state.dropped().await;
+                            break;
+                        }
+                        ControllerMessages::Close => {
+                            if let Err(e) = SinkExt::<ServerResponse>::close(&mut self.stream).await {
+                                eprintln!("failed to close addr: {}, error: {}", self.state.addr, e);
+                            }
+                            self.state.dropped().await;
+                            break;
+                        }
+                        ControllerMessages::Registration(outcome) => {
+                            self.state.registering = false;
+                            if let Some(Registered { member, rooms, since }) = outcome {
+                                for room in rooms {
+                                    self.state.shards.for_room(&room).send(ShardMessage::JoinRoom { member: member.clone(), room, since }).await;
                                 }
-                            } else {
-                                eprintln!("failed to serialize message");
+                                self.state.member = Some(member);
+                                self.state.registration_deadline = None;
                             }
                         }
+                        ControllerMessages::Negotiated(capabilities) => {
+                            self.state.pings = capabilities.contains(Capabilities::HEARTBEAT);
+                            self.queue.report_drops(capabilities.contains(Capabilities::DROP_NOTICES));
+                            self.state.moderation = capabilities.contains(Capabilities::MODERATION);
+                            self.state.rate_limits = capabilities.contains(Capabilities::RATE_LIMITS);
+                            self.state.rejections =
+                                capabilities.contains(Capabilities::MESSAGE_REJECTIONS);
+                            self.state.acks = capabilities.contains(Capabilities::ACKNOWLEDGEMENTS);
+                        }
+                        ControllerMessages::Renamed(member) => self.state.member = Some(member),
+                        ControllerMessages::Muted(until) => self.state.muted_until = until,
                         ControllerMessages::Null => {}
                     }
                 }
@@ -69,74 +186,581 @@ impl TcpActor {
                     eprintln!("killing actor");
                     return 1;
                 }
-                Ok(size) = self.stream.read(&mut buffer) => {
-                   if size == 0 {
-                       self.state.controller_handle.send(ConnectionMessage::ConnectionDropped { addr: self.state.addr }).await;
-                       break;
-                   } else {
-                       let _parsed = ClientMessage::from_bytes(&buffer[0..size]);
-                       if let Some(parsed) = _parsed {
-                           match parsed {
-                               ClientMessage::UserName(_name) => {
-                                   self.state.controller_handle.send(ConnectionMessage::UserCreationRequest { _addr: self.state.addr, _name }).await;
-                               }
-                               ClientMessage::Message(msg) => {
-                                   self.state
-                                       .controller_handle
-                                       .send(ConnectionMessage::UserMessage {
-                                           addr: self.state.addr,
-                                           message: msg,
-                                       })
-                                       .await;
-                               }
-                           }
-                       } else {
-                           eprintln!("failed to parse message");
-                       }
-                   }
+                // whatever the client sent after asking for a username waits
+                // until it knows whether it got one
+                frame = self.stream.next(), if !self.state.registering && !self.state.leaving => {
+                    match frame {
+                        Some(Ok(parsed)) => {
+                            if !self.state.greeted && !matches!(parsed, ClientMessage::Hello { .. }) {
+                                self.refuse_unannounced().await;
+                                break;
+                            }
+                            self.state.greeted = true;
+                            self.state.last_heard = Instant::now();
+                            self.state.unanswered = 0;
+                            self.handle_client_message(parsed).await
+                        }
+                        Some(Err(e)) => {
+                            // a framed stream ends after the codec errors, be
+                            // it an io failure, an oversized or a malformed frame
+                            eprintln!("failed to read from addr: {}, error: {}", self.state.addr, e);
+                            self.state.dropped().await;
+                            break;
+                        }
+                        None => {
+                            self.state.dropped().await;
+                            break;
+                        }
+                    }
+                }
+                _ = tick(ticker.as_mut()), if self.state.pings => {
+                    if self.state.unanswered >= self.state.heartbeat.max_missed {
+                        eprintln!("disconnecting addr: {}, it missed {} heartbeats", self.state.addr, self.state.unanswered);
+                        self.state.dropped().await;
+                        break;
+                    }
+                    self.state.unanswered += 1;
+                    self.state.pings_sent += 1;
+                    // not subject to backpressure, a client that is slow
+                    // to read is not mistaken for a dead one
+                    self.queue.push_control(ControllerMessages::WriteStream(ServerResponse::Ping(self.state.pings_sent)));
+                }
+                _ = tokio::time::sleep_until(registration_deadline.unwrap_or_else(Instant::now)), if registration_deadline.is_some() => {
+                    // the server decides, it may have just given out a name
+                    self.state.registration_deadline = None;
+                    self.state.controller_handle.send(ConnectionMessage::RegistrationTimeout { addr: self.state.addr }).await;
+                }
+                _ = tokio::time::sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                    eprintln!("disconnecting addr: {}, it was idle for too long", self.state.addr);
+                    self.state.dropped().await;
+                    break;
                 }
                 else => {
                     eprintln!("all senders dropped");
-                    self.state.controller_handle.send(ConnectionMessage::ConnectionDropped { addr: self.state.addr }).await;
+                    self.state.dropped().await;
                     break;
                 }
             }
         }
         0
     }
+
+    /// Turns away a client that did not open with a handshake.
+    async fn refuse_unannounced(&mut self) {
+        eprintln!(
+            "refusing addr: {}, it did not open with a handshake",
+            self.state.addr
+        );
+        let refusal = ServerResponse::ConnectionRefused {
+            reason: "handshake required first".to_string(),
+        };
+        if let Err(e) = self.stream.send(refusal).await {
+            eprintln!("failed to write to addr: {}, error: {}", self.state.addr, e);
+        }
+        if let Err(e) = SinkExt::<ServerResponse>::close(&mut self.stream).await {
+            eprintln!("failed to close addr: {}, error: {}", self.state.addr, e);
+        }
+        self.state.dropped().await;
+    }
+
+    /// Writes a response or a ready made frame, giving up if the client is
+    /// cut off meanwhile. Returns `false` if the connection has to go.
+    async fn write<I>(&mut self, item: I, log: String) -> bool
+    where
+        Framed<S, ServerCodec>: Sink<I, Error = CodecError>,
+    {
+        tokio::select! {
+            res = self.stream.send(item) => {
+                if let Err(e) = res {
+                    eprintln!("failed to write to addr: {}, error: {}", self.state.addr, e);
+                } else {
+                    println!("TCP handler {} writing to stream, msg: {}", self.state.addr, log);
+                }
+                true
+            }
+            // a client that stopped reading is not waited on
+            _ = self.queue.cut_off() => {
+                eprintln!("disconnecting addr: {}, it fell too far behind", self.state.addr);
+                false
+            }
+        }
+    }
+
+    async fn handle_client_message(&mut self, msg: ClientMessage) {
+        match msg {
+            ClientMessage::Hello {
+                protocol_version,
+                capabilities,
+            } => {
+                self.state
+                    .controller_handle
+                    .send(ConnectionMessage::Hello {
+                        addr: self.state.addr,
+                        protocol_version,
+                        capabilities,
+                    })
+                    .await;
+            }
+            ClientMessage::UserName(_name) => {
+                self.state.registering = true;
+                self.state
+                    .controller_handle
+                    .send(ConnectionMessage::UserCreationRequest {
+                        _addr: self.state.addr,
+                        _name,
+                    })
+                    .await;
+            }
+            ClientMessage::Message {
+                room,
+                message,
+                nonce,
+            } => {
+                let Some(member) = self.state.member.clone() else {
+                    return;
+                };
+                let nonce = nonce.filter(|_| self.state.acks);
+                let Some(message) = self.vetted(message, nonce).await else {
+                    return;
+                };
+                self.state
+                    .shards
+                    .for_room(&room)
+                    .send(ShardMessage::UserMessage {
+                        member,
+                        room,
+                        message,
+                        nonce,
+                    })
+                    .await;
+            }
+            ClientMessage::JoinRoom(room) => {
+                let Some(member) = self.state.member.clone() else {
+                    return;
+                };
+                self.state
+                    .shards
+                    .for_room(&room)
+                    .send(ShardMessage::JoinRoom {
+                        member,
+                        room,
+                        since: None,
+                    })
+                    .await;
+            }
+            ClientMessage::LeaveRoom(room) => {
+                let Some(member) = self.state.member.clone() else {
+                    return;
+                };
+                self.state
+                    .shards
+                    .for_room(&room)
+                    .send(ShardMessage::LeaveRoom { member, room })
+                    .await;
+            }
+            ClientMessage::ListRooms => {
+                self.state
+                    .controller_handle
+                    .send(ConnectionMessage::ListRooms {
+                        addr: self.state.addr,
+                    })
+                    .await;
+            }
+            ClientMessage::DirectMessage { to, message, nonce } => {
+                let nonce = nonce.filter(|_| self.state.acks);
+                let Some(message) = self.vetted(message, nonce).await else {
+                    return;
+                };
+                self.state
+                    .controller_handle
+                    .send(ConnectionMessage::DirectMessage {
+                        addr: self.state.addr,
+                        to,
+                        message,
+                        nonce,
+                    })
+                    .await;
+            }
+            ClientMessage::ListUsers => {
+                self.state
+                    .controller_handle
+                    .send(ConnectionMessage::ListUsers {
+                        addr: self.state.addr,
+                    })
+                    .await;
+            }
+            ClientMessage::FetchHistory {
+                room,
+                before_seq,
+                after_seq,
+                limit,
+            } => {
+                let Some(member) = self.state.member.clone() else {
+                    return;
+                };
+                self.state
+                    .shards
+                    .for_room(&room)
+                    .send(ShardMessage::FetchHistory {
+                        member,
+                        room,
+                        before_seq,
+                        after_seq,
+                        limit,
+                    })
+                    .await;
+            }
+            ClientMessage::SearchHistory { room, query, limit } => {
+                let Some(member) = self.state.member.clone() else {
+                    return;
+                };
+                self.state
+                    .shards
+                    .for_room(&room)
+                    .send(ShardMessage::SearchHistory {
+                        member,
+                        room,
+                        query,
+                        limit,
+                    })
+                    .await;
+            }
+            ClientMessage::Resume { token, last_seq } => {
+                self.state.registering = true;
+                self.state
+                    .controller_handle
+                    .send(ConnectionMessage::Resume {
+                        addr: self.state.addr,
+                        token: Credential(token),
+                        last_seq,
+                    })
+                    .await;
+            }
+            ClientMessage::Leave { reason } => {
+                self.state.leaving = true;
+                self.state
+                    .controller_handle
+                    .send(ConnectionMessage::Leave {
+                        addr: self.state.addr,
+                        reason,
+                    })
+                    .await;
+            }
+            ClientMessage::Kick { username, reason } => {
+                self.moderate(username, Moderation::Kick { reason }).await;
+            }
+            ClientMessage::Ban {
+                username,
+                reason,
+                ip,
+            } => {
+                self.moderate(username, Moderation::Ban { reason, ip })
+                    .await;
+            }
+            ClientMessage::Mute { username, duration } => {
+                self.moderate(username, Moderation::Mute { duration }).await;
+            }
+            ClientMessage::ChangeNick { new_name } => {
+                self.state
+                    .controller_handle
+                    .send(ConnectionMessage::ChangeNick {
+                        addr: self.state.addr,
+                        new_name,
+                    })
+                    .await;
+            }
+            ClientMessage::EditMessage { id, new_text } => {
+                let Some(member) = self.state.member.clone() else {
+                    return;
+                };
+                let Some(new_text) = self.vetted(new_text, None).await else {
+                    return;
+                };
+                self.amend(member, id, Some(new_text)).await;
+            }
+            ClientMessage::DeleteMessage { id } => {
+                let Some(member) = self.state.member.clone() else {
+                    return;
+                };
+                self.amend(member, id, None).await;
+            }
+            ClientMessage::Ping(value) => {
+                self.queue
+                    .push_control(ControllerMessages::WriteStream(ServerResponse::Pong(value)));
+            }
+            // hearing from the client at all is what counts
+            ClientMessage::Pong(_) => {}
+            ClientMessage::Authenticate {
+                username,
+                credential,
+            } => {
+                self.state.registering = true;
+                self.state
+                    .controller_handle
+                    .send(ConnectionMessage::Authenticate {
+                        addr: self.state.addr,
+                        username,
+                        credential: Credential(credential),
+                    })
+                    .await;
+            }
+        }
+    }
+}
+
+impl<S: Stream> TcpActor<S> {
+    async fn moderate(&mut self, username: String, action: Moderation) {
+        self.state
+            .controller_handle
+            .send(ConnectionMessage::Moderate {
+                addr: self.state.addr,
+                username,
+                action,
+            })
+            .await;
+    }
+
+    /// Asks the server to edit the message `id`, or to delete it.
+    async fn amend(&mut self, member: Arc<Member>, id: u64, new_text: Option<String>) {
+        self.state
+            .controller_handle
+            .send(ConnectionMessage::Amend {
+                member,
+                id,
+                new_text,
+            })
+            .await;
+    }
+
+    /// The chat message as it may go out, or nothing if it breaks the rules
+    /// or the client may not talk right now, in which case it is told why.
+    async fn vetted(&mut self, message: String, nonce: Option<u64>) -> Option<String> {
+        let reason = match self.state.rules.check(message) {
+            Ok(message) => match self.allow(message.len()).await {
+                Ok(()) => return Some(message),
+                Err(Some(reason)) => reason,
+                Err(None) => return None,
+            },
+            Err(reason) => NackReason::Rejected(reason),
+        };
+        self.refuse(nonce, reason);
+        None
+    }
+
+    /// Whether a chat message of `len` bytes may go out, dealing out
+    /// whatever the client has earned if it may not. The error says why,
+    /// unless the client is on its way out.
+    async fn allow(&mut self, len: usize) -> Result<(), Option<NackReason>> {
+        if let Some(until) = self.state.muted() {
+            // talking on regardless counts as flooding
+            let penalty = self
+                .state
+                .flood
+                .as_mut()
+                .and_then(|flood| flood.strike(Instant::now()));
+            return Err(match penalty {
+                Some(penalty) => self.penalize(penalty).await,
+                None => Some(NackReason::Muted { until }),
+            });
+        }
+        let verdict = match self.state.flood.as_mut() {
+            Some(flood) => flood.check(len),
+            None => Verdict::Allowed,
+        };
+        match verdict {
+            Verdict::Allowed => Ok(()),
+            Verdict::Limited { retry_after } => Err(Some(NackReason::RateLimited { retry_after })),
+            penalty => Err(self.penalize(penalty).await),
+        }
+    }
+
+    /// Mutes or disconnects a flooding client, returning why its message
+    /// went nowhere if it stays connected.
+    async fn penalize(&mut self, penalty: Verdict) -> Option<NackReason> {
+        match penalty {
+            Verdict::Mute { duration } => {
+                println!(
+                    "Muting addr: {} for {duration:?}, it is flooding",
+                    self.state.addr
+                );
+                let until = now_millis().saturating_add(duration.as_millis() as u64);
+                let until = self
+                    .state
+                    .muted_until
+                    .map_or(until, |muted| muted.max(until));
+                self.state.muted_until = Some(until);
+                self.state
+                    .controller_handle
+                    .send(ConnectionMessage::FloodMuted {
+                        addr: self.state.addr,
+                        until,
+                    })
+                    .await;
+                Some(NackReason::Muted { until })
+            }
+            Verdict::Disconnect => {
+                eprintln!("disconnecting addr: {}, it kept flooding", self.state.addr);
+                // the server sees it out like any removed user
+                self.state.leaving = true;
+                self.state
+                    .controller_handle
+                    .send(ConnectionMessage::Flooded {
+                        addr: self.state.addr,
+                    })
+                    .await;
+                None
+            }
+            Verdict::Allowed | Verdict::Limited { .. } => None,
+        }
+    }
+
+    /// Tells the client its chat message went nowhere: with a `Nack` if it
+    /// gave a nonce, otherwise with the response for `reason` if it
+    /// understands that one.
+    fn refuse(&self, nonce: Option<u64>, reason: NackReason) {
+        let response = match (nonce, reason) {
+            (Some(nonce), reason) => ServerResponse::Nack { nonce, reason },
+            (None, NackReason::Rejected(reason)) if self.state.rejections => {
+                ServerResponse::MessageRejected { reason }
+            }
+            (None, NackReason::RateLimited { retry_after }) if self.state.rate_limits => {
+                ServerResponse::RateLimited { retry_after }
+            }
+            (None, NackReason::Muted { until }) if self.state.moderation => {
+                ServerResponse::Muted { until }
+            }
+            _ => return,
+        };
+        self.queue.push(response);
+    }
+}
+
+/// Waits for the next heartbeat, or forever without one.
+async fn tick(ticker: Option<&mut Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
 }
 
 /// ActorHandle can be used to send messages to the respective actor.
 #[derive(Clone)]
 pub struct TcpActorHandle {
-    // holds a handle to the sender from mpsc::channel
-    id: mpsc::Sender<ControllerMessages>,
+    // holds a handle to the queue the actor writes out from
+    id: Arc<OutboundQueue>,
     // holds a handle to send poison pill
     kid: mpsc::Sender<()>,
 }
 
+impl fmt::Debug for TcpActorHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TcpActorHandle({} queued)", self.id.len())
+    }
+}
+
 impl TcpActorHandle {
     // when we create a new actor what we only return is the handle, during
     // it's creation we launch the actor and create a handle to it as well.
-    pub fn new(size: usize, stream: TcpStream, init_params: SingleConnectionState) -> Self {
-        let (tx, rx): (
-            mpsc::Sender<ControllerMessages>,
-            mpsc::Receiver<ControllerMessages>,
-        ) = mpsc::channel(size);
+    pub fn new<S: Stream>(
+        size: usize,
+        policy: BackpressurePolicy,
+        stream: S,
+        init_params: SingleConnectionState,
+    ) -> Self {
+        Self::spawn(size, policy, async { Ok(stream) }, init_params)
+    }
+
+    // the handshake runs in the connection's own task, anything sent to the
+    // handle meanwhile is queued until it completes
+    pub fn accept_tls(
+        size: usize,
+        policy: BackpressurePolicy,
+        stream: TcpStream,
+        acceptor: TlsAcceptor,
+        init_params: SingleConnectionState,
+    ) -> Self {
+        let handshake = async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(res) => res,
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "tls handshake timed out",
+                )),
+            }
+        };
+        Self::spawn(size, policy, handshake, init_params)
+    }
+
+    fn spawn<S, F>(
+        size: usize,
+        policy: BackpressurePolicy,
+        stream: F,
+        mut init_params: SingleConnectionState,
+    ) -> Self
+    where
+        S: Stream,
+        F: Future<Output = io::Result<S>> + Send + 'static,
+    {
+        let queue = Arc::new(OutboundQueue::new(size, policy));
         let (ktx, krx): (mpsc::Sender<()>, mpsc::Receiver<()>) = mpsc::channel(1);
 
-        let actor: TcpActor = TcpActor::new(rx, krx, stream, init_params);
+        let handle = TcpActorHandle {
+            id: queue.clone(),
+            kid: ktx,
+        };
+        let this_handle = handle.clone();
         tokio::spawn(async move {
+            let addr = init_params.addr;
+            let stream = match stream.await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("failed to set up connection from addr: {addr}, error: {e}");
+                    return;
+                }
+            };
+            // the server hears about the connection before anything it sends
+            init_params
+                .controller_handle
+                .send(ConnectionMessage::Connected {
+                    addr,
+                    handle: this_handle,
+                })
+                .await;
+            init_params.unannounced = None;
+            let actor = TcpActor::new(queue, krx, stream, init_params);
             let res = actor.start().await;
             eprintln!("Actor exited with : {res}");
         });
-        
-        TcpActorHandle { id: tx, kid: ktx }
+
+        handle
     }
-    pub async fn send(&self, msg: ServerResponse) -> () {
-        if let Err(e) = self.id.send(ControllerMessages::WriteStream(msg)).await {
-            eprintln!("{e:?}");
-        }
+    // never waits, a slow client is dealt with by the queue's policy
+    pub fn send(&self, msg: ServerResponse) {
+        self.id.push(msg);
+    }
+    // like `send`, for a response serialized ahead of time
+    pub fn send_frame(&self, frame: EncodedFrame) {
+        self.id.push_frame(frame);
+    }
+    // tells the connection whether it got the username it asked for
+    pub fn registration(&self, registered: Option<Registered>) {
+        self.id
+            .push_control(ControllerMessages::Registration(registered));
+    }
+    pub fn negotiated(&self, capabilities: Capabilities) {
+        self.id
+            .push_control(ControllerMessages::Negotiated(capabilities));
+    }
+    pub fn renamed(&self, member: Arc<Member>) {
+        self.id.push_control(ControllerMessages::Renamed(member));
+    }
+    pub fn muted(&self, until: Option<u64>) {
+        self.id.push_control(ControllerMessages::Muted(until));
+    }
+    pub fn close(&self) {
+        self.id.push_control(ControllerMessages::Close);
     }
     pub async fn terminate(&self, msg: ()) -> () {
         if let Err(e) = self.kid.send(msg).await {
@@ -1,2 +1,3 @@
 pub mod server_impl;
+pub mod shard_impl;
 pub mod tcp_impl;
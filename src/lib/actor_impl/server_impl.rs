@@ -4,27 +4,238 @@
 
 use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
-use std::sync::LazyLock;
+use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::net::TcpListener;
-use tokio::sync::OnceCell;
-use tokio::task::JoinHandle;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
 
-use crate::actor::server_actor::ServerActorHandler;
-use crate::actor::tcp_handler::TcpActorHandle;
+use crate::actor::outbound::BackpressurePolicy;
+use crate::actor::rate_limit::RateLimit;
+use crate::actor::tcp_handler::{Heartbeat, TcpActorHandle};
+use crate::actor_impl::shard_impl::Member;
+use crate::auth::{AuthError, Authenticator, Credential};
+use crate::history::{HistoryStore, MemoryHistoryStore};
+use crate::moderation::{BanList, Moderation};
+use crate::msg::validate::MessageRules;
+use crate::msg::Capabilities;
+use crate::registration::Registration;
+use crate::transport::tls::TlsAcceptor;
 
 pub struct CentralController {}
 
+/// Settings chosen when the server is started.
+#[derive(Clone)]
+pub struct ServerConfig {
+    // where every broadcast is recorded
+    pub history: Arc<dyn HistoryStore>,
+    // how many past messages are replayed to someone joining a room
+    pub replay_len: usize,
+    // when set, usernames have to be claimed with a valid credential
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    // when set, every connection has to complete a tls handshake first
+    pub tls: Option<TlsAcceptor>,
+    // how many responses may wait to be written to a single connection
+    pub queue_capacity: usize,
+    // what happens to responses for a connection whose queue is full
+    pub backpressure: BackpressurePolicy,
+    // how many room shards the rooms are spread across
+    pub shards: usize,
+    // how connections that went quiet are found and dropped
+    pub heartbeat: Heartbeat,
+    // how long the session of a lost connection can be resumed, `None` to
+    // not keep sessions at all
+    pub resume_grace: Option<Duration>,
+    // how long a connection has to get a username, `None` for as long as
+    // it likes
+    pub registration_timeout: Option<Duration>,
+    // what chat messages may contain
+    pub message_rules: MessageRules,
+    // how much every connection may say, `None` for as much as it likes
+    pub rate_limit: Option<RateLimit>,
+    // usernames allowed to kick, ban and mute everyone else
+    pub operators: HashSet<String>,
+    // names and addresses kept out, and where new bans are recorded
+    pub bans: BanList,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            history: Arc::new(MemoryHistoryStore::new(1000)),
+            replay_len: 50,
+            authenticator: None,
+            tls: None,
+            queue_capacity: 1024,
+            backpressure: BackpressurePolicy::default(),
+            shards: default_shards(),
+            heartbeat: Heartbeat::default(),
+            resume_grace: Some(Duration::from_secs(60)),
+            registration_timeout: Some(Duration::from_secs(30)),
+            message_rules: MessageRules::default(),
+            rate_limit: Some(RateLimit::default()),
+            operators: HashSet::new(),
+            bans: BanList::new(),
+        }
+    }
+}
+
+/// One room shard per cpu.
+pub fn default_shards() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
 #[derive(Debug)]
 pub enum ConnectionMessage {
-    UserMessage { addr: SocketAddr, message: String },
-    UserCreationRequest { _addr: SocketAddr, _name: String },
-    ConnectionDropped { addr: SocketAddr },
+    // a connection was accepted, and its tls handshake completed if any
+    Connected {
+        addr: SocketAddr,
+        handle: TcpActorHandle,
+    },
+    // the listener is closed, every connection it accepted has been
+    // announced by now or failed to set up
+    ListenerClosed,
+    Hello {
+        addr: SocketAddr,
+        protocol_version: u32,
+        capabilities: Capabilities,
+    },
+    ListRooms {
+        addr: SocketAddr,
+    },
+    DirectMessage {
+        addr: SocketAddr,
+        to: String,
+        message: String,
+        // acknowledged once delivered, if set
+        nonce: Option<u64>,
+    },
+    ListUsers {
+        addr: SocketAddr,
+    },
+    UserCreationRequest {
+        _addr: SocketAddr,
+        _name: String,
+    },
+    Authenticate {
+        addr: SocketAddr,
+        username: String,
+        credential: Credential,
+    },
+    // the outcome of checking an `Authenticate` off the actor
+    AuthenticationResult {
+        addr: SocketAddr,
+        username: String,
+        result: Result<(), AuthError>,
+    },
+    Resume {
+        addr: SocketAddr,
+        token: Credential,
+        last_seq: Option<u64>,
+    },
+    // a clean departure, announced at once and never suspended
+    Leave {
+        addr: SocketAddr,
+        reason: Option<String>,
+    },
+    // an operator acting on another user
+    Moderate {
+        addr: SocketAddr,
+        username: String,
+        action: Moderation,
+    },
+    ChangeNick {
+        addr: SocketAddr,
+        new_name: String,
+    },
+    // edit the message `id` to read `new_text`, or delete it if `None`
+    Amend {
+        member: Arc<Member>,
+        id: u64,
+        new_text: Option<String>,
+    },
+    // the connection still has no username when it should have
+    RegistrationTimeout {
+        addr: SocketAddr,
+    },
+    // the connection was muted for flooding until then, in milliseconds
+    // since the unix epoch
+    FloodMuted {
+        addr: SocketAddr,
+        until: u64,
+    },
+    // the connection kept flooding after every penalty
+    Flooded {
+        addr: SocketAddr,
+    },
+    ConnectionDropped {
+        addr: SocketAddr,
+        // where a suspended session goes back to
+        rooms: Vec<String>,
+    },
+    // stop accepting, tell everyone why and close every connection, giving
+    // them `drain_timeout` to write out what is queued
+    Shutdown {
+        reason: String,
+        reconnect_after: Option<Duration>,
+        drain_timeout: Duration,
+        done: oneshot::Sender<()>,
+    },
+}
+
+/// Everything the server keeps about a single accepted connection.
+pub struct Connection {
+    pub handle: TcpActorHandle,
+    // the features agreed on during the handshake, `None` until it completes
+    pub capabilities: Option<Capabilities>,
+    pub registration: Registration,
+    // who the user's messages are recorded as written by, set once it is
+    // registered and kept when it changes its username
+    pub author: Option<String>,
+    // the token that resumes this connection's session
+    pub session: Option<String>,
+}
+
+impl Connection {
+    pub fn new(handle: TcpActorHandle) -> Self {
+        Self {
+            handle,
+            capabilities: None,
+            registration: Registration::Connected,
+            author: None,
+            session: None,
+        }
+    }
+
+    /// Whether `capability` was agreed on during the handshake.
+    pub fn negotiated(&self, capability: Capabilities) -> bool {
+        self.capabilities
+            .is_some_and(|capabilities| capabilities.contains(capability))
+    }
 }
 
 pub struct ServerState {
-    pub connections: HashMap<SocketAddr, (TcpActorHandle, Option<String>)>,
-    pub user_names: HashSet<String>,
+    pub connections: HashMap<SocketAddr, Connection>,
+    // registered usernames and the connection that owns each of them, like
+    // every map of usernames here keyed by the folded name
+    pub user_names: HashMap<String, SocketAddr>,
+    // sessions whose connection was lost, the username stays taken until
+    // they expire
+    pub suspended: HashMap<String, SuspendedSession>,
+    pub bans: BanList,
+    // muted usernames and when their mute ends, in milliseconds since the
+    // unix epoch
+    pub muted: HashMap<String, u64>,
+}
+
+/// A session waiting for its client to come back.
+pub struct SuspendedSession {
+    // the username as it was registered
+    pub name: String,
+    pub author: String,
+    pub token: String,
+    pub rooms: Vec<String>,
+    pub expires: Instant,
 }
 
 impl Default for ServerState {
@@ -37,20 +248,10 @@ impl ServerState {
     pub fn new() -> Self {
         Self {
             connections: HashMap::new(),
-            user_names: HashSet::new(),
+            user_names: HashMap::new(),
+            suspended: HashMap::new(),
+            bans: BanList::new(),
+            muted: HashMap::new(),
         }
     }
 }
-
-pub static CENTRAL_CONTROLLER_HANDLE: LazyLock<OnceCell<ServerActorHandler>> =
-    LazyLock::new(OnceCell::new);
-
-pub async fn init_central_controller(size: usize, listener: TcpListener) -> JoinHandle<()> {
-    let (this_handle, join_handle): (ServerActorHandler, JoinHandle<()>) =
-        ServerActorHandler::new(size, listener);
-    CENTRAL_CONTROLLER_HANDLE
-        .set(this_handle)
-        .map_err(|_| "Failed to initialize central actor")
-        .unwrap();
-    join_handle
-}
@@ -0,0 +1,196 @@
+/*
+ *  An implementation that can be used as a shard owning a subset of rooms
+ */
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::sync::oneshot;
+
+use crate::actor::room_shard::RoomShardHandle;
+use crate::actor::tcp_handler::TcpActorHandle;
+use crate::msg::{Capabilities, RoomInfo};
+
+/// A registered connection, as the room shards see it.
+pub struct Member {
+    pub addr: SocketAddr,
+    pub name: String,
+    // what the member's messages are recorded as written by
+    pub author: String,
+    pub handle: TcpActorHandle,
+    // the features agreed on during the handshake
+    pub capabilities: Capabilities,
+}
+
+impl fmt::Debug for Member {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Member({} at {})", self.name, self.addr)
+    }
+}
+
+/// Messages for a room shard. Everything about a room goes to the one shard
+/// that owns it, so its messages are handled in the order they arrive.
+#[derive(Debug)]
+pub enum ShardMessage {
+    JoinRoom {
+        member: Arc<Member>,
+        room: String,
+        // replay what came after this message rather than the most recent
+        // ones, for a resumed session
+        since: Option<u64>,
+    },
+    LeaveRoom {
+        member: Arc<Member>,
+        room: String,
+    },
+    UserMessage {
+        member: Arc<Member>,
+        room: String,
+        message: String,
+        // acknowledged once broadcast, if set
+        nonce: Option<u64>,
+    },
+    FetchHistory {
+        member: Arc<Member>,
+        room: String,
+        before_seq: Option<u64>,
+        after_seq: Option<u64>,
+        limit: u32,
+    },
+    SearchHistory {
+        member: Arc<Member>,
+        room: String,
+        query: String,
+        limit: u32,
+    },
+    // `member` edits the message `id` of a room on this shard, or deletes it
+    // if there is no `new_text`, provided it wrote it or is an operator
+    Amend {
+        member: Arc<Member>,
+        id: u64,
+        new_text: Option<String>,
+        operator: bool,
+    },
+    // the rooms on this shard that have someone in them
+    ListRooms {
+        reply: oneshot::Sender<Vec<RoomInfo>>,
+    },
+    // the connection is gone, take it out of every room on this shard
+    ConnectionDropped {
+        addr: SocketAddr,
+    },
+}
+
+pub struct ShardState {
+    // members of every room on this shard that currently has someone in it
+    pub rooms: HashMap<String, HashMap<SocketAddr, Arc<Member>>>,
+    // the rooms on this shard each connection is in
+    pub memberships: HashMap<SocketAddr, HashSet<String>>,
+}
+
+impl Default for ShardState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShardState {
+    pub fn new() -> Self {
+        Self {
+            rooms: HashMap::new(),
+            memberships: HashMap::new(),
+        }
+    }
+
+    pub fn is_member(&self, addr: SocketAddr, room: &str) -> bool {
+        self.rooms
+            .get(room)
+            .is_some_and(|members| members.contains_key(&addr))
+    }
+
+    /// Adds a member to a room, creating the room if needed. Returns
+    /// `false` if it was already a member.
+    pub fn join_room(&mut self, member: &Arc<Member>, room: &str) -> bool {
+        let members = self.rooms.entry(room.to_string()).or_default();
+        if members.contains_key(&member.addr) {
+            return false;
+        }
+        members.insert(member.addr, member.clone());
+        self.memberships
+            .entry(member.addr)
+            .or_default()
+            .insert(room.to_string());
+        true
+    }
+
+    /// Removes a connection from a room, dropping the room once it is empty.
+    /// Returns `false` if the connection was not a member.
+    pub fn leave_room(&mut self, addr: SocketAddr, room: &str) -> bool {
+        let Some(members) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        if members.remove(&addr).is_none() {
+            return false;
+        }
+        if members.is_empty() {
+            self.rooms.remove(room);
+        }
+        if let Some(rooms) = self.memberships.get_mut(&addr) {
+            rooms.remove(room);
+            if rooms.is_empty() {
+                self.memberships.remove(&addr);
+            }
+        }
+        true
+    }
+
+    /// Removes a connection from every room on this shard.
+    pub fn leave_all(&mut self, addr: SocketAddr) {
+        for room in self.memberships.remove(&addr).unwrap_or_default() {
+            if let Some(members) = self.rooms.get_mut(&room) {
+                members.remove(&addr);
+                if members.is_empty() {
+                    self.rooms.remove(&room);
+                }
+            }
+        }
+    }
+}
+
+/// Handles to every room shard, and which of them owns a given room.
+#[derive(Clone)]
+pub struct ShardMap {
+    shards: Arc<[RoomShardHandle]>,
+}
+
+impl ShardMap {
+    pub fn new(shards: Vec<RoomShardHandle>) -> Self {
+        assert!(!shards.is_empty(), "at least one room shard is needed");
+        Self {
+            shards: shards.into(),
+        }
+    }
+
+    /// The shard that owns `room`.
+    pub fn for_room(&self, room: &str) -> &RoomShardHandle {
+        let mut hasher = DefaultHasher::new();
+        room.hash(&mut hasher);
+        &self.shards[(hasher.finish() % self.shards.len() as u64) as usize]
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &RoomShardHandle> {
+        self.shards.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.is_empty()
+    }
+}
@@ -4,29 +4,137 @@
 
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use tokio::sync::mpsc;
+use tokio::time::Instant;
 
-use crate::{
-    actor::{
-        server_actor::ServerActorHandler,
-    },
-};
+use crate::actor::rate_limit::{FloodGuard, RateLimit};
+use crate::actor::server_actor::ServerActorHandler;
+use crate::actor::tcp_handler::Heartbeat;
+use crate::actor_impl::server_impl::ConnectionMessage;
+use crate::actor_impl::shard_impl::{Member, ShardMap, ShardMessage};
+use crate::history::now_millis;
+use crate::msg::validate::MessageRules;
+use crate::msg::ServerResponse;
 
 pub struct SingleConnectionHandler {}
 
 pub struct SingleConnectionState {
-    pub controller_handle: Arc<&'static ServerActorHandler>,
+    // the server this connection reports to
+    pub controller_handle: ServerActorHandler,
+    // the shards room traffic goes to directly
+    pub shards: ShardMap,
     pub addr: SocketAddr,
+    // the client opened with `Hello`, a client that opens with anything
+    // else is refused
+    pub greeted: bool,
+    // set once the server accepted a username, room traffic is ignored
+    // until then
+    pub member: Option<Arc<Member>>,
+    // waiting to hear whether the server accepts a username
+    pub registering: bool,
+    // held until the server has heard of the connection, the listener waits
+    // for every one of these to be gone before it reports being closed
+    pub unannounced: Option<mpsc::Sender<()>>,
+    // the client said goodbye, nothing it sends afterwards is acted on
+    pub leaving: bool,
+    // the rooms the client was told it joined, oldest first, kept so a
+    // suspended session knows where to go back to
+    pub rooms: Vec<String>,
+    pub heartbeat: Heartbeat,
+    // whether the peer answers pings
+    pub pings: bool,
+    // whether the peer is told about moderation
+    pub moderation: bool,
+    // messages are rejected until then, in milliseconds since the unix epoch
+    pub muted_until: Option<u64>,
+    // when the client has to have a username by, `None` once it has one
+    pub registration_deadline: Option<Instant>,
+    // what the client's chat messages may contain
+    pub rules: MessageRules,
+    // whether the peer is told its messages broke the rules
+    pub rejections: bool,
+    // whether the peer is told it went over the rate limit
+    pub rate_limits: bool,
+    // whether the peer is told what became of chat messages it gave a nonce
+    pub acks: bool,
+    // what the client may still say, `None` without a rate limit
+    pub flood: Option<FloodGuard>,
+    // pings sent since the peer was last heard from
+    pub unanswered: u32,
+    pub pings_sent: u64,
+    pub last_heard: Instant,
 }
 
 impl SingleConnectionState {
     pub fn new(
-        controller_handle: Arc<&'static ServerActorHandler>,
+        controller_handle: ServerActorHandler,
+        shards: ShardMap,
         addr: SocketAddr,
+        heartbeat: Heartbeat,
+        rate_limit: Option<RateLimit>,
+        rules: MessageRules,
+        registration_timeout: Option<Duration>,
     ) -> Self {
         Self {
             controller_handle,
+            shards,
             addr,
+            greeted: false,
+            member: None,
+            registering: false,
+            unannounced: None,
+            leaving: false,
+            rooms: vec![],
+            heartbeat,
+            pings: false,
+            moderation: false,
+            muted_until: None,
+            registration_deadline: registration_timeout.map(|timeout| Instant::now() + timeout),
+            rules,
+            rejections: false,
+            rate_limits: false,
+            acks: false,
+            flood: rate_limit.map(FloodGuard::new),
+            unanswered: 0,
+            pings_sent: 0,
+            last_heard: Instant::now(),
         }
     }
+
+    /// When the mute that keeps this client from talking ends, if it is
+    /// muted.
+    pub fn muted(&self) -> Option<u64> {
+        self.muted_until.filter(|until| now_millis() < *until)
+    }
+
+    /// Follows the client in and out of rooms by what it is told.
+    pub fn track_rooms(&mut self, response: &ServerResponse) {
+        match response {
+            ServerResponse::RoomJoined { room } if !self.rooms.contains(room) => {
+                self.rooms.push(room.clone());
+            }
+            ServerResponse::RoomLeft { room } => self.rooms.retain(|r| r != room),
+            _ => {}
+        }
+    }
+
+    /// Lets the server and every shard the connection may be in know it is
+    /// gone.
+    pub async fn dropped(&self) {
+        if self.member.is_some() {
+            for shard in self.shards.all() {
+                shard
+                    .send(ShardMessage::ConnectionDropped { addr: self.addr })
+                    .await;
+            }
+        }
+        self.controller_handle
+            .send(ConnectionMessage::ConnectionDropped {
+                addr: self.addr,
+                rooms: self.rooms.clone(),
+            })
+            .await;
+    }
 }
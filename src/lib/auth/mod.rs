@@ -0,0 +1,86 @@
+/*
+ *  Checking that users are who they claim to be before they get a username
+ */
+
+pub mod password;
+pub mod token;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+
+pub use password::PasswordFileAuthenticator;
+pub use token::StaticTokenAuthenticator;
+
+pub(crate) use token::constant_time_eq;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// There is no account with that username.
+    UnknownUser,
+    /// The account exists but the credential does not match it.
+    InvalidCredential,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::UnknownUser => write!(f, "unknown user"),
+            AuthError::InvalidCredential => write!(f, "invalid credential"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// A password or token on its way to an [`Authenticator`], kept out of logs.
+#[derive(Clone)]
+pub struct Credential(pub String);
+
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Credential(<redacted>)")
+    }
+}
+
+/// Decides whether a credential proves ownership of a username. Checks may
+/// be deliberately slow, so the server runs them on the blocking pool.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, username: &str, credential: &str) -> Result<(), AuthError>;
+}
+
+/// Reads a file of `username:secret` lines, skipping blank lines and lines
+/// starting with `#`.
+pub(crate) fn read_entries(path: &Path) -> io::Result<Vec<(String, String)>> {
+    let contents = fs::read_to_string(path)?;
+    let mut entries = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((username, secret)) = line.split_once(':') else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "line {} of {} is not `username:secret`",
+                    number + 1,
+                    path.display()
+                ),
+            ));
+        };
+        entries.push((username.to_string(), secret.to_string()));
+    }
+    Ok(entries)
+}
+
+/// A random token, as hex, that is hard enough to guess to stand in for a
+/// credential.
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,67 @@
+/*
+ *  Authentication against a file of argon2 password hashes
+ */
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, SaltString};
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+
+use super::{read_entries, AuthError, Authenticator};
+
+/// Accounts loaded from a file of `username:hash` lines, where the hash is a
+/// PHC string as produced by [`hash_password`].
+pub struct PasswordFileAuthenticator {
+    accounts: HashMap<String, String>,
+    // checked against for unknown usernames, so that they take as long to
+    // turn away as wrong passwords and cannot be told apart by timing
+    dummy: String,
+}
+
+impl PasswordFileAuthenticator {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut accounts = HashMap::new();
+        for (username, hash) in read_entries(path)? {
+            // reject broken hashes now rather than on every login attempt
+            if let Err(e) = PasswordHash::new(&hash) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "bad password hash for {username} in {}: {e}",
+                        path.display()
+                    ),
+                ));
+            }
+            accounts.insert(username, hash);
+        }
+        let dummy = hash_password(SaltString::generate(&mut OsRng).as_str())
+            .map_err(|e| io::Error::other(format!("failed to hash a password: {e}")))?;
+        Ok(Self { accounts, dummy })
+    }
+}
+
+impl Authenticator for PasswordFileAuthenticator {
+    fn authenticate(&self, username: &str, credential: &str) -> Result<(), AuthError> {
+        let Some(hash) = self.accounts.get(username) else {
+            if let Ok(dummy) = PasswordHash::new(&self.dummy) {
+                let _ = Argon2::default().verify_password(credential.as_bytes(), &dummy);
+            }
+            return Err(AuthError::UnknownUser);
+        };
+        let hash = PasswordHash::new(hash).map_err(|_| AuthError::InvalidCredential)?;
+        Argon2::default()
+            .verify_password(credential.as_bytes(), &hash)
+            .map_err(|_| AuthError::InvalidCredential)
+    }
+}
+
+/// Hashes a password with a fresh salt, giving the PHC string to put in a
+/// password file.
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
@@ -0,0 +1,47 @@
+/*
+ *  Authentication against a fixed set of per-user tokens
+ */
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use super::{read_entries, AuthError, Authenticator};
+
+/// Every user has a single pre-shared token, loaded from a file of
+/// `username:token` lines or built in code.
+pub struct StaticTokenAuthenticator {
+    tokens: HashMap<String, String>,
+}
+
+impl StaticTokenAuthenticator {
+    pub fn new(tokens: HashMap<String, String>) -> Self {
+        Self { tokens }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(
+            read_entries(path.as_ref())?.into_iter().collect(),
+        ))
+    }
+}
+
+impl Authenticator for StaticTokenAuthenticator {
+    fn authenticate(&self, username: &str, credential: &str) -> Result<(), AuthError> {
+        let token = self.tokens.get(username).ok_or(AuthError::UnknownUser)?;
+        if constant_time_eq(token.as_bytes(), credential.as_bytes()) {
+            Ok(())
+        } else {
+            Err(AuthError::InvalidCredential)
+        }
+    }
+}
+
+/// Compares two byte strings in time that depends only on their lengths, so
+/// a wrong token does not reveal how much of it was right.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
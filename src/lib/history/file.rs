@@ -0,0 +1,280 @@
+/*
+ *  A history store backed by an append-only file of JSON lines
+ */
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+
+use super::memory::RingBuffer;
+use super::{is_after, is_before, mentions, HistoryStore};
+use crate::msg::HistoryEntry;
+
+/// Appends every message to a file, one JSON object per line, and keeps the
+/// last `cache_capacity` of them in memory to serve replays. Sequence numbers
+/// carry on from the last entry or sequence mark found in the file. Edits and
+/// deletions are appended as later versions of the entry they change. Queries
+/// the cache cannot answer fall back to reading the whole file.
+///
+/// Lines are written by a thread of the store's own, so recording a message
+/// never waits for the disk.
+pub struct FileHistoryStore {
+    path: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+/// How many sequence numbers a mark sets aside at a time, so that numbers
+/// taken for messages without an entry rarely need a line of their own.
+const SEQ_BLOCK: u64 = 1000;
+
+/// A line recording that sequence numbers below `next_seq` may have been
+/// handed out for messages that have no entry of their own.
+#[derive(Serialize, Deserialize)]
+struct SeqMark {
+    next_seq: u64,
+}
+
+struct Inner {
+    // lines for the writer thread, queued in the order they go in the file
+    lines: mpsc::Sender<Job>,
+    cache: RingBuffer,
+    // numbers below this are covered by a mark or an entry in the file
+    reserved: u64,
+    // every entry ever deleted, the cache only knows of the ones it holds
+    deleted: HashSet<u64>,
+}
+
+/// Work for the writer thread.
+enum Job {
+    Line(Vec<u8>),
+    // answered once every line queued before it is written
+    Flush(mpsc::SyncSender<()>),
+}
+
+impl FileHistoryStore {
+    pub fn open(path: impl AsRef<Path>, cache_capacity: usize) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut cache = RingBuffer::new(cache_capacity, 0);
+        let mut deleted = HashSet::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for (number, line) in reader.lines().enumerate() {
+                let line = line?;
+                match serde_json::from_str::<HistoryEntry>(&line) {
+                    Ok(entry) => {
+                        if entry.deleted {
+                            deleted.insert(entry.seq);
+                        }
+                        cache.restore(entry)
+                    }
+                    Err(e) => match serde_json::from_str::<SeqMark>(&line) {
+                        Ok(mark) => cache.skip_to(mark.next_seq),
+                        // most likely a line cut short by a crash, skip it
+                        Err(_) => {
+                            eprintln!("skipping line {} of {}: {}", number + 1, path.display(), e)
+                        }
+                    },
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (lines, jobs) = mpsc::channel();
+        let writing = path.to_path_buf();
+        thread::Builder::new()
+            .name("history-writer".to_string())
+            .spawn(move || write_lines(file, &writing, jobs))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            inner: Mutex::new(Inner {
+                lines,
+                cache,
+                reserved: 0,
+                deleted,
+            }),
+        })
+    }
+
+    /// Answers a query from the cache when it is known to hold every match,
+    /// reading the file otherwise.
+    fn query(
+        &self,
+        limit: usize,
+        pred: impl Fn(&HistoryEntry) -> bool,
+    ) -> io::Result<Vec<HistoryEntry>> {
+        {
+            let inner = self.inner.lock().unwrap();
+            let found = inner.cache.last_matching(limit, &pred);
+            if found.len() == limit || inner.cache.is_complete() {
+                return Ok(found);
+            }
+        }
+        self.flush();
+        let mut found = VecDeque::with_capacity(limit);
+        for entry in self.current()?.filter(|entry| pred(entry)) {
+            if found.len() == limit {
+                found.pop_front();
+            }
+            found.push_back(entry);
+        }
+        Ok(found.into())
+    }
+
+    /// The latest version of every entry in the file that was not deleted,
+    /// in the order they were first written.
+    fn current(&self) -> io::Result<impl Iterator<Item = HistoryEntry>> {
+        // the latest version of every entry that was edited or deleted,
+        // which has to be known before the entry itself is looked at
+        let mut amended = HashMap::new();
+        for entry in self.read()? {
+            if entry.edited_ms.is_some() {
+                amended.insert(entry.seq, entry);
+            }
+        }
+        Ok(self
+            .read()?
+            .filter(|entry| entry.edited_ms.is_none())
+            .map(move |entry| amended.remove(&entry.seq).unwrap_or(entry))
+            .filter(|entry| !entry.deleted))
+    }
+
+    /// Every entry in the file, in the order they were written.
+    fn read(&self) -> io::Result<impl Iterator<Item = HistoryEntry>> {
+        let reader = BufReader::new(File::open(&self.path)?);
+        // lines that fail to parse were already reported by `open`, or are
+        // still being written
+        Ok(reader
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str(&line).ok()))
+    }
+
+    /// Waits until every line queued so far is in the file.
+    fn flush(&self) {
+        let (done, written) = mpsc::sync_channel(1);
+        let queued = self.inner.lock().unwrap().lines.send(Job::Flush(done));
+        if queued.is_ok() {
+            let _ = written.recv();
+        }
+    }
+}
+
+impl Inner {
+    /// Queues `line` to be appended as a line of its own.
+    fn write(&self, line: &impl Serialize) {
+        match serde_json::to_vec(line) {
+            Ok(mut line) => {
+                line.push(b'\n');
+                if self.lines.send(Job::Line(line)).is_err() {
+                    eprintln!("history writer is gone, dropping a line");
+                }
+            }
+            Err(e) => eprintln!("failed to serialize history line: {e}"),
+        }
+    }
+}
+
+impl Drop for FileHistoryStore {
+    fn drop(&mut self) {
+        // whoever opens the file next expects to find everything in it
+        self.flush();
+    }
+}
+
+/// Appends the lines it is sent to `file` until the store is gone.
+fn write_lines(mut file: File, path: &Path, jobs: mpsc::Receiver<Job>) {
+    for job in jobs {
+        match job {
+            // written in one go so a crash can at worst cut the last line
+            // short, which `open` knows to skip
+            Job::Line(line) => {
+                if let Err(e) = file.write_all(&line) {
+                    eprintln!("failed to append to {}: {}", path.display(), e);
+                }
+            }
+            Job::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+impl HistoryStore for FileHistoryStore {
+    fn append(&self, room: &str, username: &str, author: &str, message: &str) -> HistoryEntry {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.cache.stamp(room, username, author, message);
+        inner.write(&entry);
+        inner.cache.push(entry.clone());
+        entry
+    }
+
+    fn next_seq(&self) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let seq = inner.cache.take_seq();
+        // nothing else tells `open` the number is gone, a mark covers the
+        // next block of numbers so most of them need no line at all
+        if seq >= inner.reserved {
+            inner.reserved = seq + SEQ_BLOCK;
+            inner.write(&SeqMark {
+                next_seq: inner.reserved,
+            });
+        }
+        seq
+    }
+
+    fn find(&self, seq: u64) -> io::Result<Option<HistoryEntry>> {
+        Ok(self.query(1, |entry| entry.seq == seq)?.pop())
+    }
+
+    fn amend(&self, entry: &HistoryEntry) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        // past the cache an entry is only known to be gone if it was deleted
+        let current = if inner.cache.holds_after(entry.seq) {
+            inner.cache.amend(entry.clone())
+        } else {
+            !inner.deleted.contains(&entry.seq)
+        };
+        if !current {
+            return false;
+        }
+        if entry.deleted {
+            inner.deleted.insert(entry.seq);
+        }
+        inner.write(entry);
+        true
+    }
+
+    fn before(
+        &self,
+        room: &str,
+        before_seq: Option<u64>,
+        limit: usize,
+    ) -> io::Result<Vec<HistoryEntry>> {
+        self.query(limit, |entry| is_before(entry, room, before_seq))
+    }
+
+    fn after(&self, room: &str, after_seq: u64, limit: usize) -> io::Result<Vec<HistoryEntry>> {
+        let pred = |entry: &HistoryEntry| is_after(entry, room, after_seq);
+        {
+            let inner = self.inner.lock().unwrap();
+            if inner.cache.holds_after(after_seq) {
+                return Ok(inner.cache.first_matching(limit, pred));
+            }
+        }
+        self.flush();
+        Ok(self
+            .current()?
+            .filter(|entry| pred(entry))
+            .take(limit)
+            .collect())
+    }
+
+    fn search(&self, room: &str, query: &str, limit: usize) -> io::Result<Vec<HistoryEntry>> {
+        let query = query.to_lowercase();
+        self.query(limit, |entry| mentions(entry, room, &query))
+    }
+}
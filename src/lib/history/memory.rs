@@ -0,0 +1,196 @@
+/*
+ *  A history store that only keeps the latest messages in memory
+ */
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Mutex;
+
+use super::{is_after, is_before, mentions, now_millis, HistoryStore};
+use crate::msg::HistoryEntry;
+
+/// Keeps the last `capacity` messages across all rooms in a ring buffer,
+/// older messages are forgotten.
+pub struct MemoryHistoryStore {
+    inner: Mutex<RingBuffer>,
+}
+
+pub(crate) struct RingBuffer {
+    next_seq: u64,
+    capacity: usize,
+    entries: VecDeque<HistoryEntry>,
+    // set once an entry has been pushed out to make room for a newer one
+    evicted: bool,
+}
+
+impl RingBuffer {
+    pub(crate) fn new(capacity: usize, next_seq: u64) -> Self {
+        Self {
+            next_seq,
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+            evicted: false,
+        }
+    }
+
+    /// Builds the entry that will be stored next without storing it yet.
+    pub(crate) fn stamp(
+        &self,
+        room: &str,
+        username: &str,
+        author: &str,
+        message: &str,
+    ) -> HistoryEntry {
+        HistoryEntry {
+            seq: self.next_seq,
+            timestamp_ms: now_millis(),
+            room: room.to_string(),
+            username: username.to_string(),
+            author: author.to_string(),
+            message: message.to_string(),
+            edited_ms: None,
+            deleted: false,
+        }
+    }
+
+    /// Hands out the next sequence number without storing anything.
+    pub(crate) fn take_seq(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq - 1
+    }
+
+    /// Makes sure no number below `next_seq` is handed out any more.
+    pub(crate) fn skip_to(&mut self, next_seq: u64) {
+        self.next_seq = self.next_seq.max(next_seq);
+    }
+
+    pub(crate) fn push(&mut self, entry: HistoryEntry) {
+        self.next_seq = self.next_seq.max(entry.seq + 1);
+        if self.entries.len() == self.capacity {
+            self.evicted = true;
+            if self.capacity == 0 {
+                return;
+            }
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Replaces the entry `entry` is a new version of, or drops it if it was
+    /// deleted. Entries that are no longer held stay forgotten, returns
+    /// whether it was held.
+    pub(crate) fn amend(&mut self, entry: HistoryEntry) -> bool {
+        let Ok(index) = self.entries.binary_search_by_key(&entry.seq, |e| e.seq) else {
+            return false;
+        };
+        if entry.deleted {
+            self.entries.remove(index);
+        } else {
+            self.entries[index] = entry;
+        }
+        true
+    }
+
+    /// Pushes an entry read back from storage, which is either new or a
+    /// later version of one pushed before.
+    pub(crate) fn restore(&mut self, entry: HistoryEntry) {
+        if entry.seq < self.next_seq {
+            self.amend(entry);
+        } else {
+            self.push(entry);
+        }
+    }
+
+    /// Whether every entry ever pushed is still held.
+    pub(crate) fn is_complete(&self) -> bool {
+        !self.evicted
+    }
+
+    /// Whether every entry ever pushed after `seq` is still held.
+    pub(crate) fn holds_after(&self, seq: u64) -> bool {
+        self.is_complete() || self.entries.front().is_some_and(|entry| entry.seq <= seq)
+    }
+
+    /// The first `limit` entries for which `pred` holds, oldest first.
+    pub(crate) fn first_matching(
+        &self,
+        limit: usize,
+        pred: impl Fn(&HistoryEntry) -> bool,
+    ) -> Vec<HistoryEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| pred(entry))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// The last `limit` entries for which `pred` holds, oldest first.
+    pub(crate) fn last_matching(
+        &self,
+        limit: usize,
+        pred: impl Fn(&HistoryEntry) -> bool,
+    ) -> Vec<HistoryEntry> {
+        let mut found: Vec<HistoryEntry> = self
+            .entries
+            .iter()
+            .rev()
+            .filter(|entry| pred(entry))
+            .take(limit)
+            .cloned()
+            .collect();
+        found.reverse();
+        found
+    }
+}
+
+impl MemoryHistoryStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(RingBuffer::new(capacity, 0)),
+        }
+    }
+}
+
+impl HistoryStore for MemoryHistoryStore {
+    fn append(&self, room: &str, username: &str, author: &str, message: &str) -> HistoryEntry {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.stamp(room, username, author, message);
+        inner.push(entry.clone());
+        entry
+    }
+
+    fn next_seq(&self) -> u64 {
+        self.inner.lock().unwrap().take_seq()
+    }
+
+    fn find(&self, seq: u64) -> io::Result<Option<HistoryEntry>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.last_matching(1, |entry| entry.seq == seq).pop())
+    }
+
+    fn amend(&self, entry: &HistoryEntry) -> bool {
+        self.inner.lock().unwrap().amend(entry.clone())
+    }
+
+    fn before(
+        &self,
+        room: &str,
+        before_seq: Option<u64>,
+        limit: usize,
+    ) -> io::Result<Vec<HistoryEntry>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.last_matching(limit, |entry| is_before(entry, room, before_seq)))
+    }
+
+    fn after(&self, room: &str, after_seq: u64, limit: usize) -> io::Result<Vec<HistoryEntry>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.first_matching(limit, |entry| is_after(entry, room, after_seq)))
+    }
+
+    fn search(&self, room: &str, query: &str, limit: usize) -> io::Result<Vec<HistoryEntry>> {
+        let query = query.to_lowercase();
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.last_matching(limit, |entry| mentions(entry, room, &query)))
+    }
+}
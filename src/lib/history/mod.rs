@@ -0,0 +1,87 @@
+/*
+ *  Storage for messages broadcast to rooms, so they can be replayed to users
+ *  that were not around when they were sent
+ */
+
+pub mod file;
+pub mod memory;
+
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::msg::HistoryEntry;
+
+pub use file::FileHistoryStore;
+pub use memory::MemoryHistoryStore;
+
+/// A place where the server records every broadcast. Implementations are
+/// shared between tasks, so they synchronise internally. Queries may have to
+/// go to disk and should be run off the async runtime.
+pub trait HistoryStore: Send + Sync {
+    /// Records a message broadcast to `room` by `author` under `username`,
+    /// stamping it with the current time and the next sequence number. The
+    /// entry is handed back even if it could not be persisted, stores log
+    /// such failures themselves.
+    fn append(&self, room: &str, username: &str, author: &str, message: &str) -> HistoryEntry;
+
+    /// Takes the next sequence number for a message that is not recorded,
+    /// such as a direct message. Stores that outlive the server remember
+    /// numbers taken this way too, so they are never handed out again.
+    fn next_seq(&self) -> u64;
+
+    /// The entry with sequence number `seq`, if it is still kept and was not
+    /// deleted.
+    fn find(&self, seq: u64) -> io::Result<Option<HistoryEntry>>;
+
+    /// Records a new version of an entry appended before, edited or deleted,
+    /// unless the entry is no longer kept or was deleted meanwhile. Returns
+    /// whether it did. Like `append`, failures to persist it are logged by
+    /// the store.
+    fn amend(&self, entry: &HistoryEntry) -> bool;
+
+    /// Up to `limit` entries of `room` older than `before_seq`, or the newest
+    /// ones if it is `None`, oldest first.
+    fn before(
+        &self,
+        room: &str,
+        before_seq: Option<u64>,
+        limit: usize,
+    ) -> io::Result<Vec<HistoryEntry>>;
+
+    /// Up to `limit` of the most recent entries of `room` whose message
+    /// contains `query`, ignoring case, oldest first.
+    fn search(&self, room: &str, query: &str, limit: usize) -> io::Result<Vec<HistoryEntry>>;
+
+    /// Up to `limit` of the oldest entries of `room` newer than `after_seq`,
+    /// oldest first, so that whoever missed them can read on from there.
+    fn after(&self, room: &str, after_seq: u64, limit: usize) -> io::Result<Vec<HistoryEntry>>;
+
+    /// Up to `limit` of the most recent entries of `room`, oldest first.
+    fn recent(&self, room: &str, limit: usize) -> io::Result<Vec<HistoryEntry>> {
+        self.before(room, None, limit)
+    }
+}
+
+/// Whether `entry` belongs to `room` and comes before `before_seq`.
+pub(crate) fn is_before(entry: &HistoryEntry, room: &str, before_seq: Option<u64>) -> bool {
+    entry.room == room && before_seq.is_none_or(|seq| entry.seq < seq)
+}
+
+/// Whether `entry` belongs to `room` and comes after `after_seq`.
+pub(crate) fn is_after(entry: &HistoryEntry, room: &str, after_seq: u64) -> bool {
+    entry.room == room && entry.seq > after_seq
+}
+
+/// Whether `entry` belongs to `room` and mentions `query`, which has to be
+/// lowercase already.
+pub(crate) fn mentions(entry: &HistoryEntry, room: &str, query: &str) -> bool {
+    entry.room == room && entry.message.to_lowercase().contains(query)
+}
+
+/// Milliseconds since the unix epoch, as recorded in history entries.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
@@ -1,3 +1,9 @@
-pub mod msg;
 pub mod actor;
 pub mod actor_impl;
+pub mod auth;
+pub mod history;
+pub mod moderation;
+pub mod msg;
+pub mod registration;
+pub mod server;
+pub mod transport;
@@ -0,0 +1,131 @@
+/*
+ *  Keeping abusive users quiet or out of the server altogether
+ */
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::registration::fold;
+
+/// What an operator does to a user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Moderation {
+    // close the user's connection, it may come back
+    Kick { reason: Option<String> },
+    // kick the user and keep its name out, and its address if `ip` is set
+    Ban { reason: Option<String>, ip: bool },
+    // ignore what the user says for a while, a zero duration lifts it
+    Mute { duration: Duration },
+}
+
+/// Something kept out of the server.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Ban {
+    Name(String),
+    Ip(IpAddr),
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ban::Name(name) => write!(f, "name:{name}"),
+            Ban::Ip(ip) => write!(f, "ip:{ip}"),
+        }
+    }
+}
+
+/// Usernames and addresses that may not connect, loaded from a file of
+/// `name:<username>` and `ip:<address>` lines that new bans are added to.
+#[derive(Debug, Clone, Default)]
+pub struct BanList {
+    // folded usernames
+    names: HashSet<String>,
+    ips: HashSet<IpAddr>,
+    // where bans are recorded, `None` to forget them on restart
+    file: Option<PathBuf>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the bans in `path`, skipping blank lines and lines starting
+    /// with `#`. The file need not exist yet, it is created by the first ban.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut bans = Self {
+            file: Some(path.to_path_buf()),
+            ..Self::default()
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(bans),
+            Err(e) => return Err(e),
+        };
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let ban = match line.split_once(':') {
+                Some(("name", name)) if !name.is_empty() => Ban::Name(name.to_string()),
+                Some(("ip", ip)) => match ip.parse() {
+                    Ok(ip) => Ban::Ip(ip),
+                    Err(_) => return Err(invalid_line(path, number)),
+                },
+                _ => return Err(invalid_line(path, number)),
+            };
+            bans.insert(ban);
+        }
+        Ok(bans)
+    }
+
+    /// Whether `name`, or a name that only differs from it in case, is
+    /// banned.
+    pub fn is_name_banned(&self, name: &str) -> bool {
+        self.names.contains(&fold(name))
+    }
+
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        self.ips.contains(&ip)
+    }
+
+    /// Adds a ban in memory only, returns `false` if it was in place already.
+    pub fn insert(&mut self, ban: Ban) -> bool {
+        match ban {
+            Ban::Name(name) => self.names.insert(fold(&name)),
+            Ban::Ip(ip) => self.ips.insert(ip),
+        }
+    }
+
+    /// The file bans are recorded in, if any.
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+}
+
+/// Appends `bans` to the file at `path`, one per line.
+pub fn record_bans(path: &Path, bans: &[Ban]) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    for ban in bans {
+        writeln!(file, "{ban}")?;
+    }
+    Ok(())
+}
+
+fn invalid_line(path: &Path, number: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "line {} of {} is not `name:<username>` or `ip:<address>`",
+            number + 1,
+            path.display()
+        ),
+    )
+}
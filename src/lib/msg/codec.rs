@@ -0,0 +1,255 @@
+/*
+ *  Length-prefixed framing for messages travelling over a tcp stream
+ *
+ *  Every frame is a big-endian u32 holding the length of the body followed
+ *  by the body itself, which is produced by `TcpMessage::to_bytes`.
+ */
+
+use std::{fmt, io, marker::PhantomData};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{ClientMessage, ServerResponse, TcpMessage};
+
+/// Number of bytes used by the length prefix of every frame.
+pub const HEADER_LEN: usize = 4;
+
+/// Default upper bound on the body of a single frame.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// Codec used by the server: decodes what clients send and encodes responses.
+pub type ServerCodec = MessageCodec<ClientMessage, ServerResponse>;
+
+/// Codec used by the client: decodes server responses and encodes requests.
+pub type ClientCodec = MessageCodec<ServerResponse, ClientMessage>;
+
+/// A message serialized once into a complete frame, header included, that
+/// can be written to any number of connections. Cloning it only bumps a
+/// reference count.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedFrame(Bytes);
+
+impl EncodedFrame {
+    /// The frame exactly as it goes on the wire.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    /// The underlying stream failed.
+    Io(io::Error),
+    /// A peer announced (or we tried to send) a frame larger than allowed.
+    FrameTooLarge { size: usize, max: usize },
+    /// The frame was complete but its body could not be deserialized.
+    Malformed,
+    /// The message could not be serialized.
+    Serialize,
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "io error: {e}"),
+            CodecError::FrameTooLarge { size, max } => {
+                write!(f, "frame of {size} bytes exceeds the limit of {max} bytes")
+            }
+            CodecError::Malformed => write!(f, "failed to deserialize frame"),
+            CodecError::Serialize => write!(f, "failed to serialize message"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+/// A `Decoder`/`Encoder` pair that frames `D` on the way in and `E` on the
+/// way out, so a single codec can sit on either end of a `Framed` stream.
+pub struct MessageCodec<D, E> {
+    max_frame_len: usize,
+    _marker: PhantomData<fn(E) -> D>,
+}
+
+impl<D, E> MessageCodec<D, E> {
+    pub fn new() -> Self {
+        Self::with_max_frame_len(DEFAULT_MAX_FRAME_LEN)
+    }
+
+    pub fn with_max_frame_len(max_frame_len: usize) -> Self {
+        Self {
+            max_frame_len,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
+}
+
+// by hand, deriving would ask for `D: Clone, E: Clone` the codec never uses
+impl<D, E> Clone for MessageCodec<D, E> {
+    fn clone(&self) -> Self {
+        Self::with_max_frame_len(self.max_frame_len)
+    }
+}
+
+impl<D, E: TcpMessage> MessageCodec<D, E> {
+    /// Serializes a message into a frame ahead of time, for sending the
+    /// same message to many peers.
+    pub fn encode_frame(&self, item: &E) -> Result<EncodedFrame, CodecError> {
+        let mut dst = BytesMut::new();
+        self.encode_into(item, &mut dst)?;
+        Ok(EncodedFrame(dst.freeze()))
+    }
+
+    fn encode_into(&self, item: &E, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let body = item.to_bytes().ok_or(CodecError::Serialize)?;
+        if body.len() > self.max_frame_len {
+            return Err(CodecError::FrameTooLarge {
+                size: body.len(),
+                max: self.max_frame_len,
+            });
+        }
+        dst.reserve(HEADER_LEN + body.len());
+        dst.put_u32(body.len() as u32);
+        dst.extend_from_slice(&body);
+        Ok(())
+    }
+}
+
+impl<D, E> Default for MessageCodec<D, E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: TcpMessage, E> Decoder for MessageCodec<D, E> {
+    type Item = D;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<D>, CodecError> {
+        if src.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let mut header = [0u8; HEADER_LEN];
+        header.copy_from_slice(&src[..HEADER_LEN]);
+        let len = u32::from_be_bytes(header) as usize;
+        if len > self.max_frame_len {
+            return Err(CodecError::FrameTooLarge {
+                size: len,
+                max: self.max_frame_len,
+            });
+        }
+        if src.len() < HEADER_LEN + len {
+            // wait for the rest of the frame, reserving room for it up front
+            src.reserve(HEADER_LEN + len - src.len());
+            return Ok(None);
+        }
+        src.advance(HEADER_LEN);
+        let body = src.split_to(len);
+        D::from_bytes(&body).map(Some).ok_or(CodecError::Malformed)
+    }
+}
+
+impl<D, E: TcpMessage> Encoder<E> for MessageCodec<D, E> {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: E, dst: &mut BytesMut) -> Result<(), CodecError> {
+        self.encode_into(&item, dst)
+    }
+}
+
+impl<D, E> Encoder<EncodedFrame> for MessageCodec<D, E> {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: EncodedFrame, dst: &mut BytesMut) -> Result<(), CodecError> {
+        dst.extend_from_slice(item.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str) -> ClientMessage {
+        ClientMessage::Message {
+            room: "lobby".to_string(),
+            message: text.to_string(),
+            nonce: None,
+        }
+    }
+
+    fn frame(item: ClientMessage) -> BytesMut {
+        let mut dst = BytesMut::new();
+        ClientCodec::new().encode(item, &mut dst).unwrap();
+        dst
+    }
+
+    #[test]
+    fn waits_for_a_frame_split_across_reads() {
+        let whole = frame(message("hello"));
+        let mut codec = ServerCodec::new();
+        let mut src = BytesMut::new();
+        // first only part of the header, then part of the body
+        for chunk in [&whole[..2], &whole[2..HEADER_LEN + 3]] {
+            src.extend_from_slice(chunk);
+            assert!(codec.decode(&mut src).unwrap().is_none());
+        }
+        src.extend_from_slice(&whole[HEADER_LEN + 3..]);
+        let decoded = codec.decode(&mut src).unwrap();
+        assert!(
+            matches!(decoded, Some(ClientMessage::Message { message, .. }) if message == "hello")
+        );
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decodes_frames_coalesced_in_one_read() {
+        let mut src = frame(message("one"));
+        src.extend_from_slice(&frame(ClientMessage::UserName("alice".to_string())));
+        src.extend_from_slice(&frame(message("two")));
+        let mut codec = ServerCodec::new();
+        assert!(matches!(
+            codec.decode(&mut src).unwrap(),
+            Some(ClientMessage::Message { message, .. }) if message == "one"
+        ));
+        assert!(matches!(
+            codec.decode(&mut src).unwrap(),
+            Some(ClientMessage::UserName(name)) if name == "alice"
+        ));
+        assert!(matches!(
+            codec.decode(&mut src).unwrap(),
+            Some(ClientMessage::Message { message, .. }) if message == "two"
+        ));
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn refuses_a_length_prefix_over_the_limit() {
+        let mut codec = ServerCodec::with_max_frame_len(16);
+        let mut src = BytesMut::new();
+        src.put_u32(17);
+        // the body never has to arrive for the frame to be refused
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(CodecError::FrameTooLarge { size: 17, max: 16 })
+        ));
+    }
+}
@@ -1,6 +1,17 @@
-use std::{clone::Clone, fmt::Debug, marker::Send, marker::Sync};
+pub mod codec;
+pub mod validate;
+
 use serde::{Deserialize, Serialize};
 
+use crate::registration::NameRejection;
+use std::{
+    clone::Clone,
+    fmt::{self, Debug},
+    marker::Send,
+    marker::Sync,
+    time::Duration,
+};
+
 pub trait TcpMessage: Debug + Send + Sync + Clone + 'static {
     fn from_bytes(bytes: &[u8]) -> Option<Self>;
     fn to_bytes(&self) -> Option<Vec<u8>>;
@@ -15,11 +26,216 @@ impl TcpMessage for String {
     }
 }
 
+/// Version of the wire protocol spoken by this build. Bump it whenever a
+/// change would make frames unreadable by the other side.
+pub const PROTOCOL_VERSION: u32 = 7;
+
+/// Oldest protocol version the server still accepts.
+pub const MIN_PROTOCOL_VERSION: u32 = 7;
+
+/// Room every user is placed in once their username is accepted.
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// Most history entries returned in a single page.
+pub const MAX_HISTORY_PAGE: u32 = 200;
+
+/// Longest room name, in characters, the server lets anyone create.
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+
+/// Room names are short, non-empty and free of whitespace and control
+/// characters so they can be typed and displayed as a single word.
+pub fn is_valid_room_name(room: &str) -> bool {
+    !room.is_empty()
+        && room.chars().count() <= MAX_ROOM_NAME_LEN
+        && !room.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Optional protocol features, exchanged as a bit set during the handshake so
+/// that flags unknown to one side are simply ignored by it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Multiple named rooms with join/part semantics.
+    pub const ROOMS: Capabilities = Capabilities(1 << 0);
+    /// Private messages addressed to a single user.
+    pub const DIRECT_MESSAGES: Capabilities = Capabilities(1 << 1);
+    /// Join/leave notifications and the online user list.
+    pub const PRESENCE: Capabilities = Capabilities(1 << 2);
+    /// Replay of recent room history on join.
+    pub const HISTORY: Capabilities = Capabilities(1 << 3);
+    /// Usernames have to be claimed with `Authenticate` instead of
+    /// `UserName`. Only granted by servers that require it.
+    pub const AUTHENTICATION: Capabilities = Capabilities(1 << 4);
+    /// A client that reads too slowly is told with `MessagesDropped` how
+    /// many responses it lost.
+    pub const DROP_NOTICES: Capabilities = Capabilities(1 << 5);
+    /// `Ping` and `Pong` in both directions, so that either side notices
+    /// when the other is gone.
+    pub const HEARTBEAT: Capabilities = Capabilities(1 << 6);
+    /// A `SessionToken` after registration that lets a client that lost its
+    /// connection `Resume` where it left off. Only granted by servers that
+    /// keep sessions around.
+    pub const RESUME: Capabilities = Capabilities(1 << 7);
+    /// Operators may `Kick`, `Ban` and `Mute` users, who are told when it
+    /// happens to them.
+    pub const MODERATION: Capabilities = Capabilities(1 << 8);
+    /// Chat messages over the server's rate limit are answered with
+    /// `RateLimited` rather than dropped without a word.
+    pub const RATE_LIMITS: Capabilities = Capabilities(1 << 9);
+    /// Chat messages the server refuses to pass on are answered with
+    /// `MessageRejected`.
+    pub const MESSAGE_REJECTIONS: Capabilities = Capabilities(1 << 10);
+    /// Usernames that break the server's rules are answered with
+    /// `UsernameRejected` rather than `UsernameExists`.
+    pub const NAME_RULES: Capabilities = Capabilities(1 << 11);
+    /// Users may change their username, which everyone hears about with
+    /// `NickChanged` rather than the user leaving and joining again.
+    pub const NICK_CHANGES: Capabilities = Capabilities(1 << 12);
+    /// Chat messages sent with a nonce are answered with `Ack` once they
+    /// went out or `Nack` if they did not.
+    pub const ACKNOWLEDGEMENTS: Capabilities = Capabilities(1 << 13);
+    /// Users may edit and delete what they said, which the room hears about
+    /// with `MessageEdited` and `MessageDeleted`.
+    pub const EDITS: Capabilities = Capabilities(1 << 14);
+
+    pub const fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Capabilities(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Every feature implemented by this build.
+    pub const fn supported() -> Self {
+        Capabilities::ROOMS
+            .union(Capabilities::DIRECT_MESSAGES)
+            .union(Capabilities::PRESENCE)
+            .union(Capabilities::HISTORY)
+            .union(Capabilities::AUTHENTICATION)
+            .union(Capabilities::DROP_NOTICES)
+            .union(Capabilities::HEARTBEAT)
+            .union(Capabilities::RESUME)
+            .union(Capabilities::MODERATION)
+            .union(Capabilities::RATE_LIMITS)
+            .union(Capabilities::MESSAGE_REJECTIONS)
+            .union(Capabilities::NAME_RULES)
+            .union(Capabilities::NICK_CHANGES)
+            .union(Capabilities::ACKNOWLEDGEMENTS)
+            .union(Capabilities::EDITS)
+    }
+
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: Capabilities) -> Self {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Capabilities) -> Self {
+        Capabilities(self.0 & other.0)
+    }
+
+    pub const fn difference(self, other: Capabilities) -> Self {
+        Capabilities(self.0 & !other.0)
+    }
+}
 
-#[derive(Debug,Clone,Serialize,Deserialize)]
+// `Hello` has to stay the first variant so that a server of any version can
+// decode it and refuse the connection cleanly.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClientMessage {
+    Hello {
+        protocol_version: u32,
+        capabilities: Capabilities,
+    },
     UserName(String),
-    Message(String),
+    Message {
+        room: String,
+        message: String,
+        // picked by the client to match the `Ack` or `Nack` for the message
+        nonce: Option<u64>,
+    },
+    JoinRoom(String),
+    LeaveRoom(String),
+    ListRooms,
+    DirectMessage {
+        to: String,
+        message: String,
+        nonce: Option<u64>,
+    },
+    ListUsers,
+    FetchHistory {
+        room: String,
+        // only entries older than this, or the newest ones if `None`
+        before_seq: Option<u64>,
+        // only entries newer than this, the oldest of them, answered with a
+        // `HistoryReplay`; `before_seq` is not looked at then
+        after_seq: Option<u64>,
+        limit: u32,
+    },
+    SearchHistory {
+        room: String,
+        query: String,
+        limit: u32,
+    },
+    Authenticate {
+        username: String,
+        credential: String,
+    },
+    // answered with a `Pong` carrying the same value
+    Ping(u64),
+    Pong(u64),
+    // instead of `UserName`, to take back a session after reconnecting
+    Resume {
+        token: String,
+        // the newest message the client has seen, anything newer in its
+        // rooms is replayed
+        last_seq: Option<u64>,
+    },
+    // the user is done, the server closes the connection once everyone
+    // has been told
+    Leave {
+        reason: Option<String>,
+    },
+    // operators only, closes the user's connection
+    Kick {
+        username: String,
+        reason: Option<String>,
+    },
+    // operators only, kicks the user and keeps its name out, along with the
+    // address it is connected from if `ip` is set
+    Ban {
+        username: String,
+        reason: Option<String>,
+        ip: bool,
+    },
+    // operators only, whatever the user says is rejected for `duration`,
+    // zero lifts the mute
+    Mute {
+        username: String,
+        duration: Duration,
+    },
+    // swap the username for another, answered with `NickChanged` or with
+    // why not
+    ChangeNick {
+        new_name: String,
+    },
+    // the author of a room message or an operator only, `id` is the one in
+    // the message's envelope
+    EditMessage {
+        id: u64,
+        new_text: String,
+    },
+    DeleteMessage {
+        id: u64,
+    },
 }
 
 impl TcpMessage for ClientMessage {
@@ -31,12 +247,247 @@ impl TcpMessage for ClientMessage {
     }
 }
 
-#[derive(Debug,Clone,Serialize,Deserialize)]
+// Likewise the handshake replies keep the first two positions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerResponse {
-    Broadcast{username: String, message: String},
-    ConnectionRefused,
+    HelloAck {
+        protocol_version: u32,
+        capabilities: Capabilities,
+    },
+    ConnectionRefused {
+        reason: String,
+    },
+    Broadcast {
+        room: String,
+        envelope: Envelope,
+        username: String,
+        message: String,
+    },
     UsernameExists,
     UsernameAccepted,
+    RoomJoined {
+        room: String,
+    },
+    RoomLeft {
+        room: String,
+    },
+    RoomList(Vec<RoomInfo>),
+    NotInRoom {
+        room: String,
+    },
+    InvalidRoomName {
+        room: String,
+    },
+    Direct {
+        envelope: Envelope,
+        from: String,
+        message: String,
+    },
+    UserNotFound {
+        username: String,
+    },
+    UserJoined {
+        username: String,
+    },
+    UserLeft {
+        username: String,
+        // given by users who left on purpose, `None` if they were dropped
+        reason: Option<String>,
+    },
+    UserList(Vec<String>),
+    HistoryReplay {
+        room: String,
+        entries: Vec<HistoryEntry>,
+        // more entries newer than these were missed, `FetchHistory` with
+        // `after_seq` set to the last of these gets the next ones
+        has_more: bool,
+    },
+    HistoryPage {
+        room: String,
+        // the search this page answers, `None` for a plain fetch
+        query: Option<String>,
+        entries: Vec<HistoryEntry>,
+        has_more: bool,
+    },
+    // the server wants `Authenticate` rather than `UserName`
+    AuthenticationRequired,
+    // unknown username or wrong credential, deliberately not told apart
+    InvalidCredentials,
+    // the server is going away, the connection is closed once everything
+    // queued for it has been written
+    ServerShuttingDown {
+        reason: String,
+        // how long to wait before trying to reconnect, if it is coming back
+        reconnect_after: Option<Duration>,
+    },
+    // this many responses were dropped because the client did not read
+    // them fast enough
+    MessagesDropped {
+        count: u64,
+    },
+    // answered with a `Pong` carrying the same value
+    Ping(u64),
+    Pong(u64),
+    // follows `UsernameAccepted`, the token resumes the session if the
+    // connection is lost for less than `grace`
+    SessionToken {
+        token: String,
+        grace: Duration,
+    },
+    // the session was taken back, its rooms are joined again
+    SessionResumed {
+        username: String,
+    },
+    // the token is unknown or its grace period is over, register again
+    SessionExpired,
+    // an operator removed this user, the connection is closed after this
+    Kicked {
+        reason: Option<String>,
+    },
+    // messages from this user are rejected until then, in milliseconds
+    // since the unix epoch; also the answer to each one of them
+    Muted {
+        until: u64,
+    },
+    // only operators may moderate, and operators cannot be moderated; only
+    // the author of a message or an operator may edit or delete it
+    NotPermitted,
+    // an operator's kick, ban or mute took effect
+    ModerationApplied {
+        username: String,
+    },
+    // the chat message was over the rate limit and went nowhere, one sent
+    // after `retry_after` would not be
+    RateLimited {
+        retry_after: Duration,
+    },
+    // the chat message broke the server's rules and went nowhere
+    MessageRejected {
+        reason: validate::Rejection,
+    },
+    // the username asked for breaks the server's rules, another may do
+    UsernameRejected {
+        reason: NameRejection,
+    },
+    // a user, the one who asked included, goes by another name from now on
+    NickChanged {
+        old: String,
+        new: String,
+    },
+    // the chat message with this nonce went out as `seq`
+    Ack {
+        nonce: u64,
+        seq: u64,
+    },
+    // the chat message with this nonce went nowhere, in place of the
+    // response that would say why
+    Nack {
+        nonce: u64,
+        reason: NackReason,
+    },
+    // the message `envelope.id` in the room now reads differently
+    MessageEdited {
+        room: String,
+        envelope: Envelope,
+        message: String,
+    },
+    // the message `envelope.id` in the room is gone
+    MessageDeleted {
+        room: String,
+        envelope: Envelope,
+    },
+    // no message with this id is kept, it may never have been one sent to
+    // a room or it was deleted
+    MessageNotFound {
+        id: u64,
+    },
+}
+
+/// Why a chat message sent with a nonce went nowhere.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NackReason {
+    Rejected(validate::Rejection),
+    RateLimited { retry_after: Duration },
+    // in milliseconds since the unix epoch
+    Muted { until: u64 },
+    NotInRoom { room: String },
+    UserNotFound { username: String },
+}
+
+impl fmt::Display for NackReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NackReason::Rejected(reason) => write!(f, "{reason}"),
+            NackReason::RateLimited { retry_after } => write!(
+                f,
+                "you are sending too fast, wait {:.1}s",
+                retry_after.as_secs_f32()
+            ),
+            NackReason::Muted { .. } => write!(f, "you are muted"),
+            NackReason::NotInRoom { room } => write!(f, "you are not in #{room}"),
+            NackReason::UserNotFound { username } => {
+                write!(f, "no user named {username} is online")
+            }
+        }
+    }
+}
+
+/// Where a message sits in what the server sends, stamped by the server so
+/// that clients can order, deduplicate and date what they receive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Envelope {
+    // the message for good, the seq it was first sent with
+    pub id: u64,
+    // grows with every message the server stamps, whichever room or user it
+    // is for
+    pub seq: u64,
+    // milliseconds since the unix epoch (UTC), taken from the server clock
+    pub timestamp_ms: u64,
+}
+
+impl Envelope {
+    /// A message sent for the first time.
+    pub fn new(seq: u64, timestamp_ms: u64) -> Self {
+        Self {
+            id: seq,
+            seq,
+            timestamp_ms,
+        }
+    }
+}
+
+/// A message as it was recorded by the server's history store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub seq: u64,
+    // milliseconds since the unix epoch, taken from the server clock
+    pub timestamp_ms: u64,
+    pub room: String,
+    pub username: String,
+    // who wrote it for good, whatever username they go by now: the account
+    // on servers with logins, the session otherwise
+    #[serde(default)]
+    pub author: String,
+    pub message: String,
+    // when the message was last edited or deleted
+    #[serde(default)]
+    pub edited_ms: Option<u64>,
+    // the message is gone, what is left of the entry only says so
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+impl HistoryEntry {
+    /// The envelope the entry was broadcast in.
+    pub fn envelope(&self) -> Envelope {
+        Envelope::new(self.seq, self.timestamp_ms)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: usize,
 }
 
 impl TcpMessage for ServerResponse {
@@ -0,0 +1,98 @@
+/*
+ *  What a chat message may contain before it is passed on to other users
+ *
+ *  Messages end up in other people's terminals, so anything that could steer
+ *  a terminal (control characters, and with them escape sequences) or make
+ *  text read differently than it is stored (bidirectional overrides) is
+ *  refused rather than stripped. What is left is normalized to NFC so the
+ *  same text is always stored and searched the same way.
+ */
+
+use std::borrow::Cow;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+/// Default longest chat message, in bytes once normalized.
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 4096;
+
+/// Why a chat message was refused.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rejection {
+    // nothing but whitespace
+    Empty,
+    TooLong { max: usize },
+    // control characters, escape sequences or bidirectional overrides
+    ControlCharacters,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Empty => write!(f, "the message is empty"),
+            Rejection::TooLong { max } => write!(f, "the message is longer than {max} bytes"),
+            Rejection::ControlCharacters => {
+                write!(f, "the message contains control characters")
+            }
+        }
+    }
+}
+
+/// Limits on the chat messages the server passes on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageRules {
+    pub max_len: usize,
+}
+
+impl Default for MessageRules {
+    fn default() -> Self {
+        Self {
+            max_len: DEFAULT_MAX_MESSAGE_LEN,
+        }
+    }
+}
+
+impl MessageRules {
+    /// The message as it should be passed on, or why it may not be.
+    pub fn check(&self, message: String) -> Result<String, Rejection> {
+        if message.chars().any(is_unsafe) {
+            return Err(Rejection::ControlCharacters);
+        }
+        if message.trim().is_empty() {
+            return Err(Rejection::Empty);
+        }
+        let message = match is_nfc_quick(message.chars()) {
+            IsNormalized::Yes => message,
+            _ => message.nfc().collect(),
+        };
+        if message.len() > self.max_len {
+            return Err(Rejection::TooLong { max: self.max_len });
+        }
+        Ok(message)
+    }
+}
+
+/// Characters that change how a terminal shows what follows them.
+pub fn is_unsafe(c: char) -> bool {
+    c.is_control() || matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+/// `text` with every unsafe character replaced, for showing text that did
+/// not go through `MessageRules` (or came from a server that skips them).
+pub fn sanitize(text: &str) -> Cow<'_, str> {
+    if !text.chars().any(is_unsafe) {
+        return Cow::Borrowed(text);
+    }
+    Cow::Owned(
+        text.chars()
+            .map(|c| {
+                if is_unsafe(c) {
+                    char::REPLACEMENT_CHARACTER
+                } else {
+                    c
+                }
+            })
+            .collect(),
+    )
+}
@@ -0,0 +1,299 @@
+/*
+ *  Who a connection is: the rules usernames follow and the steps from
+ *  connecting to leaving
+ *
+ *  A connection starts out `Connected`, becomes `Named` once the server gives
+ *  it a username and ends up `Left` when it leaves or is removed, at which
+ *  point its name is free again. It never goes back, a name is only given
+ *  once per connection, though a `Named` connection may swap it for another.
+ */
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Shortest username, in characters.
+pub const MIN_USERNAME_LEN: usize = 2;
+
+/// Longest username, in characters.
+pub const MAX_USERNAME_LEN: usize = 24;
+
+/// Names nobody may take, compared regardless of case, so that nobody can
+/// pass for the server or for its staff.
+pub const RESERVED_USERNAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "anonymous",
+    "everyone",
+    "moderator",
+    "nobody",
+    "operator",
+    "root",
+    "server",
+    "system",
+];
+
+/// Why a username was refused.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NameRejection {
+    TooShort { min: usize },
+    TooLong { max: usize },
+    // anything but ascii letters, digits, `_`, `-` and `.`
+    InvalidCharacters,
+    Reserved,
+    Banned,
+    // usernames are proven when logging in, so they stay as they are
+    Locked,
+    // the connection has a username already
+    AlreadyRegistered,
+}
+
+impl fmt::Display for NameRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameRejection::TooShort { min } => {
+                write!(f, "usernames are at least {min} characters long")
+            }
+            NameRejection::TooLong { max } => {
+                write!(f, "usernames are at most {max} characters long")
+            }
+            NameRejection::InvalidCharacters => write!(
+                f,
+                "usernames only contain letters, digits, `_`, `-` and `.`"
+            ),
+            NameRejection::Reserved => write!(f, "that username is reserved"),
+            NameRejection::Banned => write!(f, "that username is banned"),
+            NameRejection::Locked => write!(f, "usernames cannot be changed on this server"),
+            NameRejection::AlreadyRegistered => write!(f, "you have a username already"),
+        }
+    }
+}
+
+/// Whether anybody may use `name`, regardless of who has it now.
+pub fn check_username(name: &str) -> Result<(), NameRejection> {
+    let len = name.chars().count();
+    if len < MIN_USERNAME_LEN {
+        return Err(NameRejection::TooShort {
+            min: MIN_USERNAME_LEN,
+        });
+    }
+    if len > MAX_USERNAME_LEN {
+        return Err(NameRejection::TooLong {
+            max: MAX_USERNAME_LEN,
+        });
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err(NameRejection::InvalidCharacters);
+    }
+    let folded = fold(name);
+    if RESERVED_USERNAMES.contains(&folded.as_str()) {
+        return Err(NameRejection::Reserved);
+    }
+    Ok(())
+}
+
+/// The form of `name` that tells usernames apart, names that only differ in
+/// case belong to the same user.
+pub fn fold(name: &str) -> String {
+    name.to_lowercase()
+}
+
+/// How far a connection is with registering.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Registration {
+    // handshaking or asking for a username
+    #[default]
+    Connected,
+    Named {
+        name: String,
+    },
+    // gone or on its way out, without a name any more
+    Left,
+}
+
+/// A step the registration of a connection cannot take from where it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidTransition {
+    pub from: &'static str,
+    pub to: &'static str,
+}
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a {} connection cannot become {}", self.from, self.to)
+    }
+}
+
+impl Registration {
+    /// The username of a `Named` connection.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Registration::Named { name } => Some(name),
+            _ => None,
+        }
+    }
+
+    pub fn is_named(&self) -> bool {
+        matches!(self, Registration::Named { .. })
+    }
+
+    fn state(&self) -> &'static str {
+        match self {
+            Registration::Connected => "connected",
+            Registration::Named { .. } => "named",
+            Registration::Left => "left",
+        }
+    }
+
+    /// Connected to Named.
+    pub fn named(&mut self, name: String) -> Result<(), InvalidTransition> {
+        match self {
+            Registration::Connected => {
+                *self = Registration::Named { name };
+                Ok(())
+            }
+            _ => Err(InvalidTransition {
+                from: self.state(),
+                to: "named",
+            }),
+        }
+    }
+
+    /// Named to Named under another name, returning the one it had.
+    pub fn rename(&mut self, new_name: String) -> Result<String, InvalidTransition> {
+        match self {
+            Registration::Named { name } => Ok(std::mem::replace(name, new_name)),
+            _ => Err(InvalidTransition {
+                from: self.state(),
+                to: "named",
+            }),
+        }
+    }
+
+    /// Connected or Named to Left, returning the name the connection had.
+    pub fn leave(&mut self) -> Result<Option<String>, InvalidTransition> {
+        match std::mem::replace(self, Registration::Left) {
+            Registration::Connected => Ok(None),
+            Registration::Named { name } => Ok(Some(name)),
+            Registration::Left => Err(InvalidTransition {
+                from: "left",
+                to: "left",
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(name: &str) -> Registration {
+        Registration::Named {
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn connected_becomes_named() {
+        let mut registration = Registration::default();
+        assert_eq!(registration.name(), None);
+        assert_eq!(registration.named("alice".to_string()), Ok(()));
+        assert_eq!(registration, named("alice"));
+    }
+
+    #[test]
+    fn connected_cannot_rename() {
+        let mut registration = Registration::default();
+        assert_eq!(
+            registration.rename("alice".to_string()),
+            Err(InvalidTransition {
+                from: "connected",
+                to: "named",
+            })
+        );
+        assert_eq!(registration, Registration::Connected);
+    }
+
+    #[test]
+    fn connected_leaves_without_a_name() {
+        let mut registration = Registration::default();
+        assert_eq!(registration.leave(), Ok(None));
+        assert_eq!(registration, Registration::Left);
+    }
+
+    #[test]
+    fn named_is_not_named_again() {
+        let mut registration = named("alice");
+        assert_eq!(
+            registration.named("bob".to_string()),
+            Err(InvalidTransition {
+                from: "named",
+                to: "named",
+            })
+        );
+        assert_eq!(registration, named("alice"));
+    }
+
+    #[test]
+    fn named_renames_at_will() {
+        let mut registration = named("alice");
+        assert_eq!(
+            registration.rename("alicia".to_string()),
+            Ok("alice".to_string())
+        );
+        assert_eq!(
+            registration.rename("alice".to_string()),
+            Ok("alicia".to_string())
+        );
+        assert_eq!(registration, named("alice"));
+    }
+
+    #[test]
+    fn named_leaves_with_its_name() {
+        let mut registration = named("alice");
+        assert_eq!(registration.leave(), Ok(Some("alice".to_string())));
+        assert_eq!(registration, Registration::Left);
+    }
+
+    #[test]
+    fn left_cannot_be_named() {
+        let mut registration = Registration::Left;
+        assert_eq!(
+            registration.named("alice".to_string()),
+            Err(InvalidTransition {
+                from: "left",
+                to: "named",
+            })
+        );
+        assert_eq!(registration, Registration::Left);
+    }
+
+    #[test]
+    fn left_cannot_rename() {
+        let mut registration = Registration::Left;
+        assert_eq!(
+            registration.rename("alice".to_string()),
+            Err(InvalidTransition {
+                from: "left",
+                to: "named",
+            })
+        );
+        assert_eq!(registration, Registration::Left);
+    }
+
+    #[test]
+    fn left_cannot_leave_again() {
+        let mut registration = Registration::Left;
+        assert_eq!(
+            registration.leave(),
+            Err(InvalidTransition {
+                from: "left",
+                to: "left",
+            })
+        );
+        assert_eq!(registration, Registration::Left);
+    }
+}
@@ -0,0 +1,199 @@
+/*
+ *  Starting a chat server that is owned by whoever started it
+ */
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::task::{JoinError, JoinHandle};
+
+use crate::actor::outbound::BackpressurePolicy;
+use crate::actor::rate_limit::RateLimit;
+use crate::actor::server_actor::ServerActorHandler;
+use crate::actor::tcp_handler::Heartbeat;
+use crate::actor_impl::server_impl::ServerConfig;
+use crate::auth::Authenticator;
+use crate::history::HistoryStore;
+use crate::moderation::BanList;
+use crate::transport::tls::TlsAcceptor;
+
+/// Default capacity of the queue of messages waiting for the server actor.
+pub const DEFAULT_CHANNEL_SIZE: usize = 1024;
+
+/// Collects the settings of a server before it starts listening.
+pub struct ChatServerBuilder {
+    config: ServerConfig,
+    channel_size: usize,
+}
+
+impl Default for ChatServerBuilder {
+    fn default() -> Self {
+        Self {
+            config: ServerConfig::default(),
+            channel_size: DEFAULT_CHANNEL_SIZE,
+        }
+    }
+}
+
+impl ChatServerBuilder {
+    /// Replaces every setting at once.
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn history(mut self, history: Arc<dyn HistoryStore>) -> Self {
+        self.config.history = history;
+        self
+    }
+
+    pub fn replay_len(mut self, replay_len: usize) -> Self {
+        self.config.replay_len = replay_len;
+        self
+    }
+
+    pub fn authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.config.authenticator = Some(authenticator);
+        self
+    }
+
+    pub fn tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.config.tls = Some(acceptor);
+        self
+    }
+
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.config.queue_capacity = queue_capacity;
+        self
+    }
+
+    pub fn backpressure(mut self, policy: BackpressurePolicy) -> Self {
+        self.config.backpressure = policy;
+        self
+    }
+
+    pub fn heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.config.heartbeat = heartbeat;
+        self
+    }
+
+    /// Spreads the rooms across `shards` actors, at least one.
+    pub fn shards(mut self, shards: usize) -> Self {
+        self.config.shards = shards;
+        self
+    }
+
+    /// How long a dropped user keeps its name and rooms for a resume,
+    /// `None` to not offer resuming at all.
+    pub fn resume_grace(mut self, grace: Option<Duration>) -> Self {
+        self.config.resume_grace = grace;
+        self
+    }
+
+    /// Closes connections that have no username this long after connecting,
+    /// `None` to wait as long as it takes.
+    pub fn registration_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.registration_timeout = timeout;
+        self
+    }
+
+    /// Refuses chat messages longer than `max_len` bytes.
+    pub fn max_message_len(mut self, max_len: usize) -> Self {
+        self.config.message_rules.max_len = max_len;
+        self
+    }
+
+    /// Limits how much each connection may say, `None` for no limit.
+    pub fn rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.config.rate_limit = limit;
+        self
+    }
+
+    /// Lets these users kick, ban and mute everyone else.
+    pub fn operators(mut self, operators: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.operators = operators.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn bans(mut self, bans: BanList) -> Self {
+        self.config.bans = bans;
+        self
+    }
+
+    pub fn channel_size(mut self, channel_size: usize) -> Self {
+        self.channel_size = channel_size;
+        self
+    }
+
+    /// Binds to `addr` and starts serving, use port 0 to let the system
+    /// pick a free one.
+    pub async fn bind(self, addr: impl ToSocketAddrs) -> io::Result<ChatServer> {
+        let listener = TcpListener::bind(addr).await?;
+        self.serve(listener)
+    }
+
+    /// Starts serving connections accepted by an already bound listener.
+    pub fn serve(self, listener: TcpListener) -> io::Result<ChatServer> {
+        let local_addr = listener.local_addr()?;
+        let (handle, join_handle) =
+            ServerActorHandler::new(self.channel_size, listener, self.config);
+        Ok(ChatServer {
+            handle,
+            local_addr,
+            join_handle,
+        })
+    }
+}
+
+/// A running server. Every server has its own state, so any number of them
+/// can run side by side in one process.
+pub struct ChatServer {
+    handle: ServerActorHandler,
+    local_addr: SocketAddr,
+    join_handle: JoinHandle<()>,
+}
+
+impl ChatServer {
+    pub fn builder() -> ChatServerBuilder {
+        ChatServerBuilder::default()
+    }
+
+    /// The address the server accepts connections on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// A handle to the server actor, for feeding it messages directly.
+    pub fn handle(&self) -> &ServerActorHandler {
+        &self.handle
+    }
+
+    /// Waits until the server actor exits.
+    pub async fn wait(self) -> Result<(), JoinError> {
+        self.join_handle.await
+    }
+
+    /// Tells every client the server is going away, gives their
+    /// connections up to `drain_timeout` to flush and waits for the server
+    /// actor to exit.
+    pub async fn shutdown(
+        self,
+        reason: impl Into<String>,
+        reconnect_after: Option<Duration>,
+        drain_timeout: Duration,
+    ) -> Result<(), JoinError> {
+        self.handle
+            .shutdown(reason, reconnect_after, drain_timeout)
+            .await;
+        self.join_handle.await
+    }
+
+    /// Stops the server actor right away and waits for it to exit.
+    pub async fn stop(self) -> Result<(), JoinError> {
+        self.handle.terminate(()).await;
+        self.join_handle.await
+    }
+}
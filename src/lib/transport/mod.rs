@@ -0,0 +1,15 @@
+/*
+ *  The byte streams connections run over, plain tcp or tcp wrapped in tls
+ */
+
+pub mod tls;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Any bidirectional byte stream a connection can be framed over.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Stream for T {}
+
+/// A stream whose transport is only known at runtime.
+pub type BoxedStream = Box<dyn Stream>;
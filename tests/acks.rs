@@ -0,0 +1,147 @@
+/*
+ *  Telling the sender what became of each chat message it gave a nonce
+ */
+
+mod common;
+
+use common::{start_server, start_with, TestClient};
+use simple_lib::{
+    actor::rate_limit::RateLimit,
+    msg::{validate::Rejection, ClientMessage, NackReason, ServerResponse, DEFAULT_ROOM},
+    server::ChatServer,
+};
+
+async fn say(client: &mut TestClient, room: &str, message: &str, nonce: Option<u64>) {
+    client
+        .send(ClientMessage::Message {
+            room: room.to_string(),
+            message: message.to_string(),
+            nonce,
+        })
+        .await;
+}
+
+/// The first answer about a message sent with a nonce, or a refusal sent in
+/// place of one.
+async fn answer(client: &mut TestClient) -> ServerResponse {
+    client
+        .recv_until(|r| {
+            matches!(
+                r,
+                ServerResponse::Ack { .. }
+                    | ServerResponse::Nack { .. }
+                    | ServerResponse::NotInRoom { .. }
+                    | ServerResponse::UserNotFound { .. }
+                    | ServerResponse::MessageRejected { .. }
+                    | ServerResponse::RateLimited { .. }
+            )
+        })
+        .await
+}
+
+#[tokio::test]
+async fn delivered_messages_are_acknowledged_with_their_seq() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    say(&mut alice, DEFAULT_ROOM, "hi", Some(7)).await;
+    let ServerResponse::Ack { nonce, seq } = answer(&mut alice).await else {
+        panic!("the message was not acknowledged");
+    };
+    assert_eq!(nonce, 7);
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::Broadcast { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::Broadcast { envelope, .. } if envelope.seq == seq));
+
+    alice
+        .send(ClientMessage::DirectMessage {
+            to: "bob".to_string(),
+            message: "psst".to_string(),
+            nonce: Some(8),
+        })
+        .await;
+    let ServerResponse::Ack { nonce, seq } = answer(&mut alice).await else {
+        panic!("the direct message was not acknowledged");
+    };
+    assert_eq!(nonce, 8);
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::Direct { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::Direct { envelope, .. } if envelope.seq == seq));
+}
+
+#[tokio::test]
+async fn undelivered_messages_are_refused_with_the_reason() {
+    let limit = RateLimit {
+        messages_per_sec: 1.0,
+        // enough for every message up to "fast", refused or not
+        message_burst: 3,
+        strikes: 100,
+        ..RateLimit::default()
+    };
+    let server = start_with(ChatServer::builder().rate_limit(Some(limit))).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+
+    say(&mut alice, DEFAULT_ROOM, " ", Some(1)).await;
+    assert!(matches!(
+        answer(&mut alice).await,
+        ServerResponse::Nack {
+            nonce: 1,
+            reason: NackReason::Rejected(Rejection::Empty)
+        }
+    ));
+
+    say(&mut alice, "nowhere", "hello?", Some(2)).await;
+    assert!(matches!(
+        answer(&mut alice).await,
+        ServerResponse::Nack {
+            nonce: 2,
+            reason: NackReason::NotInRoom { room }
+        } if room == "nowhere"
+    ));
+
+    alice
+        .send(ClientMessage::DirectMessage {
+            to: "nobody-here".to_string(),
+            message: "hello?".to_string(),
+            nonce: Some(3),
+        })
+        .await;
+    assert!(matches!(
+        answer(&mut alice).await,
+        ServerResponse::Nack {
+            nonce: 3,
+            reason: NackReason::UserNotFound { .. }
+        }
+    ));
+
+    say(&mut alice, DEFAULT_ROOM, "too", Some(4)).await;
+    assert!(matches!(
+        answer(&mut alice).await,
+        ServerResponse::Ack { nonce: 4, .. }
+    ));
+    say(&mut alice, DEFAULT_ROOM, "fast", Some(5)).await;
+    assert!(matches!(
+        answer(&mut alice).await,
+        ServerResponse::Nack {
+            nonce: 5,
+            reason: NackReason::RateLimited { .. }
+        }
+    ));
+}
+
+#[tokio::test]
+async fn messages_without_a_nonce_are_not_acknowledged() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+
+    say(&mut alice, DEFAULT_ROOM, "hi", None).await;
+    // the room list comes from the shards, so the message is in by then
+    alice.send(ClientMessage::ListRooms).await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::Ack { .. } | ServerResponse::RoomList(_)))
+        .await;
+    assert!(matches!(response, ServerResponse::RoomList(_)));
+}
@@ -0,0 +1,90 @@
+/*
+ *  Logging in against a file of argon2 password hashes
+ */
+
+mod common;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use common::{start_with, TestClient};
+use simple_lib::{
+    auth::{password::hash_password, AuthError, Authenticator, PasswordFileAuthenticator},
+    msg::{ClientMessage, ServerResponse},
+    server::ChatServer,
+};
+
+/// Writes a password file holding `contents` and returns where it is.
+fn password_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "simple-chat-passwords-{name}-{}",
+        std::process::id()
+    ));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+fn load(name: &str) -> PasswordFileAuthenticator {
+    let hash = hash_password("hunter2").unwrap();
+    let path = password_file(name, &format!("# accounts\nalice:{hash}\n"));
+    let authenticator = PasswordFileAuthenticator::load(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    authenticator
+}
+
+async fn log_in(addr: SocketAddr, username: &str, credential: &str) -> ServerResponse {
+    let mut client = TestClient::connect(addr).await;
+    client.hello().await;
+    client
+        .send(ClientMessage::Authenticate {
+            username: username.to_string(),
+            credential: credential.to_string(),
+        })
+        .await;
+    client
+        .recv_until(|r| {
+            matches!(
+                r,
+                ServerResponse::UsernameAccepted | ServerResponse::InvalidCredentials
+            )
+        })
+        .await
+}
+
+#[test]
+fn passwords_are_checked_against_their_hash() {
+    let authenticator = load("check");
+    assert_eq!(authenticator.authenticate("alice", "hunter2"), Ok(()));
+    assert_eq!(
+        authenticator.authenticate("alice", "hunter3"),
+        Err(AuthError::InvalidCredential)
+    );
+    assert_eq!(
+        authenticator.authenticate("bob", "hunter2"),
+        Err(AuthError::UnknownUser)
+    );
+}
+
+#[test]
+fn broken_hashes_are_refused_on_load() {
+    let path = password_file("broken", "alice:not-a-hash\n");
+    assert!(PasswordFileAuthenticator::load(&path).is_err());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn only_the_right_password_logs_in() {
+    let server = start_with(ChatServer::builder().authenticator(Arc::new(load("server")))).await;
+
+    let response = log_in(server.local_addr(), "alice", "hunter2").await;
+    assert!(matches!(response, ServerResponse::UsernameAccepted));
+    // a wrong password and an unknown user look the same from outside
+    for (username, credential) in [("alice", "hunter3"), ("bob", "hunter2")] {
+        let response = log_in(server.local_addr(), username, credential).await;
+        assert!(
+            matches!(response, ServerResponse::InvalidCredentials),
+            "{username} got {response:?}"
+        );
+    }
+}
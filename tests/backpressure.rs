@@ -0,0 +1,190 @@
+/*
+ *  Clients that stop reading must not hold up anyone else
+ */
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{start_with, TestClient};
+use simple_lib::{
+    actor::{
+        outbound::{BackpressurePolicy, OutboundQueue},
+        tcp_handler::ControllerMessages,
+    },
+    history::MemoryHistoryStore,
+    msg::{codec::HEADER_LEN, ClientMessage, Envelope, ServerResponse, TcpMessage, DEFAULT_ROOM},
+    server::ChatServer,
+};
+use tokio::net::TcpSocket;
+
+fn dm(n: u64) -> ServerResponse {
+    ServerResponse::Direct {
+        envelope: Envelope::new(n, 0),
+        from: "alice".to_string(),
+        message: n.to_string(),
+    }
+}
+
+/// Pops everything queued, as the connection would write it out.
+async fn drain(queue: &OutboundQueue) -> Vec<ServerResponse> {
+    let mut out = vec![];
+    while !queue.is_empty() {
+        match queue.pop().await {
+            ControllerMessages::WriteStream(response) => out.push(response),
+            ControllerMessages::WriteFrame(frame) => {
+                out.push(ServerResponse::from_bytes(&frame.as_bytes()[HEADER_LEN..]).unwrap())
+            }
+            ControllerMessages::Close | ControllerMessages::Disconnect => break,
+            // nothing the client would see
+            _ => {}
+        }
+    }
+    out
+}
+
+fn numbers(responses: &[ServerResponse]) -> Vec<String> {
+    responses
+        .iter()
+        .map(|response| match response {
+            ServerResponse::Direct { message, .. } => message.clone(),
+            ServerResponse::MessagesDropped { count } => format!("dropped {count}"),
+            other => format!("{other:?}"),
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn drop_oldest_keeps_the_latest() {
+    let queue = OutboundQueue::new(3, BackpressurePolicy::DropOldest);
+    queue.report_drops(true);
+    for n in 0..5 {
+        queue.push(dm(n));
+    }
+    assert_eq!(numbers(&drain(&queue).await), ["dropped 2", "2", "3", "4"]);
+}
+
+#[tokio::test]
+async fn drops_are_not_reported_unless_asked_for() {
+    let queue = OutboundQueue::new(3, BackpressurePolicy::DropOldest);
+    for n in 0..5 {
+        queue.push(dm(n));
+    }
+    assert_eq!(numbers(&drain(&queue).await), ["2", "3", "4"]);
+}
+
+#[tokio::test]
+async fn drop_oldest_stays_within_capacity() {
+    let queue = OutboundQueue::new(2, BackpressurePolicy::DropOldest);
+    queue.report_drops(true);
+    queue.push(dm(0));
+    queue.push(dm(1));
+    // messages for the connection itself push responses out
+    queue.push_control(ControllerMessages::Null);
+    assert_eq!(queue.len(), 2);
+    queue.push_control(ControllerMessages::Null);
+    assert_eq!(queue.len(), 2);
+    // with nothing left to drop the client is cut off
+    queue.push(dm(2));
+    assert!(matches!(queue.pop().await, ControllerMessages::Disconnect));
+    assert!(queue.is_empty());
+}
+
+#[tokio::test]
+async fn drop_newest_keeps_the_earliest() {
+    let queue = OutboundQueue::new(3, BackpressurePolicy::DropNewest);
+    queue.report_drops(true);
+    for n in 0..5 {
+        queue.push(dm(n));
+    }
+    assert_eq!(numbers(&drain(&queue).await), ["dropped 2", "0", "1", "2"]);
+}
+
+#[tokio::test]
+async fn disconnect_closes_after_threshold() {
+    let queue = OutboundQueue::new(2, BackpressurePolicy::Disconnect { threshold: 2 });
+    for n in 0..4 {
+        queue.push(dm(n));
+    }
+    // still within the threshold
+    assert_eq!(queue.len(), 2);
+    queue.push(dm(4));
+    assert!(matches!(queue.pop().await, ControllerMessages::Disconnect));
+    queue.cut_off().await;
+    // nothing is queued after the close
+    queue.push(dm(5));
+    assert!(queue.is_empty());
+}
+
+#[tokio::test]
+async fn stalled_client_is_dropped_without_stalling_others() {
+    let server = start_with(
+        ChatServer::builder()
+            .history(Arc::new(MemoryHistoryStore::new(1)))
+            .queue_capacity(16)
+            .backpressure(BackpressurePolicy::Disconnect { threshold: 16 })
+            // others hear bob is gone right away rather than after a grace period
+            .resume_grace(None)
+            // alice floods bob on purpose, with messages big enough to fill
+            // his socket quickly
+            .rate_limit(None)
+            .max_message_len(128 * 1024),
+    )
+    .await;
+    let addr = server.local_addr();
+
+    let mut alice = TestClient::register(addr, "alice").await;
+    // bob never reads again, so his socket buffers and then his queue fill
+    // up, a small receive buffer keeps the kernel from absorbing it all
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.set_recv_buffer_size(4096).unwrap();
+    let stream = socket.connect(addr).await.unwrap();
+    let _bob = TestClient::over(stream).register_as("bob").await;
+    let mut carol = TestClient::register(addr, "carol").await;
+
+    // carol keeps up, alice never gets more than a few messages ahead of her
+    let (seen_tx, mut seen) = tokio::sync::watch::channel(0);
+    let reader = tokio::spawn(async move {
+        let mut broadcasts = 0;
+        let mut bob_left = false;
+        loop {
+            match carol.recv().await {
+                ServerResponse::Broadcast { message, .. } if message == "last" => break,
+                ServerResponse::Broadcast { .. } => {
+                    broadcasts += 1;
+                    let _ = seen_tx.send(broadcasts);
+                }
+                ServerResponse::UserLeft { username, .. } if username == "bob" => bob_left = true,
+                _ => {}
+            }
+        }
+        (broadcasts, bob_left)
+    });
+
+    let big = "x".repeat(100 * 1024);
+    for n in 0..300 {
+        alice
+            .send(ClientMessage::Message {
+                room: DEFAULT_ROOM.to_string(),
+                message: big.clone(),
+                nonce: None,
+            })
+            .await;
+        seen.wait_for(|seen| n - seen < 8).await.unwrap();
+    }
+    alice
+        .send(ClientMessage::Message {
+            room: DEFAULT_ROOM.to_string(),
+            message: "last".to_string(),
+            nonce: None,
+        })
+        .await;
+
+    let (broadcasts, bob_left) = tokio::time::timeout(Duration::from_secs(30), reader)
+        .await
+        .expect("carol stopped receiving")
+        .unwrap();
+    assert_eq!(broadcasts, 300);
+    assert!(bob_left);
+}
@@ -0,0 +1,79 @@
+/*
+ *  Serializing a broadcast once and handing the same bytes to every member
+ */
+
+mod common;
+
+use bytes::BytesMut;
+use common::{start_server, TestClient};
+use simple_lib::msg::{codec::ServerCodec, ClientMessage, Envelope, ServerResponse, DEFAULT_ROOM};
+use tokio_util::codec::Encoder;
+
+fn broadcast() -> ServerResponse {
+    ServerResponse::Broadcast {
+        room: DEFAULT_ROOM.to_string(),
+        envelope: Envelope::new(7, 1_700_000_000_000),
+        username: "alice".to_string(),
+        message: "hello everyone".to_string(),
+    }
+}
+
+#[test]
+fn a_shared_frame_is_the_response_as_it_would_be_written() {
+    let mut codec = ServerCodec::new();
+    let frame = codec.encode_frame(&broadcast()).unwrap();
+
+    let mut direct = BytesMut::new();
+    codec.encode(broadcast(), &mut direct).unwrap();
+    let mut shared = BytesMut::new();
+    codec.encode(frame.clone(), &mut shared).unwrap();
+    assert_eq!(direct, shared);
+    assert_eq!(frame.as_bytes(), &direct[..]);
+}
+
+#[test]
+fn copies_of_a_frame_share_one_buffer() {
+    let frame = ServerCodec::new().encode_frame(&broadcast()).unwrap();
+    let copies: Vec<_> = (0..10).map(|_| frame.clone()).collect();
+    assert!(copies
+        .iter()
+        .all(|copy| copy.as_bytes().as_ptr() == frame.as_bytes().as_ptr()));
+}
+
+#[tokio::test]
+async fn every_member_gets_the_same_broadcast() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut listeners = vec![];
+    for i in 0..5 {
+        listeners.push(TestClient::register(server.local_addr(), &format!("listener{i}")).await);
+    }
+
+    alice
+        .send(ClientMessage::Message {
+            room: DEFAULT_ROOM.to_string(),
+            message: "hello everyone".to_string(),
+            nonce: None,
+        })
+        .await;
+    let mut seqs = vec![];
+    for listener in &mut listeners {
+        let ServerResponse::Broadcast {
+            envelope,
+            username,
+            message,
+            ..
+        } = listener
+            .recv_until(|r| matches!(r, ServerResponse::Broadcast { .. }))
+            .await
+        else {
+            unreachable!()
+        };
+        assert_eq!(
+            (username.as_str(), message.as_str()),
+            ("alice", "hello everyone")
+        );
+        seqs.push(envelope.seq);
+    }
+    assert!(seqs.windows(2).all(|w| w[0] == w[1]));
+}
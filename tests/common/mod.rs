@@ -0,0 +1,113 @@
+/*
+ *  Helpers shared by the integration tests: isolated servers and a client
+ *  that speaks the wire protocol directly
+ */
+
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use simple_lib::{
+    msg::{codec::ClientCodec, Capabilities, ClientMessage, ServerResponse, PROTOCOL_VERSION},
+    server::{ChatServer, ChatServerBuilder},
+    transport::{BoxedStream, Stream},
+};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+/// How long a client waits for a response before giving up on it.
+pub const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts a server with default settings on a free local port.
+pub async fn start_server() -> ChatServer {
+    start_with(ChatServer::builder()).await
+}
+
+pub async fn start_with(builder: ChatServerBuilder) -> ChatServer {
+    builder.bind("127.0.0.1:0").await.unwrap()
+}
+
+pub struct TestClient {
+    framed: Framed<BoxedStream, ClientCodec>,
+}
+
+impl TestClient {
+    pub async fn connect(addr: SocketAddr) -> Self {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        Self::over(stream)
+    }
+
+    pub fn over(stream: impl Stream) -> Self {
+        let stream: BoxedStream = Box::new(stream);
+        Self {
+            framed: Framed::new(stream, ClientCodec::new()),
+        }
+    }
+
+    /// Connects, completes the handshake and registers `name`, returning
+    /// once the client is in the default room.
+    pub async fn register(addr: SocketAddr, name: &str) -> Self {
+        Self::connect(addr).await.register_as(name).await
+    }
+
+    /// Completes the handshake and registers `name` on an already open
+    /// connection.
+    pub async fn register_as(self, name: &str) -> Self {
+        let mut client = self;
+        client.hello().await;
+        client.send(ClientMessage::UserName(name.to_string())).await;
+        client
+            .recv_until(|r| matches!(r, ServerResponse::RoomJoined { .. }))
+            .await;
+        client
+    }
+
+    pub async fn send(&mut self, msg: ClientMessage) {
+        self.framed.send(msg).await.unwrap();
+    }
+
+    /// The next response, or `None` if the server closed the connection or
+    /// stayed quiet for too long.
+    pub async fn try_recv(&mut self) -> Option<ServerResponse> {
+        match tokio::time::timeout(RECV_TIMEOUT, self.framed.next()).await {
+            Ok(Some(Ok(response))) => Some(response),
+            _ => None,
+        }
+    }
+
+    pub async fn recv(&mut self) -> ServerResponse {
+        self.try_recv().await.expect("no response from server")
+    }
+
+    /// Skips responses until one matches, returning it.
+    pub async fn recv_until(&mut self, pred: impl Fn(&ServerResponse) -> bool) -> ServerResponse {
+        loop {
+            let response = self.recv().await;
+            if pred(&response) {
+                return response;
+            }
+        }
+    }
+
+    /// Sends the handshake and returns the server's answer.
+    pub async fn try_hello(&mut self) -> Option<ServerResponse> {
+        self.framed
+            .send(ClientMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: Capabilities::supported(),
+            })
+            .await
+            .ok()?;
+        self.try_recv().await
+    }
+
+    pub async fn hello(&mut self) {
+        let response = self.try_hello().await;
+        assert!(
+            matches!(response, Some(ServerResponse::HelloAck { .. })),
+            "handshake failed: {response:?}"
+        );
+    }
+}
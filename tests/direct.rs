@@ -0,0 +1,90 @@
+/*
+ *  Direct messages between users, and what becomes of ones nobody can take
+ */
+
+mod common;
+
+use common::{start_server, TestClient};
+use simple_lib::msg::{ClientMessage, ServerResponse, DEFAULT_ROOM};
+
+async fn whisper(client: &mut TestClient, to: &str, message: &str) {
+    client
+        .send(ClientMessage::DirectMessage {
+            to: to.to_string(),
+            message: message.to_string(),
+            nonce: None,
+        })
+        .await;
+}
+
+async fn lobby_members(client: &mut TestClient) -> usize {
+    client.send(ClientMessage::ListRooms).await;
+    let ServerResponse::RoomList(rooms) = client
+        .recv_until(|r| matches!(r, ServerResponse::RoomList(_)))
+        .await
+    else {
+        unreachable!()
+    };
+    rooms
+        .iter()
+        .find(|r| r.name == DEFAULT_ROOM)
+        .map_or(0, |r| r.members)
+}
+
+#[tokio::test]
+async fn direct_messages_reach_only_their_recipient() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+    let mut carol = TestClient::register(server.local_addr(), "carol").await;
+
+    // usernames are matched regardless of case
+    whisper(&mut alice, "BOB", "psst").await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::Direct { .. }))
+        .await;
+    assert!(matches!(
+        response,
+        ServerResponse::Direct { from, message, .. } if from == "alice" && message == "psst"
+    ));
+
+    carol.send(ClientMessage::ListRooms).await;
+    let response = carol
+        .recv_until(|r| {
+            matches!(
+                r,
+                ServerResponse::RoomList(_) | ServerResponse::Direct { .. }
+            )
+        })
+        .await;
+    assert!(matches!(response, ServerResponse::RoomList(_)));
+}
+
+#[tokio::test]
+async fn direct_messages_to_unknown_users_are_answered() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+
+    whisper(&mut alice, "nobody", "hello?").await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::UserNotFound { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::UserNotFound { username } if username == "nobody"));
+}
+
+#[tokio::test]
+async fn direct_messages_to_users_who_went_offline_are_answered() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let bob = TestClient::register(server.local_addr(), "bob").await;
+
+    drop(bob);
+    while lobby_members(&mut alice).await > 1 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    whisper(&mut alice, "bob", "still there?").await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::UserNotFound { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::UserNotFound { username } if username == "bob"));
+}
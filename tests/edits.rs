@@ -0,0 +1,288 @@
+/*
+ *  Editing and deleting messages after they were sent
+ */
+
+mod common;
+
+use std::sync::Arc;
+
+use common::{start_server, start_with, TestClient};
+use simple_lib::{
+    history::{FileHistoryStore, HistoryStore, MemoryHistoryStore},
+    msg::{ClientMessage, Envelope, HistoryEntry, ServerResponse, DEFAULT_ROOM},
+    server::ChatServer,
+};
+
+/// Says `message` in the default room and returns the envelope `listener`
+/// got it in.
+async fn say(client: &mut TestClient, listener: &mut TestClient, message: &str) -> Envelope {
+    client
+        .send(ClientMessage::Message {
+            room: DEFAULT_ROOM.to_string(),
+            message: message.to_string(),
+            nonce: None,
+        })
+        .await;
+    let ServerResponse::Broadcast { envelope, .. } = listener
+        .recv_until(|r| matches!(r, ServerResponse::Broadcast { .. }))
+        .await
+    else {
+        unreachable!()
+    };
+    envelope
+}
+
+async fn edit(client: &mut TestClient, id: u64, new_text: &str) {
+    client
+        .send(ClientMessage::EditMessage {
+            id,
+            new_text: new_text.to_string(),
+        })
+        .await;
+}
+
+async fn history(client: &mut TestClient) -> Vec<HistoryEntry> {
+    client
+        .send(ClientMessage::FetchHistory {
+            room: DEFAULT_ROOM.to_string(),
+            before_seq: None,
+            after_seq: None,
+            limit: 10,
+        })
+        .await;
+    let ServerResponse::HistoryPage { entries, .. } = client
+        .recv_until(|r| matches!(r, ServerResponse::HistoryPage { .. }))
+        .await
+    else {
+        unreachable!()
+    };
+    entries
+}
+
+#[tokio::test]
+async fn authors_edit_and_delete_their_messages() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    let sent = say(&mut alice, &mut bob, "helo").await;
+    edit(&mut alice, sent.id, "hello").await;
+    for client in [&mut alice, &mut bob] {
+        let response = client
+            .recv_until(|r| matches!(r, ServerResponse::MessageEdited { .. }))
+            .await;
+        assert!(matches!(
+            response,
+            ServerResponse::MessageEdited { envelope, message, .. }
+                if envelope.id == sent.id && envelope.seq > sent.seq && message == "hello"
+        ));
+    }
+    let entries = history(&mut bob).await;
+    let entry = entries.iter().find(|e| e.seq == sent.id).unwrap();
+    assert_eq!(entry.message, "hello");
+    assert!(entry.edited_ms.is_some());
+
+    alice
+        .send(ClientMessage::DeleteMessage { id: sent.id })
+        .await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::MessageDeleted { .. }))
+        .await;
+    assert!(
+        matches!(response, ServerResponse::MessageDeleted { envelope, .. } if envelope.id == sent.id)
+    );
+    assert!(history(&mut bob).await.iter().all(|e| e.seq != sent.id));
+
+    // nothing is left to change
+    edit(&mut alice, sent.id, "hello?").await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::MessageNotFound { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::MessageNotFound { id } if id == sent.id));
+}
+
+#[tokio::test]
+async fn only_authors_and_operators_change_messages() {
+    let server = start_with(ChatServer::builder().operators(["carol"])).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+    let mut carol = TestClient::register(server.local_addr(), "carol").await;
+
+    let sent = say(&mut alice, &mut carol, "mine").await;
+    edit(&mut bob, sent.id, "bob's now").await;
+    assert!(matches!(
+        bob.recv_until(|r| matches!(r, ServerResponse::NotPermitted))
+            .await,
+        ServerResponse::NotPermitted
+    ));
+
+    carol
+        .send(ClientMessage::DeleteMessage { id: sent.id })
+        .await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::MessageDeleted { .. }))
+        .await;
+    assert!(
+        matches!(response, ServerResponse::MessageDeleted { envelope, .. } if envelope.id == sent.id)
+    );
+}
+
+#[tokio::test]
+async fn an_edit_racing_a_delete_does_not_bring_the_message_back() {
+    let path = std::env::temp_dir().join(format!("simple-chat-race-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let store = Arc::new(FileHistoryStore::open(&path, 10).unwrap());
+    let server = start_with(
+        ChatServer::builder()
+            .history(store)
+            .operators(["carol"])
+            .rate_limit(None),
+    )
+    .await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+    let mut carol = TestClient::register(server.local_addr(), "carol").await;
+
+    for round in 0..10 {
+        let sent = say(&mut alice, &mut bob, &format!("take {round}")).await;
+        edit(&mut alice, sent.id, "retake").await;
+        carol
+            .send(ClientMessage::DeleteMessage { id: sent.id })
+            .await;
+        // the edit goes out before the deletion or finds nothing to change
+        let (mut deleted, mut edit_done) = (false, false);
+        while !(deleted && edit_done) {
+            match alice.recv().await {
+                ServerResponse::MessageDeleted { envelope, .. } if envelope.id == sent.id => {
+                    deleted = true
+                }
+                ServerResponse::MessageEdited { envelope, .. } if envelope.id == sent.id => {
+                    assert!(!deleted, "edited after it was deleted");
+                    edit_done = true;
+                }
+                ServerResponse::MessageNotFound { id } if id == sent.id => {
+                    assert!(deleted);
+                    edit_done = true;
+                }
+                _ => {}
+            }
+        }
+        assert!(history(&mut bob).await.iter().all(|e| e.seq != sent.id));
+    }
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn stores_do_not_amend_what_was_deleted_meanwhile() {
+    let path = |cache_capacity: usize| {
+        std::env::temp_dir().join(format!(
+            "simple-chat-amend-{cache_capacity}-{}.jsonl",
+            std::process::id()
+        ))
+    };
+    // the file store with and without the entry in its cache
+    let stores: Vec<Box<dyn HistoryStore>> = vec![
+        Box::new(MemoryHistoryStore::new(10)),
+        Box::new(FileHistoryStore::open(path(10), 10).unwrap()),
+        Box::new(FileHistoryStore::open(path(1), 1).unwrap()),
+    ];
+    for store in stores {
+        let said = store.append("lobby", "alice", "account:alice", "helo");
+        store.append("lobby", "bob", "account:bob", "hi");
+        let mut gone = said.clone();
+        gone.message.clear();
+        gone.edited_ms = Some(said.timestamp_ms);
+        gone.deleted = true;
+        assert!(store.amend(&gone));
+
+        let mut edited = said;
+        edited.message = "hello".to_string();
+        edited.edited_ms = Some(edited.timestamp_ms);
+        assert!(!store.amend(&edited));
+        assert_eq!(store.find(edited.seq).unwrap(), None);
+    }
+    for cache_capacity in [10, 1] {
+        let _ = std::fs::remove_file(path(cache_capacity));
+    }
+}
+
+async fn change_nick(client: &mut TestClient, new_name: &str) {
+    client
+        .send(ClientMessage::ChangeNick {
+            new_name: new_name.to_string(),
+        })
+        .await;
+    client
+        .recv_until(|r| matches!(r, ServerResponse::NickChanged { .. }))
+        .await;
+}
+
+#[tokio::test]
+async fn authors_keep_their_messages_across_renames() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    let sent = say(&mut alice, &mut bob, "helo").await;
+    change_nick(&mut alice, "alicia").await;
+    edit(&mut alice, sent.id, "hello").await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::MessageEdited { .. }))
+        .await;
+    assert!(matches!(
+        response,
+        ServerResponse::MessageEdited { envelope, message, .. }
+            if envelope.id == sent.id && message == "hello"
+    ));
+}
+
+#[tokio::test]
+async fn taking_over_a_username_does_not_take_over_its_messages() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    let sent = say(&mut alice, &mut bob, "mine").await;
+    change_nick(&mut alice, "alicia").await;
+    change_nick(&mut bob, "alice").await;
+    edit(&mut bob, sent.id, "bob's now").await;
+    assert!(matches!(
+        bob.recv_until(|r| matches!(r, ServerResponse::NotPermitted))
+            .await,
+        ServerResponse::NotPermitted
+    ));
+}
+
+#[test]
+fn amendments_outlive_the_file_store() {
+    let path = std::env::temp_dir().join(format!("simple-chat-edits-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    {
+        let store = FileHistoryStore::open(&path, 10).unwrap();
+        let mut typo = store.append("lobby", "alice", "account:alice", "helo");
+        let mut gone = store.append("lobby", "alice", "account:alice", "oops");
+        store.append("lobby", "bob", "account:bob", "hi");
+        typo.message = "hello".to_string();
+        typo.edited_ms = Some(typo.timestamp_ms);
+        store.amend(&typo);
+        gone.message.clear();
+        gone.edited_ms = Some(gone.timestamp_ms);
+        gone.deleted = true;
+        store.amend(&gone);
+    }
+
+    // from the cache, and from the file when the cache cannot tell
+    for cache_capacity in [10, 1] {
+        let store = FileHistoryStore::open(&path, cache_capacity).unwrap();
+        let messages: Vec<String> = store
+            .recent("lobby", 10)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.message)
+            .collect();
+        assert_eq!(messages, ["hello", "hi"]);
+        assert_eq!(store.find(1).unwrap(), None);
+        assert!(store.next_seq() >= 3);
+    }
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,139 @@
+/*
+ *  The id, sequence number and timestamp the server stamps on every message
+ */
+
+mod common;
+
+use common::{start_server, TestClient};
+use simple_lib::{
+    history::{now_millis, FileHistoryStore, HistoryStore},
+    msg::{ClientMessage, Envelope, ServerResponse, DEFAULT_ROOM},
+};
+
+async fn say(client: &mut TestClient, room: &str, message: &str) {
+    client
+        .send(ClientMessage::Message {
+            room: room.to_string(),
+            message: message.to_string(),
+            nonce: None,
+        })
+        .await;
+}
+
+async fn envelope(client: &mut TestClient) -> Envelope {
+    match client
+        .recv_until(|r| {
+            matches!(
+                r,
+                ServerResponse::Broadcast { .. } | ServerResponse::Direct { .. }
+            )
+        })
+        .await
+    {
+        ServerResponse::Broadcast { envelope, .. } | ServerResponse::Direct { envelope, .. } => {
+            envelope
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[tokio::test]
+async fn messages_are_stamped_in_the_order_they_are_sent() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+    for client in [&mut alice, &mut bob] {
+        client
+            .send(ClientMessage::JoinRoom("rust".to_string()))
+            .await;
+        client
+            .recv_until(|r| matches!(r, ServerResponse::RoomJoined { .. }))
+            .await;
+    }
+
+    let before = now_millis();
+    say(&mut alice, DEFAULT_ROOM, "one").await;
+    let first = envelope(&mut bob).await;
+    say(&mut alice, "rust", "two").await;
+    let second = envelope(&mut bob).await;
+    alice
+        .send(ClientMessage::DirectMessage {
+            to: "bob".to_string(),
+            message: "three".to_string(),
+            nonce: None,
+        })
+        .await;
+    let third = envelope(&mut bob).await;
+    let after = now_millis();
+
+    // one sequence for every room and every user
+    assert!(first.seq < second.seq && second.seq < third.seq);
+    for envelope in [first, second, third] {
+        assert_eq!(envelope.id, envelope.seq);
+        assert!((before..=after).contains(&envelope.timestamp_ms));
+    }
+}
+
+#[tokio::test]
+async fn replayed_history_keeps_its_stamps() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    say(&mut alice, DEFAULT_ROOM, "hello").await;
+    let sent = envelope(&mut bob).await;
+
+    let mut carol = TestClient::register(server.local_addr(), "carol").await;
+    carol
+        .send(ClientMessage::FetchHistory {
+            room: DEFAULT_ROOM.to_string(),
+            before_seq: None,
+            after_seq: None,
+            limit: 10,
+        })
+        .await;
+    let ServerResponse::HistoryReplay { entries, .. } = carol
+        .recv_until(|r| matches!(r, ServerResponse::HistoryReplay { .. }))
+        .await
+    else {
+        unreachable!()
+    };
+    let entry = entries.iter().find(|e| e.message == "hello").unwrap();
+    assert_eq!(entry.envelope(), sent);
+}
+
+#[test]
+fn numbers_are_not_reused_after_a_restart() {
+    let path = std::env::temp_dir().join(format!("simple-chat-seq-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let direct = {
+        let store = FileHistoryStore::open(&path, 10).unwrap();
+        store.append("lobby", "alice", "account:alice", "hi");
+        store.next_seq()
+    };
+
+    let store = FileHistoryStore::open(&path, 10).unwrap();
+    assert!(store.append("lobby", "alice", "account:alice", "again").seq > direct);
+    assert!(store.next_seq() > direct);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn numbers_without_an_entry_are_set_aside_in_blocks() {
+    let path = std::env::temp_dir().join(format!(
+        "simple-chat-seq-block-{}.jsonl",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let last = {
+        let store = FileHistoryStore::open(&path, 10).unwrap();
+        (0..100).map(|_| store.next_seq()).last().unwrap()
+    };
+    // one mark covers them all
+    let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+    assert_eq!(lines, 1);
+
+    let store = FileHistoryStore::open(&path, 10).unwrap();
+    assert!(store.next_seq() > last);
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,110 @@
+/*
+ *  Asking the server for a room's history, a page at a time or by search
+ */
+
+mod common;
+
+use common::{start_server, TestClient};
+use simple_lib::msg::{ClientMessage, HistoryEntry, ServerResponse, DEFAULT_ROOM};
+
+async fn say(client: &mut TestClient, listener: &mut TestClient, message: &str) {
+    client
+        .send(ClientMessage::Message {
+            room: DEFAULT_ROOM.to_string(),
+            message: message.to_string(),
+            nonce: None,
+        })
+        .await;
+    listener
+        .recv_until(|r| matches!(r, ServerResponse::Broadcast { .. }))
+        .await;
+}
+
+async fn page(client: &mut TestClient, request: ClientMessage) -> (Vec<HistoryEntry>, bool) {
+    client.send(request).await;
+    let ServerResponse::HistoryPage {
+        entries, has_more, ..
+    } = client
+        .recv_until(|r| matches!(r, ServerResponse::HistoryPage { .. }))
+        .await
+    else {
+        unreachable!()
+    };
+    (entries, has_more)
+}
+
+fn messages(entries: &[HistoryEntry]) -> Vec<&str> {
+    entries.iter().map(|e| e.message.as_str()).collect()
+}
+
+#[tokio::test]
+async fn history_is_fetched_page_by_page() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+    for i in 0..5 {
+        say(&mut alice, &mut bob, &format!("message {i}")).await;
+    }
+
+    let fetch = |before_seq| ClientMessage::FetchHistory {
+        room: DEFAULT_ROOM.to_string(),
+        before_seq,
+        after_seq: None,
+        limit: 2,
+    };
+    let (newest, has_more) = page(&mut bob, fetch(None)).await;
+    assert_eq!(messages(&newest), ["message 3", "message 4"]);
+    assert!(has_more);
+    let (older, has_more) = page(&mut bob, fetch(Some(newest[0].seq))).await;
+    assert_eq!(messages(&older), ["message 1", "message 2"]);
+    assert!(has_more);
+    let (oldest, has_more) = page(&mut bob, fetch(Some(older[0].seq))).await;
+    assert_eq!(messages(&oldest), ["message 0"]);
+    assert!(!has_more);
+}
+
+#[tokio::test]
+async fn history_is_searched_regardless_of_case() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+    for message in ["Rust is fun", "lunch?", "more rust", "RUSTY"] {
+        say(&mut alice, &mut bob, message).await;
+    }
+
+    let search = |limit| ClientMessage::SearchHistory {
+        room: DEFAULT_ROOM.to_string(),
+        query: "rust".to_string(),
+        limit,
+    };
+    let (found, has_more) = page(&mut bob, search(10)).await;
+    assert_eq!(messages(&found), ["Rust is fun", "more rust", "RUSTY"]);
+    assert!(!has_more);
+    let (found, has_more) = page(&mut bob, search(2)).await;
+    assert_eq!(messages(&found), ["more rust", "RUSTY"]);
+    assert!(has_more);
+}
+
+#[tokio::test]
+async fn only_members_read_a_room() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+
+    alice
+        .send(ClientMessage::FetchHistory {
+            room: "elsewhere".to_string(),
+            before_seq: None,
+            after_seq: None,
+            limit: 10,
+        })
+        .await;
+    let response = alice
+        .recv_until(|r| {
+            matches!(
+                r,
+                ServerResponse::NotInRoom { .. } | ServerResponse::HistoryPage { .. }
+            )
+        })
+        .await;
+    assert!(matches!(response, ServerResponse::NotInRoom { room } if room == "elsewhere"));
+}
@@ -0,0 +1,56 @@
+/*
+ *  Opening a connection: the handshake has to come first and speak a
+ *  protocol version the server knows
+ */
+
+mod common;
+
+use common::{start_server, TestClient};
+use simple_lib::msg::{
+    Capabilities, ClientMessage, ServerResponse, DEFAULT_ROOM, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+
+#[tokio::test]
+async fn unsupported_protocol_versions_are_refused() {
+    let server = start_server().await;
+    for protocol_version in [MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+        let mut client = TestClient::connect(server.local_addr()).await;
+        client
+            .send(ClientMessage::Hello {
+                protocol_version,
+                capabilities: Capabilities::supported(),
+            })
+            .await;
+        let response = client.recv().await;
+        assert!(
+            matches!(response, ServerResponse::ConnectionRefused { .. }),
+            "version {protocol_version} got {response:?}"
+        );
+        assert!(client.try_recv().await.is_none());
+    }
+}
+
+#[tokio::test]
+async fn connections_that_do_not_open_with_hello_are_refused() {
+    let server = start_server().await;
+    let openings = [
+        ClientMessage::UserName("alice".to_string()),
+        ClientMessage::ListRooms,
+        ClientMessage::Message {
+            room: DEFAULT_ROOM.to_string(),
+            message: "hi".to_string(),
+            nonce: None,
+        },
+    ];
+    for opening in openings {
+        let mut client = TestClient::connect(server.local_addr()).await;
+        client.send(opening).await;
+        let response = client.recv().await;
+        assert!(
+            matches!(response, ServerResponse::ConnectionRefused { .. }),
+            "got {response:?}"
+        );
+        assert!(client.try_recv().await.is_none());
+    }
+}
@@ -0,0 +1,81 @@
+/*
+ *  Pings, pongs and dropping connections that went quiet
+ */
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{start_server, start_with, TestClient};
+use simple_lib::{
+    actor::tcp_handler::Heartbeat,
+    msg::{ClientMessage, ServerResponse},
+    server::ChatServer,
+};
+
+fn heartbeat(interval: Option<u64>, idle_timeout: Option<u64>) -> Heartbeat {
+    Heartbeat {
+        interval: interval.map(Duration::from_millis),
+        max_missed: 2,
+        idle_timeout: idle_timeout.map(Duration::from_millis),
+    }
+}
+
+#[tokio::test]
+async fn client_pings_are_answered() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+
+    alice.send(ClientMessage::Ping(7)).await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::Pong(_)))
+        .await;
+    assert!(matches!(response, ServerResponse::Pong(7)));
+}
+
+#[tokio::test]
+async fn unanswered_pings_drop_the_client() {
+    let server = start_with(ChatServer::builder().heartbeat(heartbeat(Some(100), None))).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+
+    // pings keep coming until the server gives up on alice
+    let mut pings = 0;
+    while let Some(response) = alice.try_recv().await {
+        if matches!(response, ServerResponse::Ping(_)) {
+            pings += 1;
+        }
+    }
+    assert_eq!(pings, 2);
+}
+
+#[tokio::test]
+async fn answered_pings_keep_the_client() {
+    let server = start_with(ChatServer::builder().heartbeat(heartbeat(Some(50), None))).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+
+    let until = Instant::now() + Duration::from_millis(500);
+    let mut pings = 0;
+    while Instant::now() < until {
+        if let ServerResponse::Ping(value) = alice.recv().await {
+            pings += 1;
+            alice.send(ClientMessage::Pong(value)).await;
+        }
+    }
+    assert!(pings > 2, "only {pings} pings");
+
+    alice.send(ClientMessage::ListUsers).await;
+    alice
+        .recv_until(|r| matches!(r, ServerResponse::UserList(_)))
+        .await;
+}
+
+#[tokio::test]
+async fn silent_connections_time_out() {
+    let server = start_with(ChatServer::builder().heartbeat(heartbeat(None, Some(200)))).await;
+    let started = Instant::now();
+
+    // never says a word, not even hello
+    let mut lurker = TestClient::connect(server.local_addr()).await;
+    assert!(lurker.try_recv().await.is_none());
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
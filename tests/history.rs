@@ -0,0 +1,113 @@
+/*
+ *  Recording broadcasts and reading them back a page at a time
+ */
+
+use simple_lib::{
+    history::{FileHistoryStore, HistoryStore, MemoryHistoryStore},
+    msg::HistoryEntry,
+};
+
+/// Says `count` numbered messages in `room`, returning their entries.
+fn chatter(store: &dyn HistoryStore, room: &str, count: usize) -> Vec<HistoryEntry> {
+    (0..count)
+        .map(|i| store.append(room, "alice", "account:alice", &format!("{room} {i}")))
+        .collect()
+}
+
+fn messages(entries: &[HistoryEntry]) -> Vec<&str> {
+    entries.iter().map(|e| e.message.as_str()).collect()
+}
+
+/// What every store has to answer the same way, as long as nothing was
+/// evicted from it.
+fn pages_through(store: &dyn HistoryStore) {
+    let lobby = chatter(store, "lobby", 5);
+    chatter(store, "rust", 2);
+    assert!(lobby.windows(2).all(|w| w[0].seq < w[1].seq));
+
+    let recent = store.recent("lobby", 2).unwrap();
+    assert_eq!(messages(&recent), ["lobby 3", "lobby 4"]);
+    let older = store.before("lobby", Some(recent[0].seq), 2).unwrap();
+    assert_eq!(messages(&older), ["lobby 1", "lobby 2"]);
+    let oldest = store.before("lobby", Some(older[0].seq), 2).unwrap();
+    assert_eq!(messages(&oldest), ["lobby 0"]);
+    assert!(store
+        .before("lobby", Some(lobby[0].seq), 2)
+        .unwrap()
+        .is_empty());
+
+    assert_eq!(
+        messages(&store.recent("rust", 10).unwrap()),
+        ["rust 0", "rust 1"]
+    );
+    assert!(store.recent("nowhere", 10).unwrap().is_empty());
+}
+
+#[test]
+fn the_memory_store_pages_through_a_room() {
+    pages_through(&MemoryHistoryStore::new(100));
+}
+
+#[test]
+fn the_file_store_pages_through_a_room() {
+    let path =
+        std::env::temp_dir().join(format!("simple-chat-history-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    pages_through(&FileHistoryStore::open(&path, 100).unwrap());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn the_memory_store_forgets_the_oldest_messages() {
+    let store = MemoryHistoryStore::new(3);
+    let said = chatter(&store, "lobby", 5);
+
+    let kept = store.recent("lobby", 10).unwrap();
+    assert_eq!(messages(&kept), ["lobby 2", "lobby 3", "lobby 4"]);
+    assert!(store
+        .before("lobby", Some(said[2].seq), 10)
+        .unwrap()
+        .is_empty());
+    // forgetting messages does not hand their numbers out again
+    assert!(store.append("lobby", "alice", "account:alice", "again").seq > said[4].seq);
+}
+
+#[test]
+fn the_file_store_remembers_across_restarts() {
+    let path = std::env::temp_dir().join(format!(
+        "simple-chat-history-restart-{}.jsonl",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let said = chatter(&FileHistoryStore::open(&path, 10).unwrap(), "lobby", 3);
+
+    let store = FileHistoryStore::open(&path, 10).unwrap();
+    assert_eq!(
+        messages(&store.recent("lobby", 10).unwrap()),
+        ["lobby 0", "lobby 1", "lobby 2"]
+    );
+    assert!(store.append("lobby", "alice", "account:alice", "again").seq > said[2].seq);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn the_file_store_reads_past_its_cache() {
+    let path = std::env::temp_dir().join(format!(
+        "simple-chat-history-cache-{}.jsonl",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let store = FileHistoryStore::open(&path, 2).unwrap();
+    let said = chatter(&store, "lobby", 5);
+
+    let all = store.recent("lobby", 10).unwrap();
+    assert_eq!(
+        messages(&all),
+        ["lobby 0", "lobby 1", "lobby 2", "lobby 3", "lobby 4"]
+    );
+    let next = store.after("lobby", said[0].seq, 2).unwrap();
+    assert_eq!(messages(&next), ["lobby 1", "lobby 2"]);
+    let older = store.before("lobby", Some(said[3].seq), 2).unwrap();
+    assert_eq!(messages(&older), ["lobby 1", "lobby 2"]);
+    let _ = std::fs::remove_file(&path);
+}
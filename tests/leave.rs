@@ -0,0 +1,80 @@
+/*
+ *  Users leaving on purpose rather than losing their connection
+ */
+
+mod common;
+
+use common::{start_server, TestClient};
+use simple_lib::msg::{ClientMessage, ServerResponse};
+
+#[tokio::test]
+async fn leaving_is_announced_with_the_reason() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    alice
+        .send(ClientMessage::Leave {
+            reason: Some("lunch".to_string()),
+        })
+        .await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::UserLeft { .. }))
+        .await;
+    assert!(matches!(
+        response,
+        ServerResponse::UserLeft { username, reason: Some(reason) }
+            if username == "alice" && reason == "lunch"
+    ));
+
+    // the server closes the connection once alice is gone
+    while let Some(response) = alice.try_recv().await {
+        assert!(!matches!(response, ServerResponse::UserLeft { .. }));
+    }
+}
+
+#[tokio::test]
+async fn left_names_are_free_at_once() {
+    // sessions are kept around by default, but not for users who left
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    alice.send(ClientMessage::Leave { reason: None }).await;
+    assert!(alice.try_recv().await.is_none());
+
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+    bob.send(ClientMessage::ListUsers).await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::UserList(_)))
+        .await;
+    assert!(matches!(response, ServerResponse::UserList(users) if users == ["bob"]));
+
+    TestClient::register(server.local_addr(), "alice").await;
+}
+
+#[tokio::test]
+async fn nothing_sent_after_leaving_is_acted_on() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    alice.send(ClientMessage::Leave { reason: None }).await;
+    alice
+        .send(ClientMessage::Message {
+            room: "lobby".to_string(),
+            message: "still here".to_string(),
+            nonce: None,
+        })
+        .await;
+    bob.recv_until(|r| matches!(r, ServerResponse::UserLeft { .. }))
+        .await;
+    bob.send(ClientMessage::ListUsers).await;
+    let response = bob
+        .recv_until(|r| {
+            matches!(
+                r,
+                ServerResponse::UserList(_) | ServerResponse::Broadcast { .. }
+            )
+        })
+        .await;
+    assert!(matches!(response, ServerResponse::UserList(_)));
+}
@@ -0,0 +1,213 @@
+/*
+ *  Operators kicking, banning and muting other users
+ */
+
+mod common;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use common::{start_with, TestClient};
+use simple_lib::{
+    moderation::BanList,
+    msg::{ClientMessage, ServerResponse, DEFAULT_ROOM},
+    server::{ChatServer, ChatServerBuilder},
+};
+
+fn with_operator() -> ChatServerBuilder {
+    ChatServer::builder().operators(["alice"])
+}
+
+fn bans_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("simple-chat-bans-{name}-{}", std::process::id()))
+}
+
+async fn say(client: &mut TestClient, message: &str) {
+    client
+        .send(ClientMessage::Message {
+            room: DEFAULT_ROOM.to_string(),
+            message: message.to_string(),
+            nonce: None,
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn only_operators_moderate() {
+    let server = start_with(with_operator()).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    bob.send(ClientMessage::Kick {
+        username: "alice".to_string(),
+        reason: None,
+    })
+    .await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::NotPermitted))
+        .await;
+    assert!(matches!(response, ServerResponse::NotPermitted));
+
+    // operators are out of each other's reach as well
+    alice
+        .send(ClientMessage::Mute {
+            username: "alice".to_string(),
+            duration: Duration::from_secs(60),
+        })
+        .await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::NotPermitted))
+        .await;
+    assert!(matches!(response, ServerResponse::NotPermitted));
+}
+
+#[tokio::test]
+async fn kicked_users_are_told_why_and_removed() {
+    let server = start_with(with_operator()).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+    let mut carol = TestClient::register(server.local_addr(), "carol").await;
+
+    alice
+        .send(ClientMessage::Kick {
+            username: "bob".to_string(),
+            reason: Some("spam".to_string()),
+        })
+        .await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::Kicked { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::Kicked { reason: Some(r) } if r == "spam"));
+    assert!(bob.try_recv().await.is_none());
+
+    let response = carol
+        .recv_until(|r| matches!(r, ServerResponse::UserLeft { .. }))
+        .await;
+    assert!(matches!(
+        response,
+        ServerResponse::UserLeft { username, reason: Some(reason) }
+            if username == "bob" && reason == "kicked by alice: spam"
+    ));
+    alice
+        .recv_until(|r| matches!(r, ServerResponse::ModerationApplied { .. }))
+        .await;
+
+    // a kick is not a ban
+    TestClient::register(server.local_addr(), "bob").await;
+}
+
+#[tokio::test]
+async fn banned_names_stay_out_and_are_recorded() {
+    let path = bans_file("names");
+    let _ = std::fs::remove_file(&path);
+    let server = start_with(with_operator().bans(BanList::load(&path).unwrap())).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    alice
+        .send(ClientMessage::Ban {
+            username: "bob".to_string(),
+            reason: None,
+            ip: false,
+        })
+        .await;
+    bob.recv_until(|r| matches!(r, ServerResponse::Kicked { .. }))
+        .await;
+    alice
+        .recv_until(|r| matches!(r, ServerResponse::ModerationApplied { .. }))
+        .await;
+
+    let mut bob = TestClient::connect(server.local_addr()).await;
+    bob.hello().await;
+    bob.send(ClientMessage::UserName("bob".to_string())).await;
+    assert!(matches!(
+        bob.recv().await,
+        ServerResponse::ConnectionRefused { .. }
+    ));
+
+    // the ban is written off the actor, give it a moment
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let reloaded = BanList::load(&path).unwrap();
+    assert!(reloaded.is_name_banned("bob"));
+    assert!(!reloaded.is_name_banned("alice"));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn banned_addresses_are_refused() {
+    let server = start_with(with_operator()).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let _bob = TestClient::register(server.local_addr(), "bob").await;
+
+    alice
+        .send(ClientMessage::Ban {
+            username: "bob".to_string(),
+            reason: None,
+            ip: true,
+        })
+        .await;
+    alice
+        .recv_until(|r| matches!(r, ServerResponse::ModerationApplied { .. }))
+        .await;
+
+    // everyone here shares bob's address
+    let mut carol = TestClient::connect(server.local_addr()).await;
+    assert!(matches!(
+        carol.try_hello().await,
+        Some(ServerResponse::ConnectionRefused { .. })
+    ));
+}
+
+#[tokio::test]
+async fn muted_messages_are_rejected() {
+    let server = start_with(with_operator()).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    alice
+        .send(ClientMessage::Mute {
+            username: "bob".to_string(),
+            duration: Duration::from_secs(60),
+        })
+        .await;
+    bob.recv_until(|r| matches!(r, ServerResponse::Muted { .. }))
+        .await;
+
+    say(&mut bob, "can you hear me").await;
+    let response = bob.recv().await;
+    assert!(matches!(response, ServerResponse::Muted { .. }));
+
+    // lifted again
+    alice
+        .send(ClientMessage::Mute {
+            username: "bob".to_string(),
+            duration: Duration::ZERO,
+        })
+        .await;
+    bob.recv_until(|r| matches!(r, ServerResponse::Muted { .. }))
+        .await;
+    say(&mut bob, "now you can").await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::Broadcast { .. }))
+        .await;
+    assert!(
+        matches!(response, ServerResponse::Broadcast { message, .. } if message == "now you can")
+    );
+}
+
+#[test]
+fn ban_files_are_checked() {
+    let path = bans_file("parse");
+    std::fs::write(&path, "# kept out\nname:mallory\n\nip:10.0.0.1\nip:::1\n").unwrap();
+    let bans = BanList::load(&path).unwrap();
+    assert!(bans.is_name_banned("mallory"));
+    assert!(bans.is_ip_banned("10.0.0.1".parse().unwrap()));
+    assert!(bans.is_ip_banned("::1".parse().unwrap()));
+    assert!(!bans.is_ip_banned("10.0.0.2".parse().unwrap()));
+
+    std::fs::write(&path, "mallory\n").unwrap();
+    assert!(BanList::load(&path).is_err());
+    std::fs::write(&path, "ip:nowhere\n").unwrap();
+    assert!(BanList::load(&path).is_err());
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,148 @@
+/*
+ *  Users changing their username while connected
+ */
+
+mod common;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use common::{start_server, start_with, TestClient};
+use simple_lib::{
+    auth::StaticTokenAuthenticator,
+    msg::{ClientMessage, ServerResponse, DEFAULT_ROOM},
+    registration::NameRejection,
+    server::ChatServer,
+};
+
+async fn change_nick(client: &mut TestClient, new_name: &str) {
+    client
+        .send(ClientMessage::ChangeNick {
+            new_name: new_name.to_string(),
+        })
+        .await;
+}
+
+async fn user_list(client: &mut TestClient) -> Vec<String> {
+    client.send(ClientMessage::ListUsers).await;
+    let ServerResponse::UserList(users) = client
+        .recv_until(|r| matches!(r, ServerResponse::UserList(_)))
+        .await
+    else {
+        unreachable!()
+    };
+    users
+}
+
+fn renamed(response: &ServerResponse, from: &str, to: &str) -> bool {
+    matches!(response, ServerResponse::NickChanged { old, new } if old == from && new == to)
+}
+
+#[tokio::test]
+async fn renames_are_announced_to_everyone() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    change_nick(&mut alice, "alicia").await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::NickChanged { .. }))
+        .await;
+    assert!(renamed(&response, "alice", "alicia"));
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::NickChanged { .. }))
+        .await;
+    assert!(renamed(&response, "alice", "alicia"));
+    assert_eq!(user_list(&mut bob).await, ["alicia", "bob"]);
+
+    // what alice says from now on is signed with her new name
+    alice
+        .send(ClientMessage::Message {
+            room: DEFAULT_ROOM.to_string(),
+            message: "hi".to_string(),
+            nonce: None,
+        })
+        .await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::Broadcast { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::Broadcast { username, .. } if username == "alicia"));
+
+    // and her old one is free
+    TestClient::register(server.local_addr(), "alice").await;
+}
+
+#[tokio::test]
+async fn taken_and_broken_names_are_refused() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let _bob = TestClient::register(server.local_addr(), "bob").await;
+
+    change_nick(&mut alice, "BOB").await;
+    let response = alice
+        .recv_until(|r| {
+            matches!(
+                r,
+                ServerResponse::UsernameExists | ServerResponse::NickChanged { .. }
+            )
+        })
+        .await;
+    assert!(matches!(response, ServerResponse::UsernameExists));
+
+    change_nick(&mut alice, "system").await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::UsernameRejected { .. }))
+        .await;
+    assert!(matches!(
+        response,
+        ServerResponse::UsernameRejected {
+            reason: NameRejection::Reserved
+        }
+    ));
+    assert_eq!(user_list(&mut alice).await, ["alice", "bob"]);
+
+    // asking for the name she has is answered all the same
+    change_nick(&mut alice, "alice").await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::NickChanged { .. }))
+        .await;
+    assert!(renamed(&response, "alice", "alice"));
+
+    // a name that only differs in case is still alice's own
+    change_nick(&mut alice, "Alice").await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::NickChanged { .. }))
+        .await;
+    assert!(renamed(&response, "alice", "Alice"));
+}
+
+#[tokio::test]
+async fn authenticated_names_stay_put() {
+    let tokens = HashMap::from([("alice".to_string(), "secret".to_string())]);
+    let server = start_with(
+        ChatServer::builder().authenticator(Arc::new(StaticTokenAuthenticator::new(tokens))),
+    )
+    .await;
+    let mut alice = TestClient::connect(server.local_addr()).await;
+    alice.hello().await;
+    alice
+        .send(ClientMessage::Authenticate {
+            username: "alice".to_string(),
+            credential: "secret".to_string(),
+        })
+        .await;
+    alice
+        .recv_until(|r| matches!(r, ServerResponse::UsernameAccepted))
+        .await;
+
+    change_nick(&mut alice, "mallory").await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::UsernameRejected { .. }))
+        .await;
+    assert!(matches!(
+        response,
+        ServerResponse::UsernameRejected {
+            reason: NameRejection::Locked
+        }
+    ));
+}
@@ -0,0 +1,73 @@
+/*
+ *  Telling users who came online and who went away
+ */
+
+mod common;
+
+use common::{start_server, start_with, TestClient};
+use simple_lib::{
+    msg::{ClientMessage, ServerResponse},
+    server::ChatServer,
+};
+
+#[tokio::test]
+async fn others_are_told_who_joined() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::UserJoined { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::UserJoined { username } if username == "bob"));
+
+    // nobody is told about themselves
+    bob.send(ClientMessage::ListUsers).await;
+    let response = bob
+        .recv_until(|r| {
+            matches!(
+                r,
+                ServerResponse::UserList(_) | ServerResponse::UserJoined { .. }
+            )
+        })
+        .await;
+    assert!(matches!(response, ServerResponse::UserList(_)));
+}
+
+#[tokio::test]
+async fn others_are_told_who_dropped() {
+    let server = start_with(ChatServer::builder().resume_grace(None)).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let bob = TestClient::register(server.local_addr(), "bob").await;
+
+    drop(bob);
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::UserLeft { .. }))
+        .await;
+    assert!(matches!(
+        response,
+        ServerResponse::UserLeft { username, reason: None } if username == "bob"
+    ));
+}
+
+#[tokio::test]
+async fn unregistered_connections_come_and_go_unannounced() {
+    let server = start_with(ChatServer::builder().resume_grace(None)).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+
+    let mut lurker = TestClient::connect(server.local_addr()).await;
+    lurker.hello().await;
+    drop(lurker);
+    TestClient::register(server.local_addr(), "bob").await;
+
+    // bob is the first thing alice hears about
+    let response = alice
+        .recv_until(|r| {
+            matches!(
+                r,
+                ServerResponse::UserJoined { .. } | ServerResponse::UserLeft { .. }
+            )
+        })
+        .await;
+    assert!(matches!(response, ServerResponse::UserJoined { username } if username == "bob"));
+}
@@ -0,0 +1,197 @@
+/*
+ *  Keeping a single client from flooding everyone else
+ */
+
+mod common;
+
+use std::time::Duration;
+
+use common::{start_with, TestClient};
+use simple_lib::{
+    actor::rate_limit::RateLimit,
+    msg::{ClientMessage, ServerResponse, DEFAULT_ROOM},
+    server::ChatServer,
+};
+
+/// A limit of `burst` messages at once and one a second after that.
+fn strict(burst: u32) -> RateLimit {
+    RateLimit {
+        messages_per_sec: 1.0,
+        message_burst: burst,
+        strikes: 100,
+        ..RateLimit::default()
+    }
+}
+
+async fn say(client: &mut TestClient, message: &str) {
+    client
+        .send(ClientMessage::Message {
+            room: DEFAULT_ROOM.to_string(),
+            message: message.to_string(),
+            nonce: None,
+        })
+        .await;
+}
+
+/// The messages `client` heard, up to everything sent before now.
+async fn heard(client: &mut TestClient) -> Vec<String> {
+    // the room list comes from the shards, so every message is in by then
+    client.send(ClientMessage::ListRooms).await;
+    let mut messages = Vec::new();
+    loop {
+        match client.recv().await {
+            ServerResponse::Broadcast { message, .. } => messages.push(message),
+            ServerResponse::RoomList(_) => return messages,
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn messages_over_the_limit_are_rejected() {
+    let server = start_with(ChatServer::builder().rate_limit(Some(strict(2)))).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    for message in ["one", "two", "three"] {
+        say(&mut alice, message).await;
+    }
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::RateLimited { .. }))
+        .await;
+    assert!(matches!(
+        response,
+        ServerResponse::RateLimited { retry_after } if retry_after <= Duration::from_secs(1)
+    ));
+    assert_eq!(heard(&mut bob).await, ["one", "two"]);
+}
+
+#[tokio::test]
+async fn large_messages_use_up_the_byte_limit() {
+    let limit = RateLimit {
+        bytes_per_sec: 100.0,
+        byte_burst: 1000,
+        ..RateLimit::default()
+    };
+    let server = start_with(ChatServer::builder().rate_limit(Some(limit))).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    say(&mut alice, &"a".repeat(800)).await;
+    say(&mut alice, &"b".repeat(800)).await;
+    alice
+        .recv_until(|r| matches!(r, ServerResponse::RateLimited { .. }))
+        .await;
+    let heard = heard(&mut bob).await;
+    assert_eq!(heard.len(), 1);
+    assert!(heard[0].starts_with('a'));
+}
+
+#[tokio::test]
+async fn flooding_earns_a_mute_and_then_a_disconnect() {
+    let limit = RateLimit {
+        messages_per_sec: 1.0,
+        message_burst: 1,
+        strikes: 3,
+        mute_for: Duration::from_secs(60),
+        mutes_before_disconnect: 1,
+        ..RateLimit::default()
+    };
+    let server = start_with(ChatServer::builder().rate_limit(Some(limit))).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    for i in 0..5 {
+        say(&mut alice, &format!("spam {i}")).await;
+    }
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::Muted { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::Muted { until } if until > 0));
+
+    // carrying on while muted is the last straw
+    for i in 5..10 {
+        say(&mut alice, &format!("spam {i}")).await;
+    }
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::Kicked { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::Kicked { reason: Some(r) } if r == "flooding"));
+    while alice.try_recv().await.is_some() {}
+
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::UserLeft { .. }))
+        .await;
+    assert!(matches!(
+        response,
+        ServerResponse::UserLeft { username, reason: Some(reason) }
+            if username == "alice" && reason == "disconnected: flooding"
+    ));
+    // and alice is not kept around to resume
+    bob.send(ClientMessage::ListUsers).await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::UserList(_)))
+        .await;
+    assert!(matches!(response, ServerResponse::UserList(users) if users == ["bob"]));
+}
+
+#[tokio::test]
+async fn reconnecting_does_not_lift_a_flood_mute() {
+    let limit = RateLimit {
+        messages_per_sec: 1.0,
+        message_burst: 1,
+        strikes: 3,
+        mute_for: Duration::from_secs(60),
+        ..RateLimit::default()
+    };
+    let builder = ChatServer::builder()
+        .rate_limit(Some(limit))
+        .resume_grace(None);
+    let server = start_with(builder).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    for i in 0..5 {
+        say(&mut alice, &format!("spam {i}")).await;
+    }
+    alice
+        .recv_until(|r| matches!(r, ServerResponse::Muted { .. }))
+        .await;
+    drop(alice);
+    bob.recv_until(|r| matches!(r, ServerResponse::UserLeft { .. }))
+        .await;
+
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    say(&mut alice, "fresh start").await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::Muted { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::Muted { until } if until > 0));
+    assert_eq!(heard(&mut bob).await, Vec::<String>::new());
+}
+
+#[tokio::test]
+async fn no_limit_when_disabled() {
+    let server = start_with(ChatServer::builder().rate_limit(None)).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    for i in 0..50 {
+        say(&mut alice, &i.to_string()).await;
+    }
+    say(&mut alice, "done").await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::Broadcast { message, .. } if message == "done"))
+        .await;
+    assert!(matches!(response, ServerResponse::Broadcast { .. }));
+    alice.send(ClientMessage::ListRooms).await;
+    let response = alice
+        .recv_until(|r| {
+            matches!(
+                r,
+                ServerResponse::RoomList(_) | ServerResponse::RateLimited { .. }
+            )
+        })
+        .await;
+    assert!(matches!(response, ServerResponse::RoomList(_)));
+}
@@ -0,0 +1,161 @@
+/*
+ *  Claiming a username, keeping it unique and giving it back
+ */
+
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use common::{start_server, start_with, TestClient};
+use simple_lib::{
+    msg::{ClientMessage, ServerResponse},
+    registration::{check_username, NameRejection},
+    server::ChatServer,
+};
+
+/// Asks for `name` on a fresh connection and returns the answer.
+async fn claim(addr: SocketAddr, name: &str) -> (TestClient, ServerResponse) {
+    let mut client = TestClient::connect(addr).await;
+    client.hello().await;
+    client.send(ClientMessage::UserName(name.to_string())).await;
+    let response = client.recv().await;
+    (client, response)
+}
+
+async fn user_list(client: &mut TestClient) -> Vec<String> {
+    client.send(ClientMessage::ListUsers).await;
+    let ServerResponse::UserList(users) = client
+        .recv_until(|r| matches!(r, ServerResponse::UserList(_)))
+        .await
+    else {
+        unreachable!()
+    };
+    users
+}
+
+#[test]
+fn usernames_follow_the_rules() {
+    assert_eq!(check_username("alice"), Ok(()));
+    assert_eq!(check_username("bob_the-2nd.x"), Ok(()));
+    assert_eq!(check_username("a"), Err(NameRejection::TooShort { min: 2 }));
+    assert_eq!(
+        check_username(&"a".repeat(25)),
+        Err(NameRejection::TooLong { max: 24 })
+    );
+    assert_eq!(
+        check_username("two words"),
+        Err(NameRejection::InvalidCharacters)
+    );
+    assert_eq!(check_username("zoë"), Err(NameRejection::InvalidCharacters));
+    assert_eq!(check_username("Admin"), Err(NameRejection::Reserved));
+    assert_eq!(check_username("SERVER"), Err(NameRejection::Reserved));
+}
+
+#[tokio::test]
+async fn broken_names_are_rejected_with_the_reason() {
+    let server = start_server().await;
+    let (mut client, response) = claim(server.local_addr(), "Root").await;
+    assert!(matches!(
+        response,
+        ServerResponse::UsernameRejected {
+            reason: NameRejection::Reserved
+        }
+    ));
+
+    // the connection may try again
+    client
+        .send(ClientMessage::UserName("x\u{1b}".to_string()))
+        .await;
+    assert!(matches!(
+        client.recv().await,
+        ServerResponse::UsernameRejected {
+            reason: NameRejection::InvalidCharacters
+        }
+    ));
+    client
+        .send(ClientMessage::UserName("alice".to_string()))
+        .await;
+    client
+        .recv_until(|r| matches!(r, ServerResponse::UsernameAccepted))
+        .await;
+}
+
+#[tokio::test]
+async fn names_are_unique_regardless_of_case() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let (mut impostor, response) = claim(server.local_addr(), "ALICE").await;
+    assert!(matches!(response, ServerResponse::UsernameExists));
+
+    // a failed claim takes nothing, the name is free once alice leaves
+    alice.send(ClientMessage::Leave { reason: None }).await;
+    assert!(alice.try_recv().await.is_none());
+    impostor
+        .send(ClientMessage::UserName("Alice".to_string()))
+        .await;
+    impostor
+        .recv_until(|r| matches!(r, ServerResponse::UsernameAccepted))
+        .await;
+    assert_eq!(user_list(&mut impostor).await, ["Alice"]);
+}
+
+#[tokio::test]
+async fn direct_messages_find_users_regardless_of_case() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "Alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    bob.send(ClientMessage::DirectMessage {
+        to: "alice".to_string(),
+        message: "hi".to_string(),
+        nonce: None,
+    })
+    .await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::Direct { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::Direct { from, .. } if from == "bob"));
+}
+
+#[tokio::test]
+async fn a_connection_gets_one_name_only() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+
+    alice
+        .send(ClientMessage::UserName("carol".to_string()))
+        .await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::UsernameRejected { .. }))
+        .await;
+    assert!(matches!(
+        response,
+        ServerResponse::UsernameRejected {
+            reason: NameRejection::AlreadyRegistered
+        }
+    ));
+    // alice keeps hers and carol's is not taken on the side
+    assert_eq!(user_list(&mut alice).await, ["alice"]);
+    TestClient::register(server.local_addr(), "carol").await;
+}
+
+#[tokio::test]
+async fn connections_without_a_name_are_closed_in_time() {
+    let server =
+        start_with(ChatServer::builder().registration_timeout(Some(Duration::from_millis(200))))
+            .await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut idle = TestClient::connect(server.local_addr()).await;
+    idle.hello().await;
+
+    assert!(matches!(
+        idle.recv().await,
+        ServerResponse::ConnectionRefused { .. }
+    ));
+    assert!(idle.try_recv().await.is_none());
+
+    // registered connections are past it
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(user_list(&mut alice).await, ["alice"]);
+}
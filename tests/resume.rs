@@ -0,0 +1,278 @@
+/*
+ *  Session tokens and taking a session back after the connection was lost
+ */
+
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use common::{start_server, start_with, TestClient};
+use simple_lib::{
+    msg::{ClientMessage, HistoryEntry, ServerResponse, DEFAULT_ROOM, MAX_HISTORY_PAGE},
+    server::ChatServer,
+};
+
+/// Registers `name` and returns the client along with its session token.
+async fn register_with_token(addr: SocketAddr, name: &str) -> (TestClient, String) {
+    let mut client = TestClient::connect(addr).await;
+    client.hello().await;
+    client.send(ClientMessage::UserName(name.to_string())).await;
+    let ServerResponse::SessionToken { token, .. } = client
+        .recv_until(|r| matches!(r, ServerResponse::SessionToken { .. }))
+        .await
+    else {
+        unreachable!()
+    };
+    client
+        .recv_until(|r| matches!(r, ServerResponse::RoomJoined { .. }))
+        .await;
+    (client, token)
+}
+
+async fn resume(addr: SocketAddr, token: &str, last_seq: Option<u64>) -> TestClient {
+    let mut client = TestClient::connect(addr).await;
+    client.hello().await;
+    client
+        .send(ClientMessage::Resume {
+            token: token.to_string(),
+            last_seq,
+        })
+        .await;
+    client
+}
+
+async fn say(client: &mut TestClient, room: &str, message: &str) {
+    client
+        .send(ClientMessage::Message {
+            room: room.to_string(),
+            message: message.to_string(),
+            nonce: None,
+        })
+        .await;
+    // the room list comes from the shards, so the message is in by then
+    client.send(ClientMessage::ListRooms).await;
+    client
+        .recv_until(|r| matches!(r, ServerResponse::RoomList(_)))
+        .await;
+}
+
+/// The users the server says are online.
+async fn user_list(client: &mut TestClient) -> Vec<String> {
+    client.send(ClientMessage::ListUsers).await;
+    let ServerResponse::UserList(users) = client
+        .recv_until(|r| matches!(r, ServerResponse::UserList(_)))
+        .await
+    else {
+        unreachable!()
+    };
+    users
+}
+
+#[tokio::test]
+async fn resumed_session_keeps_name_rooms_and_missed_messages() {
+    let server = start_server().await;
+    let addr = server.local_addr();
+    let (mut alice, token) = register_with_token(addr, "alice").await;
+    let mut bob = TestClient::register(addr, "bob").await;
+
+    alice
+        .send(ClientMessage::JoinRoom("rust".to_string()))
+        .await;
+    alice
+        .recv_until(|r| matches!(r, ServerResponse::RoomJoined { room } if room == "rust"))
+        .await;
+    bob.send(ClientMessage::JoinRoom("rust".to_string())).await;
+    bob.recv_until(|r| matches!(r, ServerResponse::RoomJoined { room } if room == "rust"))
+        .await;
+    say(&mut bob, "rust", "before").await;
+    let ServerResponse::Broadcast { envelope, .. } = alice
+        .recv_until(|r| matches!(r, ServerResponse::Broadcast { .. }))
+        .await
+    else {
+        unreachable!()
+    };
+
+    drop(alice);
+    // bob keeps talking while alice is away
+    say(&mut bob, "rust", "while away").await;
+
+    let mut alice = resume(addr, &token, Some(envelope.seq)).await;
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::SessionResumed { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::SessionResumed { username } if username == "alice"));
+    // nothing was missed in the lobby, so only one room has a replay
+    let ServerResponse::HistoryReplay {
+        room,
+        entries,
+        has_more,
+    } = alice
+        .recv_until(|r| matches!(r, ServerResponse::HistoryReplay { .. }))
+        .await
+    else {
+        unreachable!()
+    };
+    assert_eq!(room, "rust");
+    let messages: Vec<&str> = entries.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(messages, ["while away"]);
+    assert!(!has_more);
+
+    // and bob never saw alice leave
+    bob.send(ClientMessage::ListUsers).await;
+    let response = bob
+        .recv_until(|r| {
+            matches!(
+                r,
+                ServerResponse::UserList(_) | ServerResponse::UserLeft { .. }
+            )
+        })
+        .await;
+    assert!(matches!(response, ServerResponse::UserList(_)));
+}
+
+/// The next replay of the lobby: its messages and whether there is more.
+async fn replay(client: &mut TestClient) -> (Vec<HistoryEntry>, bool) {
+    let ServerResponse::HistoryReplay {
+        entries, has_more, ..
+    } = client
+        .recv_until(|r| matches!(r, ServerResponse::HistoryReplay { .. }))
+        .await
+    else {
+        unreachable!()
+    };
+    (entries, has_more)
+}
+
+#[tokio::test]
+async fn a_long_absence_is_replayed_oldest_first_a_page_at_a_time() {
+    let server = start_with(ChatServer::builder().rate_limit(None)).await;
+    let addr = server.local_addr();
+    let (mut alice, token) = register_with_token(addr, "alice").await;
+    let mut bob = TestClient::register(addr, "bob").await;
+    say(&mut bob, DEFAULT_ROOM, "before").await;
+    let ServerResponse::Broadcast { envelope, .. } = alice
+        .recv_until(|r| matches!(r, ServerResponse::Broadcast { .. }))
+        .await
+    else {
+        unreachable!()
+    };
+    drop(alice);
+
+    let missed = MAX_HISTORY_PAGE as usize + 10;
+    for i in 0..missed {
+        bob.send(ClientMessage::Message {
+            room: DEFAULT_ROOM.to_string(),
+            message: format!("message {i}"),
+            nonce: None,
+        })
+        .await;
+    }
+    say(&mut bob, DEFAULT_ROOM, "caught up").await;
+
+    let mut alice = resume(addr, &token, Some(envelope.seq)).await;
+    let (first, has_more) = replay(&mut alice).await;
+    assert!(has_more);
+    assert_eq!(first.len(), MAX_HISTORY_PAGE as usize);
+    assert_eq!(first[0].message, "message 0");
+
+    alice
+        .send(ClientMessage::FetchHistory {
+            room: DEFAULT_ROOM.to_string(),
+            before_seq: None,
+            after_seq: Some(first.last().unwrap().seq),
+            limit: MAX_HISTORY_PAGE,
+        })
+        .await;
+    let (rest, has_more) = replay(&mut alice).await;
+    assert!(!has_more);
+    let messages: Vec<&str> = rest.iter().map(|e| e.message.as_str()).collect();
+    let expected: Vec<String> = (MAX_HISTORY_PAGE as usize..missed)
+        .map(|i| format!("message {i}"))
+        .chain(["caught up".to_string()])
+        .collect();
+    assert_eq!(messages, expected);
+}
+
+#[tokio::test]
+async fn name_is_reserved_while_suspended() {
+    let server = start_server().await;
+    let addr = server.local_addr();
+    let (alice, _token) = register_with_token(addr, "alice").await;
+    let mut bob = TestClient::register(addr, "bob").await;
+    drop(alice);
+
+    // give the server a moment to notice
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(user_list(&mut bob).await.contains(&"alice".to_string()));
+
+    let mut impostor = TestClient::connect(addr).await;
+    impostor.hello().await;
+    impostor
+        .send(ClientMessage::UserName("alice".to_string()))
+        .await;
+    assert!(matches!(
+        impostor.recv().await,
+        ServerResponse::UsernameExists
+    ));
+}
+
+#[tokio::test]
+async fn unknown_token_is_refused() {
+    let server = start_server().await;
+    let mut client = resume(server.local_addr(), "not a token", None).await;
+    assert!(matches!(
+        client.recv().await,
+        ServerResponse::SessionExpired
+    ));
+
+    // the connection can still register normally
+    client
+        .send(ClientMessage::UserName("alice".to_string()))
+        .await;
+    client
+        .recv_until(|r| matches!(r, ServerResponse::UsernameAccepted))
+        .await;
+}
+
+#[tokio::test]
+async fn sessions_expire_after_the_grace_period() {
+    let server =
+        start_with(ChatServer::builder().resume_grace(Some(Duration::from_millis(200)))).await;
+    let addr = server.local_addr();
+    let (alice, token) = register_with_token(addr, "alice").await;
+    let mut bob = TestClient::register(addr, "bob").await;
+    drop(alice);
+
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::UserLeft { .. }))
+        .await;
+    assert!(
+        matches!(response, ServerResponse::UserLeft { username, reason: None } if username == "alice")
+    );
+    let mut alice = resume(addr, &token, None).await;
+    assert!(matches!(alice.recv().await, ServerResponse::SessionExpired));
+}
+
+#[tokio::test]
+async fn no_tokens_without_a_grace_period() {
+    let server = start_with(ChatServer::builder().resume_grace(None)).await;
+    let mut alice = TestClient::connect(server.local_addr()).await;
+    alice.hello().await;
+    alice
+        .send(ClientMessage::UserName("alice".to_string()))
+        .await;
+    alice
+        .recv_until(|r| matches!(r, ServerResponse::UsernameAccepted))
+        .await;
+    alice.send(ClientMessage::ListUsers).await;
+    let response = alice
+        .recv_until(|r| {
+            matches!(
+                r,
+                ServerResponse::UserList(_) | ServerResponse::SessionToken { .. }
+            )
+        })
+        .await;
+    assert!(matches!(response, ServerResponse::UserList(_)));
+}
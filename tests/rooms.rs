@@ -0,0 +1,120 @@
+/*
+ *  Joining and leaving named rooms, and only hearing the ones joined
+ */
+
+mod common;
+
+use common::{start_server, TestClient};
+use simple_lib::msg::{ClientMessage, RoomInfo, ServerResponse, MAX_ROOM_NAME_LEN};
+
+async fn join(client: &mut TestClient, room: &str) -> ServerResponse {
+    client.send(ClientMessage::JoinRoom(room.to_string())).await;
+    client
+        .recv_until(|r| {
+            matches!(
+                r,
+                ServerResponse::RoomJoined { .. } | ServerResponse::InvalidRoomName { .. }
+            )
+        })
+        .await
+}
+
+async fn say(client: &mut TestClient, room: &str, message: &str) {
+    client
+        .send(ClientMessage::Message {
+            room: room.to_string(),
+            message: message.to_string(),
+            nonce: None,
+        })
+        .await;
+}
+
+async fn room_list(client: &mut TestClient) -> Vec<RoomInfo> {
+    client.send(ClientMessage::ListRooms).await;
+    let ServerResponse::RoomList(rooms) = client
+        .recv_until(|r| matches!(r, ServerResponse::RoomList(_)))
+        .await
+    else {
+        unreachable!()
+    };
+    rooms
+}
+
+#[tokio::test]
+async fn members_hear_what_is_said_in_their_rooms_only() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    let response = join(&mut alice, "rust").await;
+    assert!(matches!(response, ServerResponse::RoomJoined { room } if room == "rust"));
+    let rooms = room_list(&mut alice).await;
+    assert!(rooms.iter().any(|r| r.name == "rust" && r.members == 1));
+
+    // bob is not in the room, neither to speak nor to listen
+    say(&mut bob, "rust", "hello?").await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::NotInRoom { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::NotInRoom { room } if room == "rust"));
+    say(&mut alice, "rust", "anyone?").await;
+    room_list(&mut bob).await;
+
+    join(&mut bob, "rust").await;
+    say(&mut alice, "rust", "welcome").await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::Broadcast { .. }))
+        .await;
+    assert!(matches!(
+        response,
+        ServerResponse::Broadcast { room, message, .. } if room == "rust" && message == "welcome"
+    ));
+}
+
+#[tokio::test]
+async fn leaving_a_room_stops_its_traffic() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+    join(&mut alice, "rust").await;
+    join(&mut bob, "rust").await;
+
+    bob.send(ClientMessage::LeaveRoom("rust".to_string())).await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::RoomLeft { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::RoomLeft { room } if room == "rust"));
+
+    say(&mut alice, "rust", "gone?").await;
+    say(&mut alice, "lobby", "still here").await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::Broadcast { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::Broadcast { room, .. } if room == "lobby"));
+
+    // nothing left to leave
+    bob.send(ClientMessage::LeaveRoom("rust".to_string())).await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::NotInRoom { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::NotInRoom { room } if room == "rust"));
+}
+
+#[tokio::test]
+async fn invalid_room_names_are_refused() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+
+    let too_long = "r".repeat(MAX_ROOM_NAME_LEN + 1);
+    for room in ["", "two words", "bell\u{7}", too_long.as_str()] {
+        let response = join(&mut alice, room).await;
+        assert!(
+            matches!(&response, ServerResponse::InvalidRoomName { room: refused } if refused == room),
+            "{room:?} got {response:?}"
+        );
+    }
+    assert!(room_list(&mut alice)
+        .await
+        .iter()
+        .all(|r| r.name != "two words"));
+}
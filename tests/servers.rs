@@ -0,0 +1,67 @@
+/*
+ *  Several servers running in one process without seeing each other
+ */
+
+mod common;
+
+use common::{start_server, TestClient};
+use simple_lib::msg::{ClientMessage, ServerResponse, DEFAULT_ROOM};
+
+#[tokio::test]
+async fn servers_have_separate_usernames() {
+    let first = start_server().await;
+    let second = start_server().await;
+    assert_ne!(first.local_addr(), second.local_addr());
+
+    let _alice = TestClient::register(first.local_addr(), "alice").await;
+    // the name is taken on the first server only
+    let _alice_again = TestClient::register(second.local_addr(), "alice").await;
+
+    let mut bob = TestClient::connect(first.local_addr()).await;
+    bob.hello().await;
+    bob.send(ClientMessage::UserName("alice".to_string())).await;
+    assert!(matches!(bob.recv().await, ServerResponse::UsernameExists));
+}
+
+#[tokio::test]
+async fn broadcasts_stay_on_their_server() {
+    let first = start_server().await;
+    let second = start_server().await;
+
+    let mut alice = TestClient::register(first.local_addr(), "alice").await;
+    let mut bob = TestClient::register(first.local_addr(), "bob").await;
+    let mut carol = TestClient::register(second.local_addr(), "carol").await;
+
+    alice
+        .send(ClientMessage::Message {
+            room: DEFAULT_ROOM.to_string(),
+            message: "hi".to_string(),
+            nonce: None,
+        })
+        .await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::Broadcast { .. }))
+        .await;
+    assert!(matches!(
+        response,
+        ServerResponse::Broadcast { username, message, .. } if username == "alice" && message == "hi"
+    ));
+
+    carol.send(ClientMessage::ListUsers).await;
+    let response = carol
+        .recv_until(|r| matches!(r, ServerResponse::UserList(_)))
+        .await;
+    assert!(matches!(response, ServerResponse::UserList(users) if users == ["carol"]));
+}
+
+#[tokio::test]
+async fn stopped_server_leaves_others_running() {
+    let first = start_server().await;
+    let second = start_server().await;
+    let addr = second.local_addr();
+
+    first.stop().await.unwrap();
+
+    let mut client = TestClient::connect(addr).await;
+    client.hello().await;
+}
@@ -0,0 +1,144 @@
+/*
+ *  Rooms spread across several shards
+ */
+
+mod common;
+
+use std::collections::HashMap;
+
+use common::{start_with, TestClient};
+use simple_lib::{
+    msg::{ClientMessage, RoomInfo, ServerResponse, DEFAULT_ROOM},
+    server::ChatServer,
+};
+
+const ROOMS: [&str; 6] = ["red", "green", "blue", "cyan", "magenta", "yellow"];
+
+async fn join_all(client: &mut TestClient) {
+    for room in ROOMS {
+        client.send(ClientMessage::JoinRoom(room.to_string())).await;
+        client
+            .recv_until(
+                |r| matches!(r, ServerResponse::RoomJoined { room: joined } if joined == room),
+            )
+            .await;
+    }
+}
+
+async fn list_rooms(client: &mut TestClient) -> Vec<RoomInfo> {
+    client.send(ClientMessage::ListRooms).await;
+    match client
+        .recv_until(|r| matches!(r, ServerResponse::RoomList(_)))
+        .await
+    {
+        ServerResponse::RoomList(rooms) => rooms,
+        _ => unreachable!(),
+    }
+}
+
+#[tokio::test]
+async fn every_room_keeps_its_order() {
+    // far more messages than the rate limit allows at once
+    let server = start_with(ChatServer::builder().shards(4).rate_limit(None)).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+    join_all(&mut alice).await;
+    join_all(&mut bob).await;
+
+    let per_room = 20;
+    for n in 0..per_room {
+        for room in ROOMS {
+            alice
+                .send(ClientMessage::Message {
+                    room: room.to_string(),
+                    message: n.to_string(),
+                    nonce: None,
+                })
+                .await;
+        }
+    }
+
+    let mut received: HashMap<String, Vec<(u64, String)>> = HashMap::new();
+    for _ in 0..per_room * ROOMS.len() {
+        if let ServerResponse::Broadcast {
+            envelope,
+            room,
+            message,
+            ..
+        } = bob
+            .recv_until(|r| matches!(r, ServerResponse::Broadcast { .. }))
+            .await
+        {
+            received
+                .entry(room)
+                .or_default()
+                .push((envelope.seq, message));
+        }
+    }
+    let expected: Vec<String> = (0..per_room).map(|n| n.to_string()).collect();
+    for room in ROOMS {
+        let messages = &received[room];
+        assert!(messages.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        let messages: Vec<String> = messages.iter().map(|(_, m)| m.clone()).collect();
+        assert_eq!(messages, expected, "messages of {room} out of order");
+    }
+}
+
+#[tokio::test]
+async fn room_list_covers_every_shard() {
+    // without a grace period bob's departure is announced as soon as it is
+    // noticed
+    let server = start_with(ChatServer::builder().shards(3).resume_grace(None)).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+    join_all(&mut alice).await;
+    join_all(&mut bob).await;
+
+    let rooms = list_rooms(&mut alice).await;
+    let mut expected: Vec<&str> = ROOMS.into_iter().chain([DEFAULT_ROOM]).collect();
+    expected.sort();
+    assert_eq!(
+        rooms
+            .iter()
+            .map(|room| room.name.as_str())
+            .collect::<Vec<_>>(),
+        expected
+    );
+    assert!(rooms.iter().all(|room| room.members == 2));
+
+    // a dropped connection leaves every room it was in, whichever shard
+    // owns it
+    drop(bob);
+    alice
+        .recv_until(|r| matches!(r, ServerResponse::UserLeft { .. }))
+        .await;
+    let rooms = list_rooms(&mut alice).await;
+    assert!(rooms.iter().all(|room| room.members == 1), "{rooms:?}");
+}
+
+#[tokio::test]
+async fn room_traffic_sent_with_the_username_is_kept() {
+    let server = start_with(ChatServer::builder().shards(2)).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    alice.send(ClientMessage::JoinRoom("red".to_string())).await;
+
+    // everything is sent before the server answered the username
+    let mut bob = TestClient::connect(server.local_addr()).await;
+    bob.hello().await;
+    bob.send(ClientMessage::UserName("bob".to_string())).await;
+    bob.send(ClientMessage::JoinRoom("red".to_string())).await;
+    bob.send(ClientMessage::Message {
+        room: "red".to_string(),
+        message: "hi".to_string(),
+        nonce: None,
+    })
+    .await;
+
+    let response = alice
+        .recv_until(|r| matches!(r, ServerResponse::Broadcast { .. }))
+        .await;
+    assert!(matches!(
+        response,
+        ServerResponse::Broadcast { username, room, .. } if username == "bob" && room == "red"
+    ));
+}
@@ -0,0 +1,86 @@
+/*
+ *  Shutting a server down while clients are connected
+ */
+
+mod common;
+
+use std::time::Duration;
+
+use common::{start_server, TestClient};
+use simple_lib::msg::ServerResponse;
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn clients_are_told_before_the_connection_closes() {
+    let server = start_server().await;
+    let addr = server.local_addr();
+    let mut alice = TestClient::register(addr, "alice").await;
+    let mut bob = TestClient::register(addr, "bob").await;
+    // a connection that never finished the handshake is closed as well
+    let mut lurker = TestClient::connect(addr).await;
+
+    tokio::time::timeout(
+        Duration::from_secs(5),
+        server.shutdown(
+            "maintenance",
+            Some(Duration::from_secs(30)),
+            Duration::from_secs(1),
+        ),
+    )
+    .await
+    .expect("shutdown did not finish")
+    .unwrap();
+
+    for client in [&mut alice, &mut bob, &mut lurker] {
+        let response = client
+            .recv_until(|r| matches!(r, ServerResponse::ServerShuttingDown { .. }))
+            .await;
+        assert!(matches!(
+            response,
+            ServerResponse::ServerShuttingDown { reason, reconnect_after }
+                if reason == "maintenance" && reconnect_after == Some(Duration::from_secs(30))
+        ));
+        assert!(client.try_recv().await.is_none());
+    }
+
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+#[tokio::test]
+async fn connections_not_yet_accepted_are_told_too() {
+    let server = start_server().await;
+    let addr = server.local_addr();
+    // the os completes these handshakes while the server, on the same
+    // thread, has no chance to accept them before it is shut down
+    let mut clients = vec![];
+    for _ in 0..20 {
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        clients.push(TestClient::over(TcpStream::from_std(stream).unwrap()));
+    }
+
+    server
+        .shutdown("maintenance", None, Duration::from_secs(5))
+        .await
+        .unwrap();
+
+    for client in &mut clients {
+        let response = client.try_recv().await;
+        assert!(
+            matches!(response, Some(ServerResponse::ServerShuttingDown { .. })),
+            "got {response:?}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn idle_server_stops_at_once() {
+    let server = start_server().await;
+    tokio::time::timeout(
+        Duration::from_secs(1),
+        server.shutdown("bye", None, Duration::from_secs(10)),
+    )
+    .await
+    .expect("shutdown waited for the drain timeout")
+    .unwrap();
+}
@@ -0,0 +1,105 @@
+/*
+ *  Connecting to a tls server with certificates generated on the spot
+ */
+
+mod common;
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use common::{start_with, TestClient};
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use simple_lib::{
+    msg::ServerResponse,
+    server::ChatServer,
+    transport::tls::{self, TlsConnector},
+};
+use tokio::net::TcpStream;
+
+/// A certificate authority and a server certificate it issued for
+/// `localhost`, written out as PEM files.
+struct Certificates {
+    dir: PathBuf,
+    ca: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+    other: PathBuf,
+}
+
+impl Certificates {
+    fn generate() -> Self {
+        let dir = std::env::temp_dir().join(format!("simple-chat-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&key, &ca_cert, &ca_key)
+            .unwrap();
+
+        // a certificate for the same name that the server does not hold
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+        Self {
+            ca: write(&dir, "ca.pem", &ca_cert.pem()),
+            cert: write(&dir, "cert.pem", &cert.pem()),
+            key: write(&dir, "key.pem", &key.serialize_pem()),
+            other: write(&dir, "other.pem", &other.cert.pem()),
+            dir,
+        }
+    }
+}
+
+impl Drop for Certificates {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn write(dir: &Path, name: &str, contents: &str) -> PathBuf {
+    let path = dir.join(name);
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+async fn connect_tls(addr: SocketAddr, connector: &TlsConnector) -> std::io::Result<TestClient> {
+    let stream = TcpStream::connect(addr).await?;
+    let stream = connector
+        .connect(tls::server_name("localhost")?, stream)
+        .await?;
+    Ok(TestClient::over(stream))
+}
+
+#[tokio::test]
+async fn tls_clients_reach_the_server() {
+    let certs = Certificates::generate();
+    let acceptor = tls::acceptor(&certs.cert, &certs.key).unwrap();
+    let server = start_with(ChatServer::builder().tls(acceptor)).await;
+    let addr = server.local_addr();
+
+    // trusting the issuing authority
+    let connector = tls::connector_with_ca(&certs.ca).unwrap();
+    let mut client = connect_tls(addr, &connector).await.unwrap();
+    client.hello().await;
+
+    // trusting the server certificate itself
+    let connector = tls::connector_pinned(&certs.cert).unwrap();
+    let mut client = connect_tls(addr, &connector).await.unwrap();
+    client.hello().await;
+
+    // a pin for some other certificate is refused
+    let connector = tls::connector_pinned(&certs.other).unwrap();
+    assert!(connect_tls(addr, &connector).await.is_err());
+
+    // and so is a client that does not speak tls at all
+    let mut client = TestClient::connect(addr).await;
+    assert!(!matches!(
+        client.try_hello().await,
+        Some(ServerResponse::HelloAck { .. })
+    ));
+}
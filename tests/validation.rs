@@ -0,0 +1,95 @@
+/*
+ *  Refusing chat messages that are empty, too long or could steer a terminal
+ */
+
+mod common;
+
+use common::{start_server, start_with, TestClient};
+use simple_lib::{
+    msg::{
+        validate::{sanitize, Rejection},
+        ClientMessage, ServerResponse, DEFAULT_ROOM,
+    },
+    server::ChatServer,
+};
+
+async fn say(client: &mut TestClient, message: &str) {
+    client
+        .send(ClientMessage::Message {
+            room: DEFAULT_ROOM.to_string(),
+            message: message.to_string(),
+            nonce: None,
+        })
+        .await;
+}
+
+async fn rejection(client: &mut TestClient) -> Rejection {
+    let ServerResponse::MessageRejected { reason } = client
+        .recv_until(|r| matches!(r, ServerResponse::MessageRejected { .. }))
+        .await
+    else {
+        unreachable!()
+    };
+    reason
+}
+
+#[tokio::test]
+async fn broken_messages_are_rejected_and_not_passed_on() {
+    let server = start_with(ChatServer::builder().max_message_len(16)).await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    say(&mut alice, "  ").await;
+    assert_eq!(rejection(&mut alice).await, Rejection::Empty);
+    say(&mut alice, &"x".repeat(17)).await;
+    assert_eq!(rejection(&mut alice).await, Rejection::TooLong { max: 16 });
+    say(&mut alice, "\u{1b}[2J gotcha").await;
+    assert_eq!(rejection(&mut alice).await, Rejection::ControlCharacters);
+    say(&mut alice, "abc\u{202e}fed").await;
+    assert_eq!(rejection(&mut alice).await, Rejection::ControlCharacters);
+
+    say(&mut alice, "fine").await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::Broadcast { .. }))
+        .await;
+    assert!(matches!(response, ServerResponse::Broadcast { message, .. } if message == "fine"));
+}
+
+#[tokio::test]
+async fn direct_messages_are_checked_too() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let _bob = TestClient::register(server.local_addr(), "bob").await;
+
+    alice
+        .send(ClientMessage::DirectMessage {
+            to: "bob".to_string(),
+            message: "ring\u{7}".to_string(),
+            nonce: None,
+        })
+        .await;
+    assert_eq!(rejection(&mut alice).await, Rejection::ControlCharacters);
+}
+
+#[tokio::test]
+async fn messages_are_normalized() {
+    let server = start_server().await;
+    let mut alice = TestClient::register(server.local_addr(), "alice").await;
+    let mut bob = TestClient::register(server.local_addr(), "bob").await;
+
+    // an e followed by a combining acute accent
+    say(&mut alice, "cafe\u{301}").await;
+    let response = bob
+        .recv_until(|r| matches!(r, ServerResponse::Broadcast { .. }))
+        .await;
+    assert!(
+        matches!(response, ServerResponse::Broadcast { message, .. } if message == "caf\u{e9}")
+    );
+}
+
+#[test]
+fn unsafe_text_is_sanitized_for_display() {
+    assert_eq!(sanitize("plain text"), "plain text");
+    assert_eq!(sanitize("\u{1b}[31mred"), "\u{fffd}[31mred");
+    assert_eq!(sanitize("a\u{2066}b\u{9b}c"), "a\u{fffd}b\u{fffd}c");
+}